@@ -0,0 +1,80 @@
+// mod annotate
+// Notes for dergas -a: where each message sits in the input, when it runs, and other ways of
+// reading its payload.  The first payload byte is the register or subroutine number, so the
+// readings cover the bytes after it.
+
+use crate::{immediate_part, UCGMessage};
+
+// The payload after the first byte read as each type it divides evenly into.
+pub fn payload_readings(value: &[u8]) -> Vec<(&'static str, String)> {
+    fn join<T: ToString>(items: impl Iterator<Item = T>) -> String {
+        items.map(|i| i.to_string()).collect::<Vec<String>>().join(" ")
+    }
+    let mut readings = Vec::new();
+    if value.is_empty() {
+        return readings;
+    }
+    if value.len() % 2 == 0 {
+        readings.push(("u16", join(value.chunks(2).map(|c| u16::from_le_bytes([c[0], c[1]])))));
+    }
+    if value.len() % 4 == 0 {
+        let words = || value.chunks(4).map(|c| [c[0], c[1], c[2], c[3]]);
+        readings.push(("u32", join(words().map(u32::from_le_bytes))));
+        readings.push(("i32", join(words().map(i32::from_le_bytes))));
+        readings.push(("f32", join(words().map(f32::from_le_bytes))));
+    }
+    if value.len() % 8 == 0 {
+        let f64s = value.chunks(8).map(|c| f64::from_le_bytes([c[0], c[1], c[2], c[3], c[4], c[5], c[6], c[7]]));
+        readings.push(("f64", join(f64s)));
+    }
+    let ascii: String = value.iter()
+        .map(|&b| if (0x20..0x7F).contains(&b) { b as char } else { '.' })
+        .collect();
+    readings.push(("ascii", format!("\"{}\"", ascii)));
+    readings
+}
+
+// The annotation for one message, to go after it as a comment.
+pub fn annotate(msg: &dyn UCGMessage, offset: usize, time: Option<u64>) -> String {
+    let mut parts = vec![format!("offset {}", offset)];
+    if let Some(t) = time {
+        parts.push(format!("t={}s", t));
+    }
+    if let Some(m) = immediate_part(msg) {
+        if m.data.len() > 1 {
+            for (name, reading) in payload_readings(&m.data[1..]) {
+                parts.push(format!("{} {}", name, reading));
+            }
+        }
+    }
+    parts.join("; ")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::annotate::*;
+    use crate::UCGScriptedMessageInternal;
+
+    #[test]
+    fn annotate_payload() {
+        let m = UCGScriptedMessageInternal::parse_asm_line("+5 03/4 1F/7 RVAL 005 01 F1.5", false).unwrap();
+        assert_eq!(annotate(m.as_ref(), 9, Some(12)),
+                   "offset 9; t=12s; u16 0 16320; u32 1069547520; i32 1069547520; f32 1.5; ascii \"...?\"");
+        let m = UCGScriptedMessageInternal::parse_asm_line("+5 03/4 1F/7 SRUN 001 01", false).unwrap();
+        assert_eq!(annotate(m.as_ref(), 0, None), "offset 0");
+        assert_eq!(payload_readings(b"OK!"), vec![("ascii", String::from("\"OK!\""))]);
+    }
+
+    #[test]
+    fn annotate_uneven_payloads() {
+        // Six bytes only make u16s, and control and high bytes show as dots
+        let names = |v: &[u8]| payload_readings(v).into_iter().map(|(n, _)| n).collect::<Vec<&str>>();
+        assert_eq!(names(&[0x0A, 0x00, 0xFF, 0x7F, 0x80, 0x41]), vec!["u16", "ascii"]);
+        assert_eq!(payload_readings(&[0x0A, 0x00, 0xFF, 0x7F, 0x80, 0x41])[1].1, "\".....A\"");
+        assert_eq!(names(&[0; 8]), vec!["u16", "u32", "i32", "f32", "f64", "ascii"]);
+        assert!(payload_readings(&[]).is_empty());
+        let readings = payload_readings(&[0xFF; 4]);
+        assert_eq!(readings[2], ("i32", String::from("-1")));
+        assert_eq!(readings[3], ("f32", String::from("NaN")));
+    }
+}
@@ -0,0 +1,143 @@
+// mod assembler
+// Turns assembly source into encoded messages, one line at a time or a whole file at once.
+// This is everything rgas does apart from deciding where the bytes go, so other ground
+// software can assemble scripts the same way.
+
+use std::io;
+use std::io::BufRead;
+use crate::framing::Framing;
+use crate::hex::hexlify;
+use crate::symbols::SymbolTable;
+use crate::timeline::ScriptClock;
+use crate::{split_comment, UCGMessage, UCGMessageInternal, UCGScriptedMessageInternal};
+
+pub struct AssembledMessage {
+    pub index: usize,      // position among the messages, counting from 0
+    pub line: usize,       // source line, counting from 1
+    pub offset: usize,     // where the encoded message starts in the output
+    pub time: Option<u64>, // when it runs, in seconds from the start of the script
+    pub comment: String,   // trailing comment on the line, without the #
+    pub message: Box<dyn UCGMessage>,
+    pub encoded: Vec<u8>,  // the bytes to write, hexlified if asked for, including framing
+}
+
+pub struct Diagnostic {
+    pub line: usize,
+    pub message: String,
+}
+
+#[derive(Default)]
+pub struct Assembly {
+    pub messages: Vec<AssembledMessage>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Assembly {
+    // The whole output, as rgas would write it.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.messages.iter().flat_map(|m| m.encoded.iter().copied()).collect()
+    }
+}
+
+pub struct Assembler {
+    pub immediate: bool,
+    pub hex: bool,
+    pub framing: Framing,
+    pub symbols: SymbolTable,
+    clock: ScriptClock,
+    offset: usize,
+    count: usize,
+}
+
+impl Assembler {
+    pub fn new(immediate: bool) -> Assembler {
+        Assembler {
+            immediate,
+            hex: false,
+            framing: Framing::default(),
+            symbols: SymbolTable::new(),
+            clock: ScriptClock::new(),
+            offset: 0,
+            count: 0,
+        }
+    }
+
+    // Assemble one line.  Comments and blank lines give Ok(None).
+    pub fn line(&mut self, lineno: usize, text: &str) -> Result<Option<AssembledMessage>, String> {
+        if text.starts_with('#') {
+            return Ok(None);
+        }
+        let expanded = self.symbols.expand_line(text, !self.immediate);
+        let parsed = if self.immediate {
+            UCGMessageInternal::parse_asm_line(&expanded, false)
+        } else {
+            UCGScriptedMessageInternal::parse_asm_line(&expanded, false)
+        };
+        let message = match parsed {
+            Ok(m) => m,
+            // The parser reports comments and blank lines as an empty error
+            Err(e) if e.is_empty() => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let mut encoded = message.into_byte_vec();
+        if self.hex {
+            encoded = hexlify(&encoded);
+        }
+        encoded.extend_from_slice(self.framing.trailer());
+        let assembled = AssembledMessage {
+            index: self.count,
+            line: lineno,
+            offset: self.offset,
+            time: self.clock.advance_message(message.as_ref()),
+            comment: split_comment(text).1.unwrap_or("").to_string(),
+            message,
+            encoded,
+        };
+        self.count += 1;
+        self.offset += assembled.encoded.len();
+        Ok(Some(assembled))
+    }
+
+    // Assemble everything from a reader, carrying on past bad lines.
+    pub fn assemble<R: BufRead>(&mut self, input: R) -> io::Result<Assembly> {
+        let mut assembly = Assembly::default();
+        for (i, text) in input.lines().enumerate() {
+            match self.line(i + 1, &text?) {
+                Ok(Some(m)) => assembly.messages.push(m),
+                Ok(None) => (),
+                Err(message) => assembly.diagnostics.push(Diagnostic { line: i + 1, message }),
+            }
+        }
+        Ok(assembly)
+    }
+
+    pub fn assemble_str(&mut self, src: &str) -> Assembly {
+        // Reading from memory can't fail
+        self.assemble(src.as_bytes()).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::*;
+
+    #[test]
+    fn assemble_script() {
+        let mut asm = Assembler::new(false);
+        asm.symbols.define("IMU", 3, 4);
+        let out = asm.assemble_str("# start\n+5 IMU 1F/7 RQRY 001 01 # gyro\n\n10 03/4 1F/7 NOPE 000\n+2 03/4 1F/7 NOP 000\n");
+        assert_eq!(out.messages.len(), 2);
+        assert_eq!(out.diagnostics.len(), 1);
+        assert_eq!(out.diagnostics[0].line, 4);
+        let first = &out.messages[0];
+        assert_eq!((first.line, first.offset, first.time), (2, 0, Some(5)));
+        assert_eq!(first.comment, " gyro");
+        assert_eq!(first.encoded, vec![0x05, 0x00, 0x00, 0x80, 0x1C, 0xFF, 0x08, 0x01, 0x01, b'\r', b'\n']);
+        assert_eq!((out.messages[1].offset, out.messages[1].time), (11, Some(7)));
+
+        let mut asm = Assembler::new(true);
+        asm.hex = true;
+        asm.framing = Framing::Raw;
+        assert_eq!(asm.assemble_str("03/4 1F/7 RQRY 001 01").to_bytes(), b"1cff080101".to_vec());
+    }
+}
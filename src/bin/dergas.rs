@@ -1,40 +1,184 @@
 extern crate rgas;
-use argparse::{ArgumentParser, StoreTrue, Store};
+use argparse::{ArgumentParser, StoreTrue, StoreFalse, Store, StoreConst, Collect};
 use std::fs::{File,OpenOptions};
 use std::io;
 use std::io::BufRead;
-use crate::rgas::UCGMessage;
+use std::path::Path;
+use std::process::exit;
+use crate::rgas::{check, UCGMessage};
+use crate::rgas::capture::{is_capture, open_capture};
+use crate::rgas::pcapng::{is_pcapng, PcapngWriter};
+use crate::rgas::config::Config;
+use crate::rgas::filter::{Filter, Range};
+use crate::rgas::disassembler::{Disassembler, RenderOptions};
+use crate::rgas::framing::Framing;
+use crate::rgas::hex::hexlify;
+use crate::rgas::sourcemap::SourceMap;
+use crate::rgas::stats::Stats;
 
-macro_rules! check {
-    ($result:expr, $message:literal) => {
-        match($result) {Ok(val) => {val} Err(err) => panic!($message, err)};
+// Assemble a line of our own output again, for --verify.
+fn reassemble(line: &str, immediate: bool) -> Result<Vec<u8>, String> {
+    let msg = if immediate {
+        rgas::UCGMessageInternal::parse_asm_line(line, false)
+    } else {
+        rgas::UCGScriptedMessageInternal::parse_asm_line(line, false)
     };
+    msg.map(|m| m.into_byte_vec())
 }
 
-macro_rules! checkNone {
-    ($result:expr, $message:literal) => {
-        match($result) {Some(val) => {val} None => panic!($message)};
-    };
+// Write the messages in a capture that pass the filter out as pcapng.  Returns the exit status.
+fn export_pcapng(fin: Box<dyn BufRead>, fout: &mut dyn io::Write, filter: &Filter) -> i32 {
+    let (header, records) = check!(open_capture(fin), "{}");
+    let mut out = check!(PcapngWriter::new(fout, &header), "write() call failed: {}");
+    for record in records {
+        let record = match record {
+            Ok(r) => r,
+            Err(e) => {
+                eprintln!("{}", e);
+                return 1;
+            }
+        };
+        // Records that aren't messages are kept, so the packet tool shows them too
+        let keep = match record.message() {
+            Some(m) => rgas::immediate_part(m.as_ref()).is_some_and(|m| filter.matches(m, None)),
+            None => true,
+        };
+        if keep {
+            check!(out.record_at(&record), "write() call failed: {}");
+        }
+    }
+    0
+}
+
+// Decode a capture of live traffic, either an rgas capture or pcapng.  Each line says when the
+// message went by, which way, and on which link.  Returns the exit status.
+fn dump_capture(fin: Box<dyn BufRead>, fout: &mut dyn io::Write, opts: &RenderOptions, filter: &Filter,
+                mut stats: Option<Stats>, json: bool, verbose: bool) -> i32 {
+    let (header, records) = check!(open_capture(fin), "{}");
+    if verbose {
+        println!("[!] Capture started at {} us after the epoch", header.start);
+        for (key, value) in &header.metadata {
+            println!("[!]   {}: {}", key, value);
+        }
+    }
+    let interfaces = header.interfaces.clone();
+    let mut status = 0;
+    for record in records {
+        let record = match (record, &mut stats) {
+            (Ok(r), _) => r,
+            (Err(e), Some(s)) => {
+                s.add_error(e);
+                break;
+            }
+            (Err(e), None) => {
+                eprintln!("{}", e);
+                status = 1;
+                break;
+            }
+        };
+        let message = match record.message() {
+            Some(m) => m,
+            None => {
+                let e = format!("Record at {:.6}s is not a message.", record.time.as_secs_f64());
+                match &mut stats {
+                    Some(s) => s.add_error(e),
+                    None => eprintln!("{}", e),
+                }
+                continue;
+            }
+        };
+        if !rgas::immediate_part(message.as_ref()).is_some_and(|m| filter.matches(m, None)) {
+            continue;
+        }
+        if let Some(s) = &mut stats {
+            s.add_message(message.as_ref(), None);
+            continue;
+        }
+        let interface = interfaces.get(record.interface as usize).map_or("?", |i| i.as_str());
+        let text = opts.symbols.name_line(&message.into_asm(opts.decimal), false);
+        check!(writeln!(fout, "{}  # {:.6}s {} {}", text, record.time.as_secs_f64(), record.direction.arrow(), interface),
+               "write() call failed: {}");
+        check!(fout.flush(), "flush() call failed: {}");
+    }
+    if let Some(s) = stats {
+        let report = if json { s.to_json() + "\n" } else { s.to_text() };
+        check!(fout.write(report.as_bytes()), "write() call failed: {}");
+    }
+    check!(fout.flush(), "flush() call failed: {}");
+    status
 }
 
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let mut config = match Config::for_args(&args) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(2);
+        }
+    };
     let mut infile = String::new();
     let mut outfile = String::new();
-    let mut decimal = false;
-    let mut immediate = false;
+    let mut decimal = config.decimal;
+    let mut immediate = config.immediate;
     let mut verbose = false;
+    let mut verify = false;
+    let mut annotate = false;
+    let mut show_stats = false;
+    let mut recover = false;
+    let mut json = false;
+    let mut pcapng = false;
+    let mut targets: Vec<String> = Vec::new();
+    let mut sources: Vec<String> = Vec::new();
+    let mut opcodes: Vec<String> = Vec::new();
+    let mut length = String::new();
+    let mut time = String::new();
+    let mut hex_input: Option<bool> = None;
+    let mut sourcemap_file = String::new();
+    let mut framing = config.framing.clone().unwrap_or_else(|| String::from("crlf"));
+    let mut symbol_files: Vec<String> = Vec::new();
+    let mut include_dirs: Vec<String> = Vec::new();
+    let mut config_file = String::new();
+    let mut no_config = false;
 
     {
         let mut ap=ArgumentParser::new();
-        ap.refer(&mut infile).add_argument("input", Store, "Input file.  The decompiler, obviously, does not have an interactive mode.");
+        ap.refer(&mut infile).add_argument("input", Store, "Input file, or - for stdin, which is also the default.  Messages are printed as soon as they arrive, so dergas can sit on a live capture.");
         ap.refer(&mut outfile).add_argument("output", Store, "Output file.  The default is stdout.");
-        ap.refer(&mut decimal).add_option(&["-d", "--decimal"], StoreTrue, "Output decimal data");
-        ap.refer(&mut immediate).add_option(&["-m", "--immediate"], StoreTrue, "Expect immediate commands.");
+        ap.refer(&mut decimal).add_option(&["-d", "--decimal"], StoreTrue, "Output decimal data")
+            .add_option(&["--hex-data"], StoreFalse, "Output hexadecimal data, whatever rgas.toml says.");
+        ap.refer(&mut immediate).add_option(&["-m", "--immediate"], StoreTrue, "Expect immediate commands.")
+            .add_option(&["--scripted"], StoreFalse, "Expect scripted commands, whatever rgas.toml says.");
         ap.refer(&mut verbose).add_option(&["-v", "--verbose"], StoreTrue, "Be more verbose.");
+        ap.refer(&mut annotate).add_option(&["-a", "--annotate"], StoreTrue, "Comment each message with its byte offset in the input (after hex decoding), the script time it runs at, and its payload read as other types.");
+        ap.refer(&mut targets).add_option(&["--target"], Collect, "Only show messages to this address: TT, TT/S or a symbol name.  May be repeated.");
+        ap.refer(&mut sources).add_option(&["--source"], Collect, "Only show messages from this address.  May be repeated.");
+        ap.refer(&mut opcodes).add_option(&["--op"], Collect, "Only show these opcodes, e.g. RQRY,RVAL.  May be repeated.");
+        ap.refer(&mut length).add_option(&["--length"], Store, "Only show payload lengths in this range: A..B, A.., ..B or A.");
+        ap.refer(&mut time).add_option(&["--time"], Store, "Only show scripted messages that run in this range of seconds from the start of the script.");
+        ap.refer(&mut recover).add_option(&["-r", "--recover"], StoreTrue, "Skip over corrupted stretches of input, reporting each on stderr, instead of stopping at the first bad message.");
+        ap.refer(&mut show_stats).add_option(&["--stats"], StoreTrue, "Summarize the file instead of disassembling it.  Decode errors are counted rather than fatal.");
+        ap.refer(&mut json).add_option(&["--json"], StoreTrue, "Write the --stats summary as JSON.");
+        ap.refer(&mut pcapng).add_option(&["--pcapng"], StoreTrue, "Convert a capture to pcapng, for Wireshark and other packet tools, keeping the messages that pass the filters.");
+        ap.refer(&mut verify).add_option(&["--verify"], StoreTrue, "Check that every message disassembles to text rgas turns back into the same bytes, instead of writing output.");
+        ap.refer(&mut hex_input).add_option(&["-x", "--hex"], StoreConst(Some(true)), "Input is hex text, as written by rgas -x.  By default this is detected from the start of the file.")
+            .add_option(&["--binary"], StoreConst(Some(false)), "Input is binary, even if it looks like hex text.");
+        ap.refer(&mut sourcemap_file).add_option(&["-s", "--sourcemap"], Store, "Source map written by rgas -s.  Restores comments and blank lines from the original source.");
+        ap.refer(&mut framing).add_option(&["-f", "--framing"], Store, "What follows each message: \"crlf\" (the default) or \"raw\" for nothing.");
+        Config::add_path_options(&mut ap, &mut symbol_files, &mut include_dirs);
+        Config::add_options(&mut ap, &mut config_file, &mut no_config);
         ap.parse_args_or_exit();
     }
+    let framing: Framing = check!(framing.parse(), "{}");
+    config.add_paths(&symbol_files, &include_dirs);
+    let symbols = check!(config.load_symbols(), "{}");
+    let mut filter = check!(Filter::from_options(&targets, &sources, &opcodes, &length, &symbols), "{}");
+    if !time.is_empty() {
+        filter.time = check!(Range::parse(&time), "{}");
+    }
+
     let stdout;
-    let mut fout: Box<dyn io::Write> = if outfile.len() == 0 {
+    let mut fout: Box<dyn io::Write> = if outfile.is_empty() {
         stdout = io::stdout();
         Box::new(stdout.lock())
     } else {
@@ -48,31 +192,147 @@ fn main() {
         }
     };
 
-    let mut fin = io::BufReader::new(check!(File::open(infile), "Unable to open input file: {}"));
-    loop {
-        let mut buf: Vec<u8> = Vec::new();
-        match fin.read_until(0x0A, &mut buf) {
-            Err(msg) => {panic!("Error reading line: {}", msg)}
-            Ok(0) => {break}
-            Ok(_) => {
-                // this seems incredibly unsafe, not passing it a length or anything.
-                // but the data has a length byte in it, so i guess i don't really care
-                buf.pop();
-                buf.pop();
-                if verbose {
-                    println!("[!] Parsing binary string {:x?}", buf);
+    let map = if sourcemap_file.is_empty() {
+        None
+    } else {
+        let fmap = io::BufReader::new(check!(File::open(sourcemap_file), "Unable to open source map file: {}"));
+        Some(check!(SourceMap::read_from(fmap), "Unable to read source map: {}"))
+    };
+
+    let mut fin: Box<dyn BufRead> = if infile.is_empty() || infile == "-" {
+        Box::new(io::stdin().lock())
+    } else {
+        Box::new(io::BufReader::new(check!(File::open(config.resolve(Path::new(&infile))), "Unable to open input file: {}")))
+    };
+    let opts = RenderOptions { decimal, symbols, annotate };
+    let stats = if show_stats { Some(Stats::new()) } else { None };
+    let sample = check!(fin.fill_buf(), "Read error: {}");
+    if is_capture(sample) || is_pcapng(sample) {
+        exit(if pcapng {
+            export_pcapng(fin, &mut fout, &filter)
+        } else {
+            dump_capture(fin, &mut fout, &opts, &filter, stats, json, verbose)
+        });
+    }
+    if pcapng {
+        eprintln!("--pcapng needs a capture, as written by rgas send --capture or rgas bridge --capture.");
+        exit(2);
+    }
+    // Hex input and container headers are detected here.  A container header says how the file
+    // was written, whatever the command line says.
+    let mut messages = check!(Disassembler::open(fin, hex_input, immediate, framing), "{}").recovering(recover);
+    let immediate = messages.immediate();
+    let hex = messages.hex;
+    if let (true, Some(header)) = (verbose, &messages.container) {
+        println!("[!] Container of {} {} messages", header.count, if immediate { "immediate" } else { "scripted" });
+        for (key, value) in &header.metadata {
+            println!("[!]   {}: {}", key, value);
+        }
+    }
+    let mut checked = 0;
+    let mut stats = stats;
+    let mut mismatches = 0;
+    for decoded in messages.by_ref() {
+        let decoded = match (decoded, &mut stats) {
+            (Ok(d), _) => d,
+            (Err(e), Some(s)) => {
+                s.add_error(e.to_string());
+                continue;
+            }
+            (Err(e), None) if recover => {
+                eprintln!("{}", e);
+                continue;
+            }
+            (Err(e), None) => {
+                // Without --recover the first bad message ends the run
+                eprintln!("{}", e);
+                exit(1);
+            }
+        };
+        if verbose {
+            println!("[!] Parsing binary string {:x?}", decoded.bytes);
+        }
+        let message = decoded.message.as_ref();
+        if !rgas::immediate_part(message).is_some_and(|m| filter.matches(m, decoded.time)) {
+            continue;
+        }
+        if let Some(s) = &mut stats {
+            s.add_message(message, decoded.time);
+            continue;
+        }
+        if verify {
+            // Go through the text exactly as it would be printed, symbol names and all
+            let text = decoded.asm(&opts);
+            checked += 1;
+            match reassemble(&opts.symbols.expand_line(&text, !immediate), immediate) {
+                Ok(bytes) if bytes == decoded.bytes => (),
+                Ok(bytes) => {
+                    mismatches += 1;
+                    eprintln!("message {} at offset {}: \"{}\" assembles to {} instead of {}", decoded.index, decoded.offset, text,
+                              String::from_utf8_lossy(&hexlify(&bytes)), String::from_utf8_lossy(&hexlify(&decoded.bytes)));
                 }
-                if buf.len() > 4 {
-                    let opcode = if immediate {
-                        checkNone!(rgas::UCGMessageInternal::from_byte_vec(&mut buf), "Parse error")
-                    } else {
-                        checkNone!(rgas::UCGScriptedMessageInternal::from_byte_vec(&mut buf), "Parse error")
-                    };
-                    check!(fout.write(opcode.into_asm(decimal).as_bytes()), "write() call failed: {}");
-                    check!(fout.write(b"\n"), "write() call failed: {}");
+                Err(e) => {
+                    mismatches += 1;
+                    eprintln!("message {} at offset {}: \"{}\" does not assemble: {}", decoded.index, decoded.offset, text, e);
                 }
             }
+            continue;
+        }
+        // Only trust the source map if it describes a message of this size at this offset.  Offsets
+        // in the map count hex digits when the input is hex, so there we can only go by position.
+        let location = map.as_ref().and_then(|m| if hex {
+            m.messages.get(decoded.index)
+        } else {
+            m.at_offset(decoded.offset).filter(|l| l.length == decoded.length)
+        });
+        if let Some(l) = location {
+            for (_, text) in &l.leading {
+                check!(writeln!(fout, "{}", text), "write() call failed: {}");
+            }
         }
+        check!(fout.write(decoded.asm(&opts).as_bytes()), "write() call failed: {}");
+        let mut comments: Vec<String> = location.iter()
+            .filter(|l| !l.comment.is_empty())
+            .map(|l| l.comment.clone())
+            .collect();
+        if annotate {
+            comments.push(decoded.annotation());
+        }
+        if !comments.is_empty() {
+            check!(write!(fout, "  # {}", comments.join("; ")), "write() call failed: {}");
+        }
+        check!(fout.write(b"\n"), "write() call failed: {}");
+        // Don't sit on output when reading from a pipe or a serial port
+        check!(fout.flush(), "flush() call failed: {}");
     }
-
-}
\ No newline at end of file
+    let container_problem = messages.check_container().err();
+    if let Some(mut s) = stats {
+        if let Some(problem) = container_problem {
+            s.add_error(problem);
+        }
+        let report = if json { s.to_json() + "\n" } else { s.to_text() };
+        check!(fout.write(report.as_bytes()), "write() call failed: {}");
+        return;
+    }
+    if verify {
+        if mismatches > 0 {
+            eprintln!("{} of {} messages do not survive a round trip.", mismatches, checked);
+            exit(1);
+        }
+        if let Some(problem) = container_problem {
+            eprintln!("{}", problem);
+            exit(1);
+        }
+        eprintln!("All {} messages reassemble to identical bytes.", checked);
+        return;
+    }
+    if let Some(m) = &map {
+        for (_, text) in &m.trailing {
+            check!(writeln!(fout, "{}", text), "write() call failed: {}");
+        }
+    }
+    if let Some(problem) = container_problem {
+        eprintln!("{}", problem);
+        exit(1);
+    }
+}
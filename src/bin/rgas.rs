@@ -1,105 +1,819 @@
 extern crate rgas;
 extern crate argparse;
-use argparse::{ArgumentParser, StoreTrue, Store, Print};
-use std::io::prelude::*;
+use argparse::{ArgumentParser, StoreTrue, StoreFalse, Store, StoreOption, Print, List, Collect};
 use std::fs;
 use std::io;
-use std::io::BufRead;
+use std::io::{BufRead, IsTerminal, Read};
 use std::io::Write;
+use std::path::Path;
 use std::process::exit;
-use rgas::UCGMessage;
+use std::sync::atomic::AtomicBool;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use rgas::{check, checkNone, formatter, immediate_part, lint, Severity, UCGMessage, UCGMessageInternal, UCGScriptedMessageInternal};
+use rgas::assembler::{AssembledMessage, Assembler};
+use rgas::bridge::{self, Incoming, Side};
+use rgas::config::Config;
+use rgas::disassembler::{Disassembler, RenderOptions};
+use rgas::capture::{self, CaptureHeader, CaptureWriter, Direction, RecordSink, RecordingTransport, ReplayOptions};
+use rgas::container::ContainerHeader;
+use rgas::correlate::{Correlator, Event, Policy};
+use rgas::filter::Filter;
+use rgas::framing::Framing;
+use rgas::monitor::Monitor;
+use rgas::pcapng::PcapngWriter;
+use rgas::player::{Player, PlayerControl, PlayerOptions, SystemClock};
+use rgas::sourcemap::SourceMap;
+use rgas::timeline::{build_timeline, TimelineOptions};
+use rgas::transport::{receive_until_quiet, ChannelTransport, SerialTransport, TcpTransport, Transport};
 
-macro_rules! check {
-    ($result:expr, $message:literal) => {
-        match($result) {Ok(val) => {val} Err(err) => panic!($message, err)};
+// for unit testing.
+// Assemble one input, writing each message as soon as it is done so interactive use sees it straight away.
+fn process_file<R: BufRead>(fin: R, fout: &mut dyn io::Write, asm: &mut Assembler, interactive: bool, srcname: &str,
+                            map: &mut Option<SourceMap>, collect: &mut Vec<AssembledMessage>) {
+    for (lineno, line) in fin.lines().enumerate() {
+        let lineno = lineno + 1;
+        let line = check!(line, "readline() failed: {}");
+        match asm.line(lineno, &line) {
+            Ok(Some(m)) => {
+                check!(fout.write(&m.encoded), "write() call failed: {}");
+                if let Some(map) = map.as_mut() {
+                    map.add_message(srcname, lineno, m.offset, m.encoded.len(), &m.comment);
+                }
+                collect.push(m);
+            }
+            Ok(None) => {
+                // Comment or blank line
+                if let Some(map) = map.as_mut() {
+                    map.add_line(srcname, lineno, &line);
+                }
+            }
+            Err(msg) => {
+                if interactive {
+                    println!("parse error: {}", msg);
+                    // TODO make rustyline put the previous line right back into the linebuffer.
+                } else {
+                    panic!("parse error on line {}: {}", lineno, msg);
+                }
+            }
+        }
+    }
+}
+
+// rgas lint: report suspicious but well-formed statements.
+fn lint_main(args: Vec<String>, mut config: Config) -> i32 {
+    let mut immediate = config.immediate;
+    let mut files: Vec<String> = Vec::new();
+    let mut config_file = String::new();
+    let mut no_config = false;
+    let mut symbol_files: Vec<String> = Vec::new();
+    let mut include_dirs: Vec<String> = Vec::new();
+    let mut allow: Vec<String> = Vec::new();
+    let mut warn: Vec<String> = Vec::new();
+    let mut deny: Vec<String> = Vec::new();
+    let mut list_rules = false;
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Check UCGv2 assembly sources for likely mistakes.");
+        ap.refer(&mut immediate)
+            .add_option(&["-m", "--immediate"], StoreTrue, "Sources are in UCGv2 immediate mode.")
+            .add_option(&["--scripted"], StoreFalse, "Sources are in scripted mode, whatever rgas.toml says.");
+        Config::add_options(&mut ap, &mut config_file, &mut no_config);
+        Config::add_path_options(&mut ap, &mut symbol_files, &mut include_dirs);
+        ap.refer(&mut allow)
+            .add_option(&["-A", "--allow"], Collect, "Turn a rule off.");
+        ap.refer(&mut warn)
+            .add_option(&["-W", "--warn"], Collect, "Report a rule as a warning.");
+        ap.refer(&mut deny)
+            .add_option(&["-D", "--deny"], Collect, "Report a rule as an error.");
+        ap.refer(&mut list_rules)
+            .add_option(&["--rules"], StoreTrue, "List the available rules and exit.");
+        ap.refer(&mut files)
+            .add_argument("files", List, "Assembly files to check.");
+        if let Err(code) = ap.parse(args, &mut io::stdout(), &mut io::stderr()) {
+            return code;
+        }
+    }
+    config.add_paths(&symbol_files, &include_dirs);
+    if list_rules {
+        for rule in lint::RULES {
+            println!("{:<18} {:<8} {}", rule.id, rule.severity, rule.description);
+        }
+        return 0;
+    }
+    let mut opts = lint::LintOptions {
+        immediate,
+        symbols: check!(config.load_symbols(), "{}"),
+        levels: check!(config.lint_levels(), "{}"),
     };
+    for (ids, level) in [(&allow, None), (&warn, Some(Severity::Warning)), (&deny, Some(Severity::Error))] {
+        for id in ids {
+            if lint::find_rule(id).is_none() {
+                eprintln!("Unknown lint rule: {}", id);
+                return 2;
+            }
+            opts.levels.insert(id.clone(), level);
+        }
+    }
+    let mut errors = 0;
+    let mut warnings = 0;
+    for file in &files {
+        let path = config.resolve(Path::new(file));
+        let src = check!(fs::read_to_string(&path), "Unable to open input file: {}");
+        for d in lint::lint_source(&src, &opts) {
+            println!("{}:{}: {}[{}]: {}", file, d.line, d.severity, d.rule, d.message);
+            match d.severity {
+                Severity::Error => errors += 1,
+                Severity::Warning => warnings += 1,
+            }
+        }
+    }
+    println!("{} error(s), {} warning(s).", errors, warnings);
+    if errors > 0 { 1 } else { 0 }
 }
 
-fn hexlify(vec:&[u8]) -> Vec<u8> {
-    let hex = b"0123456789abcdef";
-    let mut ret: Vec<u8> = Vec::with_capacity(vec.len()*2);
-    for ch in vec {
-        ret.push(hex[((ch & 0xf0) >> 4) as usize]);
-        ret.push(hex[(ch & 0x0f) as usize]);
+// rgas fmt: rewrite sources into the canonical layout.
+fn fmt_main(args: Vec<String>, mut config: Config) -> i32 {
+    let mut immediate = config.immediate;
+    let mut check_only = false;
+    let mut config_file = String::new();
+    let mut no_config = false;
+    let mut symbol_files: Vec<String> = Vec::new();
+    let mut include_dirs: Vec<String> = Vec::new();
+    let mut files: Vec<String> = Vec::new();
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Rewrite UCGv2 assembly sources into the canonical layout.");
+        ap.refer(&mut immediate)
+            .add_option(&["-m", "--immediate"], StoreTrue, "Sources are in UCGv2 immediate mode.")
+            .add_option(&["--scripted"], StoreFalse, "Sources are in scripted mode, whatever rgas.toml says.");
+        Config::add_options(&mut ap, &mut config_file, &mut no_config);
+        Config::add_path_options(&mut ap, &mut symbol_files, &mut include_dirs);
+        ap.refer(&mut check_only)
+            .add_option(&["--check"], StoreTrue, "Don't rewrite anything; fail if any file is not formatted.");
+        ap.refer(&mut files)
+            .add_argument("files", List, "Assembly files to format in place.");
+        if let Err(code) = ap.parse(args, &mut io::stdout(), &mut io::stderr()) {
+            return code;
+        }
     }
-    return ret;
+    config.add_paths(&symbol_files, &include_dirs);
+    let opts = formatter::FormatOptions { immediate, symbols: check!(config.load_symbols(), "{}") };
+    let mut status = 0;
+    for file in &files {
+        let path = config.resolve(Path::new(file));
+        let src = check!(fs::read_to_string(&path), "Unable to open input file: {}");
+        match formatter::format_source(&src, &opts) {
+            Ok(formatted) if formatted == src => (),
+            Ok(formatted) => {
+                if check_only {
+                    println!("{}: not formatted", file);
+                    status = 1;
+                } else {
+                    check!(fs::write(&path, formatted), "Unable to write output file: {}");
+                    println!("{}: formatted", file);
+                }
+            }
+            Err(errors) => {
+                for (line, msg) in errors {
+                    println!("{}:{}: {}", file, line, msg);
+                }
+                status = 1;
+            }
+        }
+    }
+    status
 }
 
-// for unit testing.
-fn test_parse(line: &String, _verbose:bool) -> Result<Vec<u8>, String> {
-    return Ok(Vec::from(line.as_bytes()));
+// Start a capture file recording traffic on the given links.  Files named .pcapng are written
+// as pcapng, for packet tools; anything else is an rgas capture.
+fn create_capture(path: &str, interfaces: &[&str]) -> Result<Box<dyn RecordSink>, String> {
+    let mut header = CaptureHeader::new(interfaces);
+    header.metadata.push((String::from("creator"), format!("rgas {}", env!("CARGO_PKG_VERSION"))));
+    let file = fs::File::create(path).map_err(|e| format!("Unable to create capture file: {}", e))?;
+    let failed = |e: io::Error| format!("Unable to write capture file: {}", e);
+    if path.ends_with(".pcapng") {
+        Ok(Box::new(PcapngWriter::new(file, &header).map_err(failed)?))
+    } else {
+        Ok(Box::new(CaptureWriter::new(file, &header).map_err(failed)?))
+    }
 }
 
-macro_rules! process_file {
-    ($fin:expr, $fout:expr, $verbose:expr, $hex:expr, $interactive:expr, $immediate:expr, $timedest:expr) => {
-    let mut lineno = 1;
-    for line in $fin.lines() {
-        match(line) {
-            Err(err) => {panic!("readline() failed: {}", err)}
-            Ok(line) => {
-                if line.chars().nth(0) == Some('#') {
-                    // This line is a comment
-                    continue
+// Send each command in one input and print what comes back, noting replies that answer
+// nothing and commands that get no answer.
+fn send_lines<R: BufRead>(input: R, name: &str, link: &mut dyn Transport, asm: &mut Assembler,
+                          opts: &RenderOptions, tracker: &mut Correlator, started: Instant) -> i32 {
+    // A reply gets as long as the line takes to go quiet
+    let wait = tracker.default.timeout;
+    let mut status = 0;
+    for (i, line) in input.lines().enumerate() {
+        let line = check!(line, "readline() failed: {}");
+        let msg = match asm.line(i + 1, &line) {
+            Ok(Some(m)) => m,
+            Ok(None) => continue,
+            Err(e) => {
+                println!("{}:{}: {}", name, i + 1, e);
+                status = 1;
+                continue;
+            }
+        };
+        let line = rgas::split_comment(&line).0.trim();
+        println!("> {}", line);
+        tracker.send(checkNone!(immediate_part(msg.message.as_ref()), "Not an immediate message"), started.elapsed());
+        let mut bytes = msg.encoded;
+        loop {
+            if let Err(e) = link.send(&bytes) {
+                println!("Unable to send: {}", e);
+                return 1;
+            }
+            let reply = match receive_until_quiet(link, wait) {
+                Ok(r) => r,
+                Err(e) => {
+                    println!("Unable to receive: {}", e);
+                    return 1;
                 }
-                let res: Result<Box<dyn UCGMessage>, String> = if $immediate {
-                    rgas::UCGMessageInternal::parse_asm_line(&line, false)
-                } else {
-                    rgas::UCGScriptedMessageInternal::parse_asm_line(&line, false)
-                };
-                match(res) {
-                //match(test_parse(&line, $verbose)) {
-                    Ok(bytecode) => {
-                        if !$immediate {
-                            *$timedest = *$timedest + bytecode.get_time();
-                        }
-                        let mut bytes = bytecode.into_byte_vec();
-                        //let mut bytes = bytecode;
-                        
-                        // i know i don't have to put parenthesees around my if statements, but old habits die hard
-                        if $hex {
-                           bytes = hexlify(&bytes);
-                        }
-                        
-                        check!($fout.write(&bytes), "write() call failed: {}");
-                        check!($fout.write(b"\r\n"), "write() call failed: {}");
+            };
+            for decoded in Disassembler::new(&reply[..], true, asm.framing).recovering(true) {
+                let d = match decoded {
+                    Ok(d) => d,
+                    Err(e) => {
+                        println!("< {}", e);
+                        continue;
                     }
-                    Err(msg) => {
-                        // the compiler returns an error with an empty string on comment lines.
-                        // i mean, idk how else you would do it, but that was a quirk i was not prepared for
-                        if msg.len() != 0 {
-                            if $interactive {
-                                println!("parse error: {}", msg);
-                                // TODO make rustyline put the previous line right back into the linebuffer.
-                            } else {
-                                panic!("parse error on line {}: {}", lineno, msg);
-                            }
-                        }
+                };
+                let reply = checkNone!(immediate_part(d.message.as_ref()), "Not an immediate message");
+                match tracker.receive(reply, started.elapsed()) {
+                    Event::Unexpected => println!("< {}  # unexpected", d.asm(opts)),
+                    _ => println!("< {}", d.asm(opts)),
+                }
+            }
+            let mut retry = false;
+            for event in tracker.poll(started.elapsed()) {
+                match event {
+                    Event::Retry(r) => {
+                        println!("> {}  # retry {}", line, r.attempts - 1);
+                        bytes = r.bytes;
+                        bytes.extend_from_slice(asm.framing.trailer());
+                        retry = true;
                     }
+                    Event::TimedOut(r) => println!("! no reply to {}", r.describe()),
+                    _ => (),
                 }
             }
+            if !retry {
+                break;
+            }
+        }
+    }
+    status
+}
+
+// rgas send: assemble immediate commands and send them down a serial line, printing any replies.
+fn send_main(args: Vec<String>, mut config: Config) -> i32 {
+    let mut port = config.port.clone().unwrap_or_default();
+    let mut baud = config.baud.unwrap_or(115200);
+    let mut framing = config.framing.clone().unwrap_or_else(|| String::from("crlf"));
+    let mut wait: u64 = 500;
+    let mut retries: u32 = 0;
+    let mut capture = String::new();
+    let mut config_file = String::new();
+    let mut no_config = false;
+    let mut symbol_files: Vec<String> = Vec::new();
+    let mut include_dirs: Vec<String> = Vec::new();
+    let mut files: Vec<String> = Vec::new();
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Send UCGv2 immediate commands to a device over a serial line and print the replies.");
+        ap.refer(&mut port)
+            .add_option(&["-p", "--port"], Store, "Serial port the device is on, e.g. /dev/ttyUSB0.");
+        ap.refer(&mut baud)
+            .add_option(&["-b", "--baud"], Store, "Baud rate.  The default is 115200.");
+        ap.refer(&mut framing)
+            .add_option(&["-f", "--framing"], Store, "What follows each message: \"crlf\" (the default) or \"raw\" for nothing.");
+        ap.refer(&mut wait)
+            .add_option(&["-w", "--wait"], Store, "How long the line must be quiet, in milliseconds, before moving on to the next command.  The default is 500.");
+        ap.refer(&mut retries)
+            .add_option(&["-r", "--retries"], Store, "Send a command again this many times if nothing answers it.  The default is 0.");
+        ap.refer(&mut capture)
+            .add_option(&["-c", "--capture"], Store, "Record everything sent and received to this capture file, for dergas or rgas replay.  A name ending .pcapng writes pcapng instead.");
+        Config::add_options(&mut ap, &mut config_file, &mut no_config);
+        Config::add_path_options(&mut ap, &mut symbol_files, &mut include_dirs);
+        ap.refer(&mut files)
+            .add_argument("files", List, "Files of commands to send.  Commands are read from stdin if there are none.");
+        if let Err(code) = ap.parse(args, &mut io::stdout(), &mut io::stderr()) {
+            return code;
+        }
+    }
+    config.add_paths(&symbol_files, &include_dirs);
+    if port.is_empty() {
+        println!("No serial port given.  Use --port or set port in {}.", rgas::config::CONFIG_FILE_NAME);
+        return 2;
+    }
+    let mut asm = Assembler::new(true);
+    asm.framing = check!(framing.parse(), "{}");
+    asm.symbols = check!(config.load_symbols(), "{}");
+    let opts = RenderOptions { symbols: asm.symbols.clone(), ..RenderOptions::default() };
+    let serial = match SerialTransport::open(&port, baud) {
+        Ok(l) => l,
+        Err(e) => {
+            println!("{}", e);
+            return 1;
+        }
+    };
+    let mut link: Box<dyn Transport> = if capture.is_empty() {
+        Box::new(serial)
+    } else {
+        let writer = check!(create_capture(&capture, &[&port]), "{}");
+        Box::new(RecordingTransport::new(serial, writer, 0, asm.framing))
+    };
+    let mut tracker = Correlator::new();
+    tracker.default = Policy { timeout: Duration::from_millis(wait), retries };
+    let started = Instant::now();
+    if files.is_empty() {
+        return send_lines(io::stdin().lock(), "<stdin>", link.as_mut(), &mut asm, &opts, &mut tracker, started);
+    }
+    let mut status = 0;
+    for file in &files {
+        let path = config.resolve(Path::new(file));
+        let fin = io::BufReader::new(check!(fs::File::open(&path), "Unable to open input file: {}"));
+        status = status.max(send_lines(fin, file, link.as_mut(), &mut asm, &opts, &mut tracker, started));
+    }
+    status
+}
+
+// rgas bridge: pass messages between two links, e.g. a serial port and a TCP socket.
+fn bridge_main(args: Vec<String>, mut config: Config) -> i32 {
+    let mut framing = config.framing.clone().unwrap_or_else(|| String::from("crlf"));
+    let mut verbose = false;
+    let mut capture = String::new();
+    let mut tap_addr = String::new();
+    let mut config_file = String::new();
+    let mut no_config = false;
+    let mut symbol_files: Vec<String> = Vec::new();
+    let mut include_dirs: Vec<String> = Vec::new();
+    let mut a = String::new();
+    let mut b = String::new();
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Forward UCGv2 messages both ways between two links.  Each end is one of \
+                            serial:PORT[@BAUD], tcp:HOST:PORT, tcp-listen:ADDR:PORT, udp:ADDR:PORT or udp:ADDR:PORT,PEER:PORT.  \
+                            Serial and TCP carry framed streams; UDP carries one unframed message per datagram.");
+        ap.refer(&mut framing)
+            .add_option(&["-f", "--framing"], Store, "What follows each message on serial and TCP links: \"crlf\" (the default) or \"raw\" for nothing.");
+        ap.refer(&mut verbose)
+            .add_option(&["-v", "--verbose"], StoreTrue, "Print every message forwarded.");
+        ap.refer(&mut capture)
+            .add_option(&["-c", "--capture"], Store, "Record every message forwarded to this capture file, against the end it came in at.  Messages from b are recorded as sent and messages from a as received, so with the ground on b, rgas replay sends its commands again by default.  A name ending .pcapng writes pcapng instead.");
+        ap.refer(&mut tap_addr)
+            .add_option(&["--tap"], Store, "Also listen at this address, e.g. 127.0.0.1:5100, and send every connection there a copy of \
+                                            the messages going both ways, without letting it send any.  For rgas monitor --tcp.");
+        Config::add_options(&mut ap, &mut config_file, &mut no_config);
+        Config::add_path_options(&mut ap, &mut symbol_files, &mut include_dirs);
+        ap.refer(&mut a)
+            .add_argument("a", Store, "The device end, e.g. serial:/dev/ttyUSB0.")
+            .required();
+        ap.refer(&mut b)
+            .add_argument("b", Store, "The ground end, e.g. tcp-listen:0.0.0.0:5000.")
+            .required();
+        if let Err(code) = ap.parse(args, &mut io::stdout(), &mut io::stderr()) {
+            return code;
+        }
+    }
+    config.add_paths(&symbol_files, &include_dirs);
+    let framing: Framing = check!(framing.parse(), "{}");
+    let symbols = check!(config.load_symbols(), "{}");
+    let mut links = Vec::new();
+    for end in [&a, &b] {
+        match bridge::open_link(end, framing) {
+            Ok(l) => links.push(l),
+            Err(e) => {
+                println!("{}", e);
+                return 1;
+            }
+        }
+    }
+    let (mut link_b, mut link_a) = (links.pop().unwrap(), links.pop().unwrap());
+    let mut writer = if capture.is_empty() {
+        None
+    } else {
+        Some(check!(create_capture(&capture, &[&a, &b]), "{}"))
+    };
+    let mut tap = if tap_addr.is_empty() {
+        None
+    } else {
+        match bridge::Tap::bind(&tap_addr, framing) {
+            Ok(t) => Some(t),
+            Err(e) => {
+                println!("{}", e);
+                return 1;
+            }
+        }
+    };
+    println!("Bridging {} and {}", a, b);
+    let stop = AtomicBool::new(false);
+    let result = bridge::run(link_a.as_mut(), link_b.as_mut(), tap.as_mut(), &stop, |side, incoming| {
+        let (from, to) = match side {
+            Side::A => (&a, &b),
+            Side::B => (&b, &a),
+            Side::Tap => (&tap_addr, &tap_addr),
         };
-        lineno+=1;
+        if let (Some(w), Incoming::Message(m)) = (writer.as_mut(), incoming) {
+            // Traffic from b towards a is what the ground sent, so that's what rgas replay picks by default
+            let (direction, interface) = if side == Side::A { (Direction::Received, 0) } else { (Direction::Sent, 1) };
+            check!(w.record(direction, interface, m), "Unable to write capture file: {}");
+        }
+        match incoming {
+            Incoming::Message(m) if verbose => {
+                let text = match UCGMessageInternal::from_byte_vec(&mut m.clone()) {
+                    Some(msg) => symbols.name_line(&msg.into_asm(false), false),
+                    None => String::from_utf8(rgas::hex::hexlify(m)).unwrap_or_default(),
+                };
+                println!("{} -> {}: {}", from, to, text);
+            }
+            Incoming::Message(_) => (),
+            Incoming::Notice(n) => println!("{}: {}", from, n),
+        }
+    });
+    if let Err(e) = result {
+        println!("Bridge stopped: {}", e);
+        return 1;
     }
+    0
+}
+
+// rgas monitor: decode traffic as it arrives, one line per message.
+fn monitor_main(args: Vec<String>, mut config: Config) -> i32 {
+    let mut port = String::new();
+    let mut baud = config.baud.unwrap_or(115200);
+    let mut tcp = String::new();
+    let mut framing = config.framing.clone().unwrap_or_else(|| String::from("crlf"));
+    let mut colour = io::stdout().is_terminal();
+    let mut targets: Vec<String> = Vec::new();
+    let mut sources: Vec<String> = Vec::new();
+    let mut opcodes: Vec<String> = Vec::new();
+    let mut length = String::new();
+    let mut config_file = String::new();
+    let mut no_config = false;
+    let mut symbol_files: Vec<String> = Vec::new();
+    let mut include_dirs: Vec<String> = Vec::new();
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Decode UCGv2 traffic as it arrives and print a line for each message: when it came, \
+                            > for commands and < for responses, where it went, its opcode and its payload.  \
+                            Reads a serial port, an rgas bridge tap or, by default, stdin.");
+        ap.refer(&mut port)
+            .add_option(&["-p", "--port"], Store, "Serial port to listen on, e.g. /dev/ttyUSB0.");
+        ap.refer(&mut baud)
+            .add_option(&["-b", "--baud"], Store, "Baud rate.  The default is 115200.");
+        ap.refer(&mut tcp)
+            .add_option(&["--tcp"], Store, "Connect to the --tap address of an rgas bridge, e.g. 127.0.0.1:5100, and show the traffic both ways.  \
+                                           Other TCP servers may serve one client at a time, or only pass one direction.");
+        ap.refer(&mut framing)
+            .add_option(&["-f", "--framing"], Store, "What follows each message: \"crlf\" (the default) or \"raw\" for nothing.");
+        ap.refer(&mut colour)
+            .add_option(&["--colour", "--color"], StoreTrue, "Colour the output even when it isn't going to a terminal.")
+            .add_option(&["--no-colour", "--no-color"], StoreFalse, "Don't colour the output.");
+        ap.refer(&mut targets)
+            .add_option(&["--target"], Collect, "Only show messages to this address: TT, TT/S or a symbol name.  May be repeated.");
+        ap.refer(&mut sources)
+            .add_option(&["--source"], Collect, "Only show messages from this address.  May be repeated.");
+        ap.refer(&mut opcodes)
+            .add_option(&["--op"], Collect, "Only show these opcodes, e.g. RQRY,RVAL.  May be repeated.");
+        ap.refer(&mut length)
+            .add_option(&["--length"], Store, "Only show payload lengths in this range: A..B, A.., ..B or A.");
+        Config::add_options(&mut ap, &mut config_file, &mut no_config);
+        Config::add_path_options(&mut ap, &mut symbol_files, &mut include_dirs);
+        if let Err(code) = ap.parse(args, &mut io::stdout(), &mut io::stderr()) {
+            return code;
+        }
+    }
+    config.add_paths(&symbol_files, &include_dirs);
+    if !port.is_empty() && !tcp.is_empty() {
+        println!("Give either --port or --tcp, not both.");
+        return 2;
+    }
+    let symbols = check!(config.load_symbols(), "{}");
+    let mut monitor = Monitor::new(check!(framing.parse(), "{}"), &symbols);
+    monitor.colour = colour;
+    monitor.filter = check!(Filter::from_options(&targets, &sources, &opcodes, &length, &symbols), "{}");
+    let from_stdin = port.is_empty() && tcp.is_empty();
+    let opened: Result<Box<dyn Transport>, String> = if !port.is_empty() {
+        SerialTransport::open(&port, baud).map(|l| Box::new(l) as Box<dyn Transport>)
+    } else if !tcp.is_empty() {
+        TcpTransport::connect(&tcp).map(|l| Box::new(l) as Box<dyn Transport>)
+    } else {
+        // Read stdin on its own thread, so the loop below treats it like any other link
+        let (mut feed, link) = ChannelTransport::pair();
+        thread::spawn(move || {
+            let mut chunk = [0u8; 1024];
+            while let Ok(n @ 1..) = io::stdin().lock().read(&mut chunk) {
+                if feed.send(&chunk[..n]).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(Box::new(link))
+    };
+    let mut link = match opened {
+        Ok(l) => l,
+        Err(e) => {
+            println!("{}", e);
+            return 1;
+        }
+    };
+    let started = Instant::now();
+    let mut chunk = [0u8; 1024];
+    loop {
+        let n = match link.receive(&mut chunk, Duration::from_millis(100)) {
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                if !from_stdin {
+                    println!("{}", monitor.notice(&e.to_string(), started.elapsed()));
+                }
+                return 0;
+            }
+            Err(e) => {
+                println!("{}", monitor.notice(&format!("Unable to receive: {}", e), started.elapsed()));
+                return 1;
+            }
+        };
+        for line in monitor.feed(&chunk[..n], started.elapsed()) {
+            println!("{}", line);
+        }
+    }
+}
+
+// Let p, r and q typed on stdin pause, resume and stop rgas play or rgas replay.
+fn spawn_stdin_control(control: PlayerControl) {
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            match line.as_deref().map(str::trim) {
+                Ok("p") => control.pause(),
+                Ok("r") => control.resume(),
+                Ok("q") | Err(_) => return control.stop(),
+                _ => (),
+            }
+        }
+    });
+}
+
+// The serial port rgas play or rgas replay sends to, or None for a dry run.  Err is the exit status.
+fn open_player_link(port: &str, baud: u32, dry_run: bool) -> Result<Option<SerialTransport>, i32> {
+    if dry_run {
+        return Ok(None);
+    }
+    if port.is_empty() {
+        println!("No serial port given.  Use --port or set port in {}, or --dry-run.", rgas::config::CONFIG_FILE_NAME);
+        return Err(2);
+    }
+    match SerialTransport::open(port, baud) {
+        Ok(l) => Ok(Some(l)),
+        Err(e) => {
+            println!("{}", e);
+            Err(1)
+        }
+    }
+}
+
+// rgas replay: send messages from a capture again, with the timing they had.
+fn replay_main(args: Vec<String>, mut config: Config) -> i32 {
+    let mut port = config.port.clone().unwrap_or_default();
+    let mut baud = config.baud.unwrap_or(115200);
+    let mut framing = config.framing.clone().unwrap_or_else(|| String::from("crlf"));
+    let mut dry_run = false;
+    let mut speed: f64 = 1.0;
+    let mut received = false;
+    let mut interface = String::new();
+    let mut config_file = String::new();
+    let mut no_config = false;
+    let mut symbol_files: Vec<String> = Vec::new();
+    let mut include_dirs: Vec<String> = Vec::new();
+    let mut file = String::new();
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Send the messages in a capture down a serial line again, with the same gaps between them as when they were recorded.  \
+                            While replaying, type p and enter to pause, r to resume and q to stop.");
+        ap.refer(&mut port)
+            .add_option(&["-p", "--port"], Store, "Serial port to send to, e.g. /dev/ttyUSB0.");
+        ap.refer(&mut baud)
+            .add_option(&["-b", "--baud"], Store, "Baud rate.  The default is 115200.");
+        ap.refer(&mut framing)
+            .add_option(&["-f", "--framing"], Store, "What follows each message: \"crlf\" (the default) or \"raw\" for nothing.");
+        ap.refer(&mut dry_run)
+            .add_option(&["-n", "--dry-run"], StoreTrue, "Go through the timing without sending anything.");
+        ap.refer(&mut speed)
+            .add_option(&["-s", "--speed"], Store, "Replay this many times faster than it was recorded.  The default is 1.");
+        ap.refer(&mut received)
+            .add_option(&["--received"], StoreTrue, "Replay the messages the recording host received, rather than the ones it sent.  In a capture from rgas bridge, those are the ones that came from end a.");
+        ap.refer(&mut interface)
+            .add_option(&["-i", "--interface"], Store, "Only replay messages recorded on this link, as named in the capture.");
+        Config::add_options(&mut ap, &mut config_file, &mut no_config);
+        Config::add_path_options(&mut ap, &mut symbol_files, &mut include_dirs);
+        ap.refer(&mut file)
+            .add_argument("file", Store, "Capture to replay, as written by --capture: an rgas capture or pcapng.")
+            .required();
+        if let Err(code) = ap.parse(args, &mut io::stdout(), &mut io::stderr()) {
+            return code;
+        }
+    }
+    config.add_paths(&symbol_files, &include_dirs);
+    let fin = io::BufReader::new(check!(fs::File::open(config.resolve(Path::new(&file))), "Unable to open capture file: {}"));
+    let (header, records) = check!(capture::open_capture(fin), "{}");
+    let interfaces = header.interfaces;
+    let records = match records.collect::<Result<Vec<_>, _>>() {
+        Ok(r) => r,
+        Err(e) => {
+            println!("{}: {}", file, e);
+            return 1;
+        }
+    };
+    let mut opts = ReplayOptions {
+        speed,
+        direction: if received { Direction::Received } else { Direction::Sent },
+        framing: check!(framing.parse(), "{}"),
+        dry_run,
+        ..ReplayOptions::default()
+    };
+    if !interface.is_empty() {
+        match interfaces.iter().position(|i| *i == interface) {
+            Some(i) => opts.interface = Some(i as u8),
+            None => {
+                println!("{} has no link named {}.  It has: {}", file, interface, interfaces.join(", "));
+                return 1;
+            }
+        }
+    }
+    let control = PlayerControl::new();
+    spawn_stdin_control(control.clone());
+    let mut link = match open_player_link(&port, baud, dry_run) {
+        Ok(l) => l,
+        Err(code) => return code,
+    };
+    let symbols = check!(config.load_symbols(), "{}");
+    let result = capture::replay(&records, link.as_mut().map(|l| l as &mut dyn Transport), &opts, &SystemClock::new(), &control, |r, sent| {
+        let text = match r.message() {
+            Some(m) => symbols.name_line(&m.into_asm(false), false),
+            None => String::from_utf8(rgas::hex::hexlify(&r.bytes)).unwrap_or_default(),
+        };
+        println!("[{:>9.3}s] {}", sent.as_secs_f64(), text);
+    });
+    match result {
+        Ok(n) => {
+            println!("{} messages replayed.", n);
+            0
+        }
+        Err(e) => {
+            println!("Unable to send: {}", e);
+            1
+        }
+    }
+}
+
+// rgas play: send a script's messages down a serial line at the times it gives.
+fn play_main(args: Vec<String>, mut config: Config) -> i32 {
+    let mut port = config.port.clone().unwrap_or_default();
+    let mut baud = config.baud.unwrap_or(115200);
+    let mut framing = config.framing.clone().unwrap_or_else(|| String::from("crlf"));
+    let mut dry_run = false;
+    let mut speed: f64 = 1.0;
+    let mut start: usize = 0;
+    let mut log_file = String::new();
+    let mut config_file = String::new();
+    let mut no_config = false;
+    let mut symbol_files: Vec<String> = Vec::new();
+    let mut include_dirs: Vec<String> = Vec::new();
+    let mut file = String::new();
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Play a UCGv2 script in real time, sending each message when its timestamp comes round.  \
+                            While playing, type p and enter to pause, r to resume and q to stop.");
+        ap.refer(&mut port)
+            .add_option(&["-p", "--port"], Store, "Serial port the device is on, e.g. /dev/ttyUSB0.");
+        ap.refer(&mut baud)
+            .add_option(&["-b", "--baud"], Store, "Baud rate.  The default is 115200.");
+        ap.refer(&mut framing)
+            .add_option(&["-f", "--framing"], Store, "What follows each message: \"crlf\" (the default) or \"raw\" for nothing.");
+        ap.refer(&mut dry_run)
+            .add_option(&["-n", "--dry-run"], StoreTrue, "Go through the script's timing without sending anything.");
+        ap.refer(&mut speed)
+            .add_option(&["-s", "--speed"], Store, "Play this many times faster than real time, e.g. 10.  The default is 1.");
+        ap.refer(&mut start)
+            .add_option(&["--start"], Store, "Start at this message, counting from 0, skipping the ones before it.");
+        ap.refer(&mut log_file)
+            .add_option(&["-l", "--log"], Store, "Also write a CSV log of when each message was due and when it was sent.");
+        Config::add_options(&mut ap, &mut config_file, &mut no_config);
+        Config::add_path_options(&mut ap, &mut symbol_files, &mut include_dirs);
+        ap.refer(&mut file)
+            .add_argument("file", Store, "Script to play.")
+            .required();
+        if let Err(code) = ap.parse(args, &mut io::stdout(), &mut io::stderr()) {
+            return code;
+        }
+    }
+    config.add_paths(&symbol_files, &include_dirs);
+    let mut asm = Assembler::new(false);
+    asm.symbols = check!(config.load_symbols(), "{}");
+    let fin = io::BufReader::new(check!(fs::File::open(config.resolve(Path::new(&file))), "Unable to open input file: {}"));
+    let script = check!(asm.assemble(fin), "Unable to read script: {}");
+    if !script.diagnostics.is_empty() {
+        for d in &script.diagnostics {
+            println!("{}:{}: {}", file, d.line, d.message);
+        }
+        return 1;
+    }
+    let entries = script.messages.iter().filter_map(|m| m.message.as_any().downcast_ref::<UCGScriptedMessageInternal>());
+
+    let opts = PlayerOptions { dry_run, time_scale: speed, start_index: start, framing: check!(framing.parse(), "{}") };
+    let mut player = Player::new(opts, SystemClock::new());
+    spawn_stdin_control(player.control.clone());
+    let mut link = match open_player_link(&port, baud, dry_run) {
+        Ok(l) => l,
+        Err(code) => return code,
     };
+    let render = RenderOptions { symbols: asm.symbols.clone(), ..RenderOptions::default() };
+    let played = player.play(entries, link.as_mut().map(|l| l as &mut dyn Transport), |r| {
+        let asm = script.messages[r.index].message.into_asm(false);
+        println!("[{:>9.3}s] #{} {}", r.sent.as_secs_f64(), r.index, render.symbols.name_line(&asm, true));
+    });
+    let log = match played {
+        Ok(log) => log,
+        Err(e) => {
+            println!("Unable to send: {}", e);
+            return 1;
+        }
+    };
+    if !log_file.is_empty() {
+        let mut out = check!(fs::File::create(&log_file), "Unable to create log file: {}");
+        check!(writeln!(out, "index,script_time,due_ms,sent_ms,late_ms"), "Unable to write log file: {}");
+        for r in &log {
+            let late = r.sent.saturating_sub(r.due);
+            check!(writeln!(out, "{},{},{},{},{}", r.index, r.script_time, r.due.as_millis(), r.sent.as_millis(), late.as_millis()),
+                   "Unable to write log file: {}");
+        }
+    }
+    0
 }
 
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let mut config = match Config::for_args(&args) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(2);
+        }
+    };
+
+    // Subcommands get their own argument parsers, named "rgas <subcommand>" in usage messages.
+    if args.len() > 1 {
+        let mut sub_args = args[1..].to_vec();
+        sub_args[0] = format!("{} {}", args[0], args[1]);
+        match args[1].as_str() {
+            "lint" => exit(lint_main(sub_args, config)),
+            "fmt" => exit(fmt_main(sub_args, config)),
+            "send" => exit(send_main(sub_args, config)),
+            "play" => exit(play_main(sub_args, config)),
+            "bridge" => exit(bridge_main(sub_args, config)),
+            "replay" => exit(replay_main(sub_args, config)),
+            "monitor" => exit(monitor_main(sub_args, config)),
+            _ => (),
+        }
+    }
+
     let mut verbose = false;
-    let mut immediate = false;
-    let mut hex = false;
+    let mut immediate = config.immediate;
+    let mut hex = config.hex;
+    let mut container = config.container;
+    let mut framing = config.framing.clone().unwrap_or_else(|| String::from("crlf"));
+    let mut symbol_files: Vec<String> = Vec::new();
+    let mut include_dirs: Vec<String> = Vec::new();
+    let mut config_file = String::new();
+    let mut no_config = false;
     let mut force_interactive = false;
     let mut outfile = String::new();
     let mut infile = String::new();
     let mut record_time = false;
+    let mut sourcemap_file = String::new();
+    let mut check = false;
+    let mut max_duration: Option<u64> = config.max_duration;
     {
         let mut ap = ArgumentParser::new();
         ap.set_description("Command grammar assembler for UCGv2.");
         ap.refer(&mut verbose)
             .add_option(&["-v", "--verbose"], StoreTrue, "Be more verbose.");
         ap.refer(&mut immediate)
-            .add_option(&["-m", "--immediate"], StoreTrue, "Use UCGv2 immediate mode.");
+            .add_option(&["-m", "--immediate"], StoreTrue, "Use UCGv2 immediate mode.")
+            .add_option(&["--scripted"], StoreFalse, "Use scripted mode, whatever rgas.toml says.");
         ap.refer(&mut hex)
-            .add_option(&["-x", "--hex"], StoreTrue, "Output hexadecimal strings instead of binary.");
+            .add_option(&["-x", "--hex"], StoreTrue, "Output hexadecimal strings instead of binary.")
+            .add_option(&["--binary"], StoreFalse, "Output binary, whatever rgas.toml says.");
+        ap.refer(&mut container)
+            .add_option(&["--container"], StoreTrue, "Start binary output with a header giving the mode, framing and message count, so dergas can read it without being told.")
+            .add_option(&["--no-container"], StoreFalse, "Write no header, whatever rgas.toml says.");
+        ap.refer(&mut framing)
+            .add_option(&["-f", "--framing"], Store, "What follows each message: \"crlf\" (the default) or \"raw\" for nothing.");
+        Config::add_path_options(&mut ap, &mut symbol_files, &mut include_dirs);
+        Config::add_options(&mut ap, &mut config_file, &mut no_config);
         ap.refer(&mut outfile)
             .add_option(&["-o", "--outfile"], Store, "Output file to write to.  Defaults to STDOUT.");
         ap.refer(&mut infile)
@@ -108,19 +822,48 @@ fn main() {
             .add_option(&["-I", "--interactive"], StoreTrue, "Force interactive mode.");
         ap.refer(&mut record_time)
             .add_option(&["-t", "--time"], StoreTrue, "Record total time spent and print it at the end.");
+        ap.refer(&mut sourcemap_file)
+            .add_option(&["-s", "--sourcemap"], Store, "Also write a source map sidecar file linking each message back to its source line.");
+        ap.refer(&mut check)
+            .add_option(&["-c", "--check"], StoreTrue, "Check the script's timeline instead of writing output.");
+        ap.refer(&mut max_duration)
+            .add_option(&["--max-duration"], StoreOption, "Longest a script may run, in seconds.  Used by --check.");
         ap.add_option(&["-V", "--version"],
             Print("Version ".to_string() + env!("CARGO_PKG_VERSION")+"\n"), "Show version");
         ap.parse_args_or_exit();
     }
 
-    
-    if outfile.len() == 0 && !hex {
+    let framing: Framing = match framing.parse() {
+        Ok(f) => f,
+        Err(e) => {
+            println!("{}", e);
+            exit(1);
+        }
+    };
+    config.add_paths(&symbol_files, &include_dirs);
+    let symbols = match config.load_symbols() {
+        Ok(s) => s,
+        Err(e) => {
+            println!("{}", e);
+            exit(1);
+        }
+    };
+
+    if container && hex {
+        println!("A container is binary, so it can't be written with -x.");
+        exit(1);
+    }
+    if check && immediate {
+        println!("Immediate commands have no timeline, so --check can't be used with -m.");
+        exit(1);
+    }
+    if outfile.is_empty() && !hex && !check {
         println!("No output file specified and -x not specified.  Refusing to output binary data to the terminal.");
         exit(1);
     }
 
     // Enter interactive mode if forced or if no input file was given.
-    let interactive_mode = force_interactive || infile.len() == 0;
+    let interactive_mode = force_interactive || infile.is_empty();
     if interactive_mode {
         println!("rgas: UCGv2 Command Grammar Assembler.");
         println!("Copyright (c) 2021 Logan Power and Sean Worley.  All Rights Reserved.");
@@ -143,7 +886,10 @@ fn main() {
     {
         let stdout; // for some reason the Stdout object is required by, but not referenced by, the return value of stdout.lock() so we must keep it alive on our own
                     // why isn't there an implicit reference by keeping the lock object alive?  good question
-        let mut fout:Box<dyn io::Write> = if outfile.len() == 0 {
+        let mut fout:Box<dyn io::Write> = if check {
+            // Checking only, so nothing gets written
+            Box::new(io::sink())
+        } else if outfile.is_empty() {
             stdout = io::stdout();
             Box::new(stdout.lock())
         } else {
@@ -158,37 +904,77 @@ fn main() {
             }
         };
 
-        // For some reason that is utterly beyond me, you can't invoke the lines() method on a trait object, because it has to be sized.
-        // So I used a macro to process input.
-        // Ah well, I needed a special case to setup rustyline anyway.
-        let mut time_total = 0;
+        let mut messages: Vec<AssembledMessage> = Vec::new();
+        let mut map = if sourcemap_file.is_empty() {
+            None
+        } else {
+            Some(SourceMap::new())
+        };
+        let mut asm = Assembler::new(immediate);
+        asm.hex = hex;
+        asm.framing = framing;
+        asm.symbols = symbols;
+        // A container header needs the message count and CRC, so hold the messages back until the end
+        let mut body: Vec<u8> = Vec::new();
+        let out: &mut dyn io::Write = if container { &mut body } else { &mut *fout };
+        let source = if interactive_mode { String::from("<stdin>") } else { config.resolve(Path::new(&infile)).to_string_lossy().into_owned() };
         if interactive_mode {
             // If we are in interactive mode, use rustyline and read lines in from the user.
             // TODO actually use rustyline.
             let stdin = io::stdin();
-            process_file!(stdin.lock(), fout, verbose, hex, true, immediate, &mut time_total);
-            if !immediate && record_time {
-                println!("Total execution time: {} seconds.", time_total);
-            }
+            process_file(stdin.lock(), out, &mut asm, true, "<stdin>", &mut map, &mut messages);
         } else {
             // If we aren't, read lines in from the file.
 
             
             // i can only assume that there is a less syntactically lame way to handle errors like this
             // the me of 5 years ago would have taken one look at this and dismissed Rust out of hand
-            match fs::File::open(infile) {
+            match fs::File::open(&source) {
                 Ok(file) => {
-                    process_file!(io::BufReader::new(file), fout, verbose, hex, false, immediate, &mut time_total);
+                    process_file(io::BufReader::new(file), out, &mut asm, false, &source, &mut map, &mut messages);
                     println!("Processing the file completed successfully.");
-                    if !immediate && record_time {
-                        println!("Total execution time: {} seconds.", time_total);
-                    }
                 }
                 Err(msg) => {
                     panic!("Unable to open input file: {}", msg);
                 }
             }
-            return;
+        }
+        if container {
+            let mut header = ContainerHeader::new(!immediate, framing);
+            let created = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+            header.metadata.push((String::from("creator"), format!("rgas {}", env!("CARGO_PKG_VERSION"))));
+            header.metadata.push((String::from("created"), created.to_string()));
+            header.metadata.push((String::from("source"), source.clone()));
+            let bytes = match header.seal(messages.len(), &body).and_then(|_| header.to_bytes()) {
+                Ok(b) => b,
+                Err(e) => {
+                    println!("{}", e);
+                    exit(1);
+                }
+            };
+            check!(fout.write(&bytes), "write() call failed: {}");
+            check!(fout.write(&body), "write() call failed: {}");
+        }
+        if !immediate && (check || record_time) {
+            let scripted = messages.iter()
+                .filter_map(|m| m.message.as_any().downcast_ref::<rgas::UCGScriptedMessageInternal>());
+            let timeline = build_timeline(scripted, &TimelineOptions { max_duration });
+            if record_time {
+                println!("Total execution time: {} seconds.", timeline.duration);
+            }
+            if check {
+                for issue in &timeline.issues {
+                    println!("line {}: {}: {}", messages[issue.index].line, issue.severity, issue.message);
+                }
+                if timeline.has_errors() {
+                    exit(1);
+                }
+                println!("Timeline OK: {} messages over {} seconds.", messages.len(), timeline.duration);
+            }
+        }
+        if let Some(map) = map {
+            let mut fmap = check!(fs::File::create(&sourcemap_file), "Unable to open source map file: {}");
+            check!(map.write_to(&mut fmap), "Unable to write source map: {}");
         }
     }
 
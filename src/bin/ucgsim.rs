@@ -0,0 +1,129 @@
+extern crate rgas;
+use argparse::{ArgumentParser, StoreTrue, Store, Collect};
+use std::io;
+use std::net::TcpListener;
+use std::path::Path;
+use std::process::exit;
+use std::sync::atomic::AtomicBool;
+#[cfg(unix)]
+use serialport::{SerialPort, TTYPort};
+use rgas::check;
+use rgas::config::Config;
+use rgas::framing::Framing;
+use rgas::sim::{Device, SimConfig, Simulator, Traffic};
+use rgas::symbols::SymbolTable;
+use rgas::transport::{SerialTransport, TcpTransport, Transport};
+
+// Print traffic the way rgas send does: > for what the ground sent, < for what came back.
+fn log_traffic(t: &Traffic, symbols: &SymbolTable) {
+    match t {
+        Traffic::Received(m) => println!("> {}", symbols.name_line(&m.into_asm(false), false)),
+        Traffic::Sent(m) => println!("< {}", symbols.name_line(&m.into_asm(false), false)),
+        Traffic::Bad(e) => println!("! {}", e),
+    }
+}
+
+fn serve(sim: &mut Simulator, link: &mut dyn Transport, verbose: bool, symbols: &SymbolTable) -> io::Result<()> {
+    let stop = AtomicBool::new(false);
+    sim.serve(link, &stop, |t| if verbose { log_traffic(t, symbols) })
+}
+
+#[cfg(unix)]
+fn serve_pty(sim: &mut Simulator, verbose: bool, symbols: &SymbolTable) -> io::Result<()> {
+    // Keep our own handle on the far end open, so the pty survives clients coming and going
+    let (master, slave) = check!(TTYPort::pair(), "Unable to create a pty: {}");
+    println!("Listening on {}", slave.name().unwrap_or_default());
+    serve(sim, &mut SerialTransport::from_port(Box::new(master)), verbose, symbols)
+}
+
+#[cfg(not(unix))]
+fn serve_pty(_sim: &mut Simulator, _verbose: bool, _symbols: &SymbolTable) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "Pseudo-terminals need a Unix system.  Use --tcp or --port."))
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let mut config = match Config::for_args(&args) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(2);
+        }
+    };
+    let mut device_file = String::new();
+    let mut addresses: Vec<String> = Vec::new();
+    let mut pty = false;
+    let mut tcp = String::new();
+    let mut port = String::new();
+    let mut baud: u32 = 115200;
+    let mut verbose = false;
+    let mut framing = config.framing.clone().unwrap_or_else(|| String::from("crlf"));
+    let mut symbol_files: Vec<String> = Vec::new();
+    let mut include_dirs: Vec<String> = Vec::new();
+    let mut config_file = String::new();
+    let mut no_config = false;
+
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Simulate UCGv2 devices, answering immediate commands over a pty, a TCP socket or a serial port.");
+        ap.refer(&mut device_file).add_argument("devices", Store, "TOML file describing the devices, their registers and subroutines.");
+        ap.refer(&mut addresses).add_option(&["-d", "--device"], Collect, "Add a device at this address with sixteen one-byte registers and four subroutines.  May be repeated.");
+        ap.refer(&mut pty).add_option(&["--pty"], StoreTrue, "Listen on a new pseudo-terminal and print its name.  This is the default, on Unix only.");
+        ap.refer(&mut tcp).add_option(&["--tcp"], Store, "Listen for TCP connections at this address, e.g. 127.0.0.1:5000, and serve them one at a time.");
+        ap.refer(&mut port).add_option(&["-p", "--port"], Store, "Listen on this serial port.");
+        ap.refer(&mut baud).add_option(&["-b", "--baud"], Store, "Baud rate for --port.  The default is 115200.");
+        ap.refer(&mut framing).add_option(&["-f", "--framing"], Store, "What follows each message: \"crlf\" (the default) or \"raw\" for nothing.");
+        ap.refer(&mut verbose).add_option(&["-v", "--verbose"], StoreTrue, "Print every message received and sent.");
+        Config::add_path_options(&mut ap, &mut symbol_files, &mut include_dirs);
+        Config::add_options(&mut ap, &mut config_file, &mut no_config);
+        ap.parse_args_or_exit();
+    }
+    config.add_paths(&symbol_files, &include_dirs);
+    let symbols = check!(config.load_symbols(), "{}");
+    let sim_config = if device_file.is_empty() {
+        SimConfig::default()
+    } else {
+        check!(SimConfig::load(&config.resolve(Path::new(&device_file))), "{}")
+    };
+    let framing: Framing = check!(framing.parse(), "{}");
+    let mut sim = check!(Simulator::from_config(&sim_config, &symbols, framing), "{}");
+    for a in &addresses {
+        let address = check!(Device::parse_address(a, &symbols), "{}");
+        sim.devices.push(Device::with_defaults(address));
+    }
+    if sim.devices.is_empty() {
+        eprintln!("No devices to simulate.  Give a device file or --device.");
+        exit(2);
+    }
+    for d in &sim.devices {
+        println!("Device {:02X}/{:X}: {} registers, {} subroutines", d.address.0, d.address.1, d.registers.len(), d.subroutines.len());
+    }
+
+    if [!tcp.is_empty(), !port.is_empty(), pty].iter().filter(|&&b| b).count() > 1 {
+        eprintln!("Choose one of --pty, --tcp and --port.");
+        exit(2);
+    }
+    let result = if !tcp.is_empty() {
+        let listener = check!(TcpListener::bind(&tcp), "Unable to listen: {}");
+        println!("Listening on {}", tcp);
+        for stream in listener.incoming() {
+            let stream = check!(stream, "Unable to accept connection: {}");
+            println!("Connection from {}", stream.peer_addr().map(|a| a.to_string()).unwrap_or_default());
+            // The simulator keeps its state from one connection to the next
+            if let Err(e) = serve(&mut sim, &mut TcpTransport::from_stream(stream), verbose, &symbols) {
+                println!("Connection closed: {}", e);
+            }
+        }
+        Ok(())
+    } else if !port.is_empty() {
+        let mut link = check!(SerialTransport::open(&port, baud), "{}");
+        println!("Listening on {}", port);
+        serve(&mut sim, &mut link, verbose, &symbols)
+    } else {
+        serve_pty(&mut sim, verbose, &symbols)
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
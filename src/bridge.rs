@@ -0,0 +1,405 @@
+// mod bridge
+// Carries a UCG link between hosts.  Each end of a bridge passes whole messages: a serial port
+// or TCP connection carries them as a framed stream, and a UDP socket carries one message per
+// datagram with no framing.  Messages go through unchanged in both directions.
+//
+// Ends are written as:
+//
+//   serial:/dev/ttyUSB0@115200   a serial port; the baud rate is 115200 if left off
+//   tcp:host:5000                connect to a TCP server
+//   tcp-listen:0.0.0.0:5000      accept TCP connections, one at a time
+//   udp:0.0.0.0:5001             answer whoever last sent a datagram
+//   udp:0.0.0.0:5001,host:6001   always send to the given address
+//
+// Messages that arrive for a listening socket with nobody connected, or a UDP socket that
+// hasn't heard from anyone yet, are dropped.
+//
+// A Tap lets other programs, such as rgas monitor, watch a bridge without taking either end's
+// place: any number of TCP clients can connect, and each is sent a framed copy of every message
+// going either way.  Anything they send is ignored, and a client that falls too far behind is
+// dropped rather than holding up the bridge.
+
+use std::io;
+use std::io::Write;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use crate::framing::{payload_length, FrameBuffer, Framing};
+use crate::transport::{SerialTransport, TcpTransport, Transport};
+
+// How long each end is given to produce something before the other is checked.
+const POLL: Duration = Duration::from_millis(5);
+
+// More than this waiting to go to one tap client means it has stopped reading.
+const TAP_BACKLOG: usize = 1 << 20;
+
+pub enum Incoming {
+    Message(Vec<u8>), // without framing
+    Notice(String),   // something worth logging: a connection, or bytes thrown away
+}
+
+pub trait MessageLink {
+    fn send_message(&mut self, msg: &[u8]) -> io::Result<()>;
+
+    // Whatever arrives within timeout; empty if nothing did.
+    fn receive_messages(&mut self, timeout: Duration) -> io::Result<Vec<Incoming>>;
+}
+
+// A framed stream over any transport.
+pub struct StreamLink<T: Transport> {
+    link: T,
+    framing: Framing,
+    frames: FrameBuffer,
+}
+
+impl<T: Transport> StreamLink<T> {
+    pub fn new(link: T, framing: Framing) -> StreamLink<T> {
+        StreamLink { link, framing, frames: FrameBuffer::new(framing, false) }
+    }
+}
+
+impl<T: Transport> MessageLink for StreamLink<T> {
+    fn send_message(&mut self, msg: &[u8]) -> io::Result<()> {
+        self.link.send(&[msg, self.framing.trailer()].concat())
+    }
+
+    fn receive_messages(&mut self, timeout: Duration) -> io::Result<Vec<Incoming>> {
+        let mut chunk = [0u8; 1024];
+        let n = self.link.receive(&mut chunk, timeout)?;
+        self.frames.push(&chunk[..n]);
+        let mut incoming = Vec::new();
+        while let Some(frame) = self.frames.next_frame() {
+            incoming.push(match frame {
+                Ok(m) => Incoming::Message(m),
+                Err(e) => Incoming::Notice(e),
+            });
+        }
+        Ok(incoming)
+    }
+}
+
+// Accepts one TCP connection at a time, and goes back to waiting when it closes.
+pub struct TcpServerLink {
+    listener: TcpListener,
+    framing: Framing,
+    client: Option<StreamLink<TcpTransport>>,
+}
+
+impl TcpServerLink {
+    pub fn bind(addr: &str, framing: Framing) -> Result<TcpServerLink, String> {
+        let listener = TcpListener::bind(addr).map_err(|e| format!("Unable to listen on {}: {}", addr, e))?;
+        listener.set_nonblocking(true).map_err(|e| format!("Unable to listen on {}: {}", addr, e))?;
+        Ok(TcpServerLink { listener, framing, client: None })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+}
+
+impl MessageLink for TcpServerLink {
+    fn send_message(&mut self, msg: &[u8]) -> io::Result<()> {
+        if let Some(c) = self.client.as_mut() {
+            if c.send_message(msg).is_err() {
+                self.client = None;
+            }
+        }
+        Ok(())
+    }
+
+    fn receive_messages(&mut self, timeout: Duration) -> io::Result<Vec<Incoming>> {
+        let client = match self.client.as_mut() {
+            Some(c) => c,
+            None => {
+                return match self.listener.accept() {
+                    Ok((stream, peer)) => {
+                        stream.set_nonblocking(false)?;
+                        self.client = Some(StreamLink::new(TcpTransport::from_stream(stream), self.framing));
+                        Ok(vec![Incoming::Notice(format!("Connection from {}.", peer))])
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        thread::sleep(timeout);
+                        Ok(Vec::new())
+                    }
+                    Err(e) => Err(e),
+                };
+            }
+        };
+        match client.receive_messages(timeout) {
+            Ok(incoming) => Ok(incoming),
+            Err(e) => {
+                self.client = None;
+                Ok(vec![Incoming::Notice(format!("Connection closed: {}", e))])
+            }
+        }
+    }
+}
+
+// One message per datagram.
+pub struct DatagramLink {
+    socket: UdpSocket,
+    peer: Option<SocketAddr>,
+    fixed: bool, // peer was given rather than learned
+}
+
+impl DatagramLink {
+    pub fn bind(local: &str, peer: Option<&str>) -> Result<DatagramLink, String> {
+        let socket = UdpSocket::bind(local).map_err(|e| format!("Unable to bind {}: {}", local, e))?;
+        let peer = match peer {
+            Some(p) => match p.to_socket_addrs().ok().and_then(|mut a| a.next()) {
+                Some(a) => Some(a),
+                None => return Err(format!("Bad address {}.", p)),
+            },
+            None => None,
+        };
+        Ok(DatagramLink { socket, fixed: peer.is_some(), peer })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
+
+impl MessageLink for DatagramLink {
+    fn send_message(&mut self, msg: &[u8]) -> io::Result<()> {
+        if let Some(peer) = self.peer {
+            self.socket.send_to(msg, peer)?;
+        }
+        Ok(())
+    }
+
+    fn receive_messages(&mut self, timeout: Duration) -> io::Result<Vec<Incoming>> {
+        self.socket.set_read_timeout(Some(timeout.max(Duration::from_millis(1))))?;
+        let mut buf = [0u8; 4 + 0x7FF];
+        let (n, from) = match self.socket.recv_from(&mut buf) {
+            Ok(r) => r,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        if !self.fixed {
+            self.peer = Some(from);
+        }
+        if n < 4 || n != 4 + payload_length(&buf) {
+            return Ok(vec![Incoming::Notice(format!("Dropped a datagram of {} bytes from {}: not one message.", n, from))]);
+        }
+        Ok(vec![Incoming::Message(buf[..n].to_vec())])
+    }
+}
+
+struct TapClient {
+    stream: TcpStream,
+    pending: Vec<u8>, // mirrored but not yet taken by the socket
+}
+
+impl TapClient {
+    // Write as much as the socket will take without blocking.  False if the client should go.
+    fn flush(&mut self) -> bool {
+        while !self.pending.is_empty() {
+            match self.stream.write(&self.pending) {
+                Ok(0) => return false,
+                Ok(n) => {
+                    self.pending.drain(..n);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(_) => return false,
+            }
+        }
+        self.pending.len() <= TAP_BACKLOG
+    }
+}
+
+// Read-only listeners, each sent a copy of the traffic.
+pub struct Tap {
+    listener: TcpListener,
+    framing: Framing,
+    clients: Vec<TapClient>,
+}
+
+impl Tap {
+    pub fn bind(addr: &str, framing: Framing) -> Result<Tap, String> {
+        let listener = TcpListener::bind(addr).map_err(|e| format!("Unable to listen on {}: {}", addr, e))?;
+        listener.set_nonblocking(true).map_err(|e| format!("Unable to listen on {}: {}", addr, e))?;
+        Ok(Tap { listener, framing, clients: Vec::new() })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    // Take on whoever is waiting to connect, and say who they are.
+    pub fn accept(&mut self) -> Vec<String> {
+        let mut notices = Vec::new();
+        while let Ok((stream, peer)) = self.listener.accept() {
+            // Writes mustn't hold up the bridge, so what a client can't take yet waits in pending
+            if stream.set_nonblocking(true).is_ok() {
+                self.clients.push(TapClient { stream, pending: Vec::new() });
+                notices.push(format!("Tap connection from {}.", peer));
+            }
+        }
+        notices
+    }
+
+    // Accept, and pass on whatever clients couldn't take last time.  For when nothing is going by.
+    pub fn poll(&mut self) -> Vec<String> {
+        let notices = self.accept();
+        self.clients.retain_mut(|c| c.flush());
+        notices
+    }
+
+    // Copy a message, without framing, to every client, dropping any that have gone or fallen
+    // too far behind.
+    pub fn mirror(&mut self, msg: &[u8]) {
+        for c in &mut self.clients {
+            c.pending.extend_from_slice(msg);
+            c.pending.extend_from_slice(self.framing.trailer());
+        }
+        self.clients.retain_mut(|c| c.flush());
+    }
+}
+
+// Open one end of a bridge from its description.
+pub fn open_link(spec: &str, framing: Framing) -> Result<Box<dyn MessageLink>, String> {
+    let (kind, rest) = match spec.split_once(':') {
+        Some(s) => s,
+        None => return Err(format!("Bridge end \"{}\" should start with serial:, tcp:, tcp-listen: or udp:.", spec)),
+    };
+    match kind {
+        "serial" => {
+            let (path, baud) = match rest.rsplit_once('@') {
+                Some((p, b)) => (p, b.parse().map_err(|_| format!("Bad baud rate \"{}\".", b))?),
+                None => (rest, 115200),
+            };
+            Ok(Box::new(StreamLink::new(SerialTransport::open(path, baud)?, framing)))
+        }
+        "tcp" => Ok(Box::new(StreamLink::new(TcpTransport::connect(rest)?, framing))),
+        "tcp-listen" => Ok(Box::new(TcpServerLink::bind(rest, framing)?)),
+        "udp" => {
+            let (local, peer) = match rest.split_once(',') {
+                Some((l, p)) => (l, Some(p)),
+                None => (rest, None),
+            };
+            Ok(Box::new(DatagramLink::bind(local, peer)?))
+        }
+        _ => Err(format!("Unknown kind of bridge end \"{}\".  Expected serial, tcp, tcp-listen or udp.", kind)),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Side {
+    A,
+    B,
+    Tap, // only ever a notice, about a tap connection
+}
+
+fn pump(from: &mut dyn MessageLink, to: &mut dyn MessageLink, side: Side, tap: &mut Option<&mut Tap>,
+        log: &mut dyn FnMut(Side, &Incoming)) -> io::Result<()> {
+    for incoming in from.receive_messages(POLL)? {
+        if let Incoming::Message(m) = &incoming {
+            to.send_message(m)?;
+            if let Some(t) = tap.as_deref_mut() {
+                t.mirror(m);
+            }
+        }
+        log(side, &incoming);
+    }
+    Ok(())
+}
+
+// Forward messages both ways until stop is set or either end fails, copying them to tap if
+// there is one.  log is told about everything that arrives, and which end it arrived at.
+pub fn run<F: FnMut(Side, &Incoming)>(a: &mut dyn MessageLink, b: &mut dyn MessageLink, mut tap: Option<&mut Tap>,
+                                      stop: &AtomicBool, mut log: F) -> io::Result<()> {
+    while !stop.load(Ordering::SeqCst) {
+        pump(a, b, Side::A, &mut tap, &mut log)?;
+        pump(b, a, Side::B, &mut tap, &mut log)?;
+        // Clients that connect while the link is quiet are taken on straight away
+        if let Some(t) = tap.as_deref_mut() {
+            for n in t.poll() {
+                log(Side::Tap, &Incoming::Notice(n));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bridge::*;
+    use std::io::Read;
+    use std::sync::Arc;
+
+    #[test]
+    fn tcp_to_udp() {
+        let mut tcp = TcpServerLink::bind("127.0.0.1:0", Framing::Crlf).unwrap();
+        let tcp_addr = tcp.local_addr().unwrap();
+        let far = UdpSocket::bind("127.0.0.1:0").unwrap();
+        far.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let mut udp = DatagramLink::bind("127.0.0.1:0", Some(&far.local_addr().unwrap().to_string())).unwrap();
+        let udp_addr = udp.local_addr().unwrap();
+        let mut tap = Tap::bind("127.0.0.1:0", Framing::Crlf).unwrap();
+        let mut watcher = TcpStream::connect(tap.local_addr().unwrap()).unwrap();
+        watcher.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_bridge = stop.clone();
+        let (notices, seen_notices) = std::sync::mpsc::channel();
+        let bridge = thread::spawn(move || run(&mut tcp, &mut udp, Some(&mut tap), &stop_bridge, |side, incoming| {
+            if let (Side::Tap, Incoming::Notice(n)) = (side, incoming) {
+                notices.send(n.clone()).unwrap();
+            }
+        }));
+        // The watcher is taken on before any traffic goes by
+        assert!(seen_notices.recv_timeout(Duration::from_secs(2)).unwrap().starts_with("Tap connection"));
+
+        let mut ground = TcpStream::connect(tcp_addr).unwrap();
+        ground.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        // Split across two writes, so the bridge has to put the message back together
+        ground.write_all(&[0x1C, 0xFF, 0x08]).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        ground.write_all(&[0x01, 0x01, b'\r', b'\n']).unwrap();
+        let mut buf = [0u8; 64];
+        let n = far.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], &[0x1C, 0xFF, 0x08, 0x01, 0x01]);
+
+        far.send_to(&[0xFF, 0x1C, 0x28, 0x02, 0x01, 0x2A], udp_addr).unwrap();
+        let mut reply = [0u8; 8];
+        ground.read_exact(&mut reply).unwrap();
+        assert_eq!(reply, [0xFF, 0x1C, 0x28, 0x02, 0x01, 0x2A, b'\r', b'\n']);
+
+        // The tap saw both directions
+        let mut seen = [0u8; 15];
+        watcher.read_exact(&mut seen).unwrap();
+        assert_eq!(&seen[..7], &[0x1C, 0xFF, 0x08, 0x01, 0x01, b'\r', b'\n']);
+        assert_eq!(&seen[7..], &reply);
+
+        stop.store(true, Ordering::SeqCst);
+        bridge.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn tap_drops_stalled_client() {
+        let mut tap = Tap::bind("127.0.0.1:0", Framing::Raw).unwrap();
+        let _stalled = TcpStream::connect(tap.local_addr().unwrap()).unwrap();
+        let mut notices = Vec::new();
+        for _ in 0..200 {
+            notices.extend(tap.poll());
+            if !notices.is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(notices.len(), 1);
+        // What the socket can't take waits, until there is far too much of it
+        let msg = vec![0u8; 4 + 0x7FF];
+        tap.mirror(&msg);
+        assert_eq!(tap.clients.len(), 1);
+        for _ in 0..8000 {
+            tap.mirror(&msg);
+            if tap.clients.is_empty() {
+                break;
+            }
+        }
+        assert!(tap.clients.is_empty());
+    }
+}
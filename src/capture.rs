@@ -0,0 +1,463 @@
+// mod capture
+// Recordings of live link traffic, with when each message went by, which way, and on which
+// link.  All integers are little-endian:
+//
+//   magic      8 bytes   89 52 47 43 41 50 0D 0A  (\x89RGCAP\r\n)
+//   version    1 byte    layout of the file, currently 1
+//   reserved   1 byte    0
+//   start      8 bytes   when recording began, in microseconds since the Unix epoch
+//   metalen    2 bytes   length of the metadata
+//   metadata   metalen   UTF-8 "key=value" lines, as in a container header
+//   ifcount    1 byte    number of interfaces
+//   interfaces           for each, a 1 byte length and then its name, e.g. /dev/ttyUSB0
+//
+// then a record for every message, to the end of the file:
+//
+//   time       8 bytes   microseconds since recording began
+//   direction  1 byte    0: sent by the recording host, 1: received by it
+//   interface  1 byte    index into the interfaces
+//   length     2 bytes   length of the message
+//   message    length    the message as on the link, without framing
+//
+// Only immediate messages travel over links, so that's what captures hold.
+
+use std::convert::{TryFrom, TryInto};
+use std::io;
+use std::io::{BufRead, Read, Write};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use crate::framing::{FrameBuffer, Framing};
+use crate::pcapng::{is_pcapng, PcapngReader};
+use crate::player::{Clock, Pacer, PlayerControl};
+use crate::transport::Transport;
+use crate::{UCGMessage, UCGMessageInternal};
+
+pub const CAPTURE_MAGIC: [u8; 8] = *b"\x89RGCAP\r\n";
+pub const CAPTURE_VERSION: u8 = 1;
+
+pub fn is_capture(sample: &[u8]) -> bool {
+    sample.starts_with(&CAPTURE_MAGIC)
+}
+
+// A length too big for its field.  Writing it cut short would leave a file that can't be read back.
+fn fits<T: TryFrom<usize>>(len: usize, what: &str) -> io::Result<T> {
+    T::try_from(len).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("{} is too long for a capture: {}.", what, len)))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+impl Direction {
+    // > for sent and < for received, as rgas send prints them.
+    pub fn arrow(&self) -> &'static str {
+        match self {
+            Direction::Sent => ">",
+            Direction::Received => "<",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CaptureRecord {
+    pub time: Duration,
+    pub direction: Direction,
+    pub interface: u8,
+    pub bytes: Vec<u8>,
+}
+
+impl CaptureRecord {
+    pub fn message(&self) -> Option<Box<dyn UCGMessage>> {
+        UCGMessageInternal::from_byte_vec(&mut self.bytes.clone())
+    }
+}
+
+#[derive(Clone)]
+pub struct CaptureHeader {
+    pub version: u8,
+    pub start: u64, // microseconds since the Unix epoch
+    pub metadata: Vec<(String, String)>,
+    pub interfaces: Vec<String>,
+}
+
+impl CaptureHeader {
+    // A header for a recording starting now.
+    pub fn new(interfaces: &[&str]) -> CaptureHeader {
+        let start = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_micros() as u64).unwrap_or(0);
+        CaptureHeader {
+            version: CAPTURE_VERSION,
+            start,
+            metadata: Vec::new(),
+            interfaces: interfaces.iter().map(|i| i.to_string()).collect(),
+        }
+    }
+
+    pub fn to_bytes(&self) -> io::Result<Vec<u8>> {
+        let meta: String = self.metadata.iter().map(|(k, v)| format!("{}={}\n", k, v)).collect();
+        let mut out = CAPTURE_MAGIC.to_vec();
+        out.extend_from_slice(&[self.version, 0]);
+        out.extend_from_slice(&self.start.to_le_bytes());
+        out.extend_from_slice(&fits::<u16>(meta.len(), "Metadata")?.to_le_bytes());
+        out.extend_from_slice(meta.as_bytes());
+        out.push(fits(self.interfaces.len(), "Interface list")?);
+        for i in &self.interfaces {
+            out.push(fits(i.len(), "Interface name")?);
+            out.extend_from_slice(i.as_bytes());
+        }
+        Ok(out)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.metadata.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+
+    pub fn interface_name(&self, i: u8) -> &str {
+        self.interfaces.get(i as usize).map_or("?", |s| s.as_str())
+    }
+
+    pub fn read_from<R: Read>(input: &mut R) -> Result<CaptureHeader, String> {
+        let fail = |e: io::Error| format!("Unable to read capture header: {}", e);
+        let mut fixed = [0u8; 20];
+        input.read_exact(&mut fixed).map_err(fail)?;
+        if !is_capture(&fixed) {
+            return Err(String::from("Not an rgas capture (bad magic number)."));
+        }
+        if fixed[8] != CAPTURE_VERSION {
+            return Err(format!("Capture version {} is not supported; this rgas reads version {}.", fixed[8], CAPTURE_VERSION));
+        }
+        let start = u64::from_le_bytes(fixed[10..18].try_into().unwrap());
+        let mut meta = vec![0u8; u16::from_le_bytes([fixed[18], fixed[19]]) as usize];
+        input.read_exact(&mut meta).map_err(fail)?;
+        let meta = String::from_utf8(meta).map_err(|_| String::from("Capture metadata is not valid UTF-8."))?;
+        let metadata = meta.lines()
+            .filter_map(|l| l.split_once('='))
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let mut count = [0u8; 1];
+        input.read_exact(&mut count).map_err(fail)?;
+        let mut interfaces = Vec::new();
+        for _ in 0..count[0] {
+            let mut len = [0u8; 1];
+            input.read_exact(&mut len).map_err(fail)?;
+            let mut name = vec![0u8; len[0] as usize];
+            input.read_exact(&mut name).map_err(fail)?;
+            interfaces.push(String::from_utf8_lossy(&name).into_owned());
+        }
+        Ok(CaptureHeader { version: fixed[8], start, metadata, interfaces })
+    }
+}
+
+// Somewhere live traffic can be recorded to, as it goes by.
+pub trait RecordSink {
+    fn record(&mut self, direction: Direction, interface: u8, message: &[u8]) -> io::Result<()>;
+}
+
+impl<S: RecordSink + ?Sized> RecordSink for Box<S> {
+    fn record(&mut self, direction: Direction, interface: u8, message: &[u8]) -> io::Result<()> {
+        (**self).record(direction, interface, message)
+    }
+}
+
+pub struct CaptureWriter<W: Write> {
+    out: W,
+    started: Instant,
+}
+
+impl<W: Write> CaptureWriter<W> {
+    // Write the header; times of records count from now.
+    pub fn new(mut out: W, header: &CaptureHeader) -> io::Result<CaptureWriter<W>> {
+        out.write_all(&header.to_bytes()?)?;
+        out.flush()?;
+        Ok(CaptureWriter { out, started: Instant::now() })
+    }
+
+    pub fn record_at(&mut self, r: &CaptureRecord) -> io::Result<()> {
+        let mut out = (r.time.as_micros() as u64).to_le_bytes().to_vec();
+        out.push(if r.direction == Direction::Sent { 0 } else { 1 });
+        out.push(r.interface);
+        out.extend_from_slice(&fits::<u16>(r.bytes.len(), "Message")?.to_le_bytes());
+        out.extend_from_slice(&r.bytes);
+        self.out.write_all(&out)?;
+        // A capture is most wanted when something has gone wrong, so don't sit on it
+        self.out.flush()
+    }
+}
+
+impl<W: Write> RecordSink for CaptureWriter<W> {
+    fn record(&mut self, direction: Direction, interface: u8, message: &[u8]) -> io::Result<()> {
+        let time = self.started.elapsed();
+        self.record_at(&CaptureRecord { time, direction, interface, bytes: message.to_vec() })
+    }
+}
+
+pub struct CaptureReader<R: Read> {
+    input: R,
+    pub header: CaptureHeader,
+}
+
+impl<R: Read> CaptureReader<R> {
+    pub fn new(mut input: R) -> Result<CaptureReader<R>, String> {
+        let header = CaptureHeader::read_from(&mut input)?;
+        Ok(CaptureReader { input, header })
+    }
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = Result<CaptureRecord, String>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut fixed = [0u8; 12];
+        // A clean end comes between records
+        match self.input.read(&mut fixed[..1]) {
+            Ok(0) => return None,
+            Ok(_) => (),
+            Err(e) => return Some(Err(format!("Read error: {}", e))),
+        }
+        let truncated = |e: io::Error| format!("Capture ends partway through a record: {}", e);
+        if let Err(e) = self.input.read_exact(&mut fixed[1..]) {
+            return Some(Err(truncated(e)));
+        }
+        let mut bytes = vec![0u8; u16::from_le_bytes([fixed[10], fixed[11]]) as usize];
+        if let Err(e) = self.input.read_exact(&mut bytes) {
+            return Some(Err(truncated(e)));
+        }
+        Some(Ok(CaptureRecord {
+            time: Duration::from_micros(u64::from_le_bytes(fixed[..8].try_into().unwrap())),
+            direction: if fixed[8] == 0 { Direction::Sent } else { Direction::Received },
+            interface: fixed[9],
+            bytes,
+        }))
+    }
+}
+
+pub type Records<'a> = Box<dyn Iterator<Item = Result<CaptureRecord, String>> + 'a>;
+
+// Open either an rgas capture or a pcapng file, whichever the input turns out to be.
+pub fn open_capture<'a, R: BufRead + 'a>(mut input: R) -> Result<(CaptureHeader, Records<'a>), String> {
+    let pcapng = is_pcapng(input.fill_buf().map_err(|e| format!("Read error: {}", e))?);
+    if pcapng {
+        let reader = PcapngReader::new(input)?;
+        Ok((reader.header.clone(), Box::new(reader)))
+    } else {
+        let reader = CaptureReader::new(input)?;
+        Ok((reader.header.clone(), Box::new(reader)))
+    }
+}
+
+// Records everything that goes through a transport, one record per message.  Whatever the
+// link carries is passed on untouched; the framing is only used to find where messages end.
+pub struct RecordingTransport<T: Transport, S: RecordSink> {
+    inner: T,
+    writer: S,
+    interface: u8,
+    sent: FrameBuffer,
+    received: FrameBuffer,
+}
+
+impl<T: Transport, S: RecordSink> RecordingTransport<T, S> {
+    pub fn new(inner: T, writer: S, interface: u8, framing: Framing) -> RecordingTransport<T, S> {
+        RecordingTransport {
+            inner,
+            writer,
+            interface,
+            sent: FrameBuffer::new(framing, false),
+            received: FrameBuffer::new(framing, false),
+        }
+    }
+
+    fn record(&mut self, direction: Direction, bytes: &[u8]) -> io::Result<()> {
+        let frames = if direction == Direction::Sent { &mut self.sent } else { &mut self.received };
+        frames.push(bytes);
+        let mut messages = Vec::new();
+        while let Some(frame) = frames.next_frame() {
+            // Bytes that aren't a message aren't recorded
+            if let Ok(m) = frame {
+                messages.push(m);
+            }
+        }
+        for m in messages {
+            self.writer.record(direction, self.interface, &m)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: Transport, S: RecordSink> Transport for RecordingTransport<T, S> {
+    fn send(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.inner.send(bytes)?;
+        self.record(Direction::Sent, bytes)
+    }
+
+    fn receive(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        let n = self.inner.receive(buf, timeout)?;
+        self.record(Direction::Received, &buf[..n])?;
+        Ok(n)
+    }
+}
+
+pub struct ReplayOptions {
+    pub speed: f64,                // how many times faster than it was recorded
+    pub direction: Direction,      // which records to send
+    pub interface: Option<u8>,     // only records from this interface, or all
+    pub framing: Framing,
+    pub dry_run: bool,
+}
+
+impl Default for ReplayOptions {
+    fn default() -> ReplayOptions {
+        ReplayOptions { speed: 1.0, direction: Direction::Sent, interface: None, framing: Framing::default(), dry_run: false }
+    }
+}
+
+impl ReplayOptions {
+    pub fn selects(&self, r: &CaptureRecord) -> bool {
+        r.direction == self.direction && self.interface.map_or(true, |i| i == r.interface)
+    }
+}
+
+// Send the chosen records again with the gaps between them that were recorded, the first
+// straight away.  Records are taken in time order, since a file merged from several links
+// needn't be.  on_send gets each record and when it actually went.  Returns the number sent.
+pub fn replay<C: Clock, F: FnMut(&CaptureRecord, Duration)>(records: &[CaptureRecord], mut link: Option<&mut dyn Transport>,
+                                                          opts: &ReplayOptions, clock: &C, control: &PlayerControl,
+                                                          mut on_send: F) -> io::Result<usize> {
+    if opts.speed <= 0.0 || !opts.speed.is_finite() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Speed must be a positive number."));
+    }
+    let mut chosen: Vec<&CaptureRecord> = records.iter().filter(|r| opts.selects(r)).collect();
+    chosen.sort_by_key(|r| r.time);
+    let first = match chosen.first() {
+        Some(r) => r.time,
+        None => return Ok(0),
+    };
+    let mut pacer = Pacer::new(clock, control);
+    let mut count = 0;
+    for r in chosen {
+        if !pacer.wait_for((r.time - first).div_f64(opts.speed)) {
+            break;
+        }
+        if !opts.dry_run {
+            if let Some(l) = link.as_mut() {
+                l.send(&[&r.bytes[..], opts.framing.trailer()].concat())?;
+            }
+        }
+        on_send(r, pacer.elapsed());
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::capture::*;
+    use crate::transport::ChannelTransport;
+    use std::cell::Cell;
+
+    struct FakeClock(Cell<Duration>);
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Duration {
+            self.0.get()
+        }
+
+        fn sleep(&self, d: Duration) {
+            self.0.set(self.0.get() + d);
+        }
+    }
+
+    #[test]
+    fn capture_round_trip() {
+        let mut header = CaptureHeader::new(&["/dev/ttyUSB0"]);
+        header.metadata.push((String::from("creator"), String::from("rgas test")));
+        let (ground, mut device) = ChannelTransport::pair();
+        let mut file = Vec::new();
+        {
+            let writer = CaptureWriter::new(&mut file, &header).unwrap();
+            let mut link = RecordingTransport::new(ground, writer, 0, Framing::Crlf);
+            // A command in two pieces, and a reply
+            link.send(&[0x1C, 0xFF, 0x08]).unwrap();
+            link.send(&[0x01, 0x01, b'\r', b'\n']).unwrap();
+            device.send(&[0xFF, 0x1C, 0x28, 0x02, 0x01, 0x2A, b'\r', b'\n']).unwrap();
+            let mut buf = [0u8; 16];
+            assert_eq!(link.receive(&mut buf, Duration::from_millis(100)).unwrap(), 8);
+        }
+
+        let reader = CaptureReader::new(&file[..]).unwrap();
+        assert_eq!(reader.header.interface_name(0), "/dev/ttyUSB0");
+        assert_eq!(reader.header.get("creator"), Some("rgas test"));
+        let mut records: Vec<CaptureRecord> = reader.map(|r| r.unwrap()).collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!((records[0].direction, records[0].interface), (Direction::Sent, 0));
+        assert_eq!(records[0].message().unwrap().into_asm(false), "03/4 1F/7 RQRY 001 01");
+        assert_eq!(records[1].message().unwrap().into_asm(false), "1F/7 03/4 RVAL 002 01 2A");
+        assert!(CaptureReader::new(&file[..file.len() - 1]).unwrap().last().unwrap().is_err());
+
+        // Replay the commands at double speed
+        records[0].time = Duration::from_secs(10);
+        records.push(CaptureRecord { time: Duration::from_secs(14), ..records[0].clone() });
+        let (mut ground, mut device) = ChannelTransport::pair();
+        let opts = ReplayOptions { speed: 2.0, ..ReplayOptions::default() };
+        let mut times = Vec::new();
+        let clock = FakeClock(Cell::new(Duration::ZERO));
+        let sent = replay(&records, Some(&mut ground), &opts, &clock, &PlayerControl::new(), |_, t| times.push(t)).unwrap();
+        assert_eq!(sent, 2);
+        assert_eq!(times, vec![Duration::ZERO, Duration::from_secs(2)]);
+        let mut buf = [0u8; 16];
+        assert_eq!(device.receive(&mut buf, Duration::from_millis(100)).unwrap(), 7);
+
+        // A record earlier than the first goes first rather than upsetting the timing
+        records.push(CaptureRecord { time: Duration::from_secs(9), ..records[0].clone() });
+        let mut times = Vec::new();
+        let clock = FakeClock(Cell::new(Duration::ZERO));
+        replay(&records, None, &opts, &clock, &PlayerControl::new(), |r, t| times.push((r.time.as_secs(), t))).unwrap();
+        assert_eq!(times, vec![(9, Duration::ZERO), (10, Duration::from_millis(500)), (14, Duration::from_millis(2500))]);
+    }
+
+    #[test]
+    fn capture_truncated() {
+        let mut file = Vec::new();
+        let mut writer = CaptureWriter::new(&mut file, &CaptureHeader::new(&["a", "b"])).unwrap();
+        let record = CaptureRecord { time: Duration::ZERO, direction: Direction::Received, interface: 1, bytes: vec![0x1C, 0xFF, 0x08, 0x01, 0x01] };
+        writer.record_at(&record).unwrap();
+        let header_len = file.len() - 17;
+
+        // Cut anywhere in the header, the header can't be read
+        for cut in [4, 19, header_len - 1] {
+            let err = CaptureReader::new(&file[..cut]).err().unwrap();
+            assert!(err.starts_with("Unable to read capture header"), "{}", err);
+        }
+        // Cut between records, the capture just ends
+        assert_eq!(CaptureReader::new(&file[..header_len]).unwrap().count(), 0);
+        // Cut in a record's fixed part or its message, the record is an error
+        for cut in [header_len + 1, header_len + 12, file.len() - 1] {
+            let records: Vec<Result<CaptureRecord, String>> = CaptureReader::new(&file[..cut]).unwrap().collect();
+            match &records[..] {
+                [Err(e)] => assert!(e.starts_with("Capture ends partway through a record"), "{}", e),
+                _ => panic!("expected one error when cut at {}", cut),
+            }
+        }
+
+        let mut bad = file.clone();
+        bad[8] = CAPTURE_VERSION + 1;
+        assert!(CaptureReader::new(&bad[..]).err().unwrap().contains("not supported"));
+        bad[0] = b'X';
+        assert!(CaptureReader::new(&bad[..]).err().unwrap().contains("bad magic number"));
+    }
+
+    #[test]
+    fn capture_rejects_oversized_fields() {
+        let invalid = |r: io::Result<Vec<u8>>| r.unwrap_err().kind() == io::ErrorKind::InvalidInput;
+        let long_name = "x".repeat(256);
+        assert!(invalid(CaptureHeader::new(&[long_name.as_str()]).to_bytes()));
+        assert!(invalid(CaptureHeader::new(&["a"; 256]).to_bytes()));
+        let mut header = CaptureHeader::new(&["a"; 255]);
+        assert!(header.to_bytes().is_ok());
+        header.metadata.push((String::from("notes"), "x".repeat(70000)));
+        assert!(invalid(header.to_bytes()));
+
+        let mut file = Vec::new();
+        let mut writer = CaptureWriter::new(&mut file, &CaptureHeader::new(&["a"])).unwrap();
+        let record = CaptureRecord { time: Duration::ZERO, direction: Direction::Sent, interface: 0, bytes: vec![0; 70000] };
+        assert_eq!(writer.record_at(&record).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+}
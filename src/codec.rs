@@ -0,0 +1,181 @@
+// mod codec
+// Encoder and Decoder for tokio_util::codec, so a Framed socket or serial stream gives and
+// takes whole messages.  Only built with the tokio feature.
+//
+// Like framing::FrameBuffer, the decoder resyncs rather than failing: anything that can't be the
+// start of a message, or a message not followed by its framing, is skipped a byte at a time and
+// counted in skipped.  A decoder error would end the stream, and one bad byte on a serial line
+// shouldn't do that.
+
+use std::io;
+use bytes::{Buf, BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+use crate::framing::{scan, Framing, Scan};
+use crate::{UCGMessage, UCGMessageInternal, UCGScriptedMessageInternal};
+
+pub struct UcgCodec {
+    scripted: bool,
+    framing: Framing,
+    pub skipped: usize, // bytes thrown away so far
+}
+
+impl UcgCodec {
+    pub fn new(scripted: bool, framing: Framing) -> UcgCodec {
+        UcgCodec { scripted, framing, skipped: 0 }
+    }
+
+    pub fn immediate(framing: Framing) -> UcgCodec {
+        UcgCodec::new(false, framing)
+    }
+
+    pub fn scripted(framing: Framing) -> UcgCodec {
+        UcgCodec::new(true, framing)
+    }
+}
+
+impl Decoder for UcgCodec {
+    type Item = Box<dyn UCGMessage>;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Box<dyn UCGMessage>>, io::Error> {
+        loop {
+            let size = match scan(src, self.framing, self.scripted) {
+                Scan::Incomplete(n) => {
+                    src.reserve(n - src.len());
+                    return Ok(None);
+                }
+                Scan::Junk(_) => {
+                    src.advance(1);
+                    self.skipped += 1;
+                    continue;
+                }
+                Scan::Message(size) => size,
+            };
+            let trailer = self.framing.trailer().len();
+            let mut bytes = src.split_to(size).to_vec();
+            src.advance(trailer);
+            let msg = if self.scripted {
+                UCGScriptedMessageInternal::from_byte_vec(&mut bytes)
+            } else {
+                UCGMessageInternal::from_byte_vec(&mut bytes)
+            };
+            match msg {
+                Some(m) => return Ok(Some(m)),
+                None => self.skipped += size + trailer,
+            }
+        }
+    }
+
+    // What is left at the end of the stream can't be finished, so rather than the default error
+    // it is skipped a byte at a time like any other junk, in case a whole message follows a
+    // header that was cut short.
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Box<dyn UCGMessage>>, io::Error> {
+        loop {
+            if let Some(m) = self.decode(src)? {
+                return Ok(Some(m));
+            }
+            if src.is_empty() {
+                return Ok(None);
+            }
+            src.advance(1);
+            self.skipped += 1;
+        }
+    }
+}
+
+impl<'m> Encoder<&'m dyn UCGMessage> for UcgCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: &'m dyn UCGMessage, dst: &mut BytesMut) -> Result<(), io::Error> {
+        if msg.as_any().is::<UCGScriptedMessageInternal>() != self.scripted {
+            let mode = if self.scripted { "scripted" } else { "immediate" };
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Codec is for {} messages.", mode)));
+        }
+        dst.put_slice(&msg.into_byte_vec());
+        dst.put_slice(self.framing.trailer());
+        Ok(())
+    }
+}
+
+impl Encoder<Box<dyn UCGMessage>> for UcgCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: Box<dyn UCGMessage>, dst: &mut BytesMut) -> Result<(), io::Error> {
+        self.encode(msg.as_ref(), dst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::codec::*;
+
+    #[test]
+    fn codec_round_trip() {
+        let mut codec = UcgCodec::immediate(Framing::Crlf);
+        let mut buf = BytesMut::new();
+        let msg = UCGMessageInternal::parse_asm_line("03/4 1F/7 RQRY 001 01", false).unwrap();
+        codec.encode(msg, &mut buf).unwrap();
+        assert_eq!(&buf[..], &[0x1C, 0xFF, 0x08, 0x01, 0x01, b'\r', b'\n']);
+        let scripted = UCGScriptedMessageInternal::parse_asm_line("+5 03/4 1F/7 NOP 000", false).unwrap();
+        assert!(codec.encode(scripted.as_ref(), &mut BytesMut::new()).is_err());
+
+        // A stray byte in front, then the message arriving in two pieces
+        let mut input = BytesMut::from(&[0x00][..]);
+        input.extend_from_slice(&buf[..3]);
+        assert!(codec.decode(&mut input).unwrap().is_none());
+        input.extend_from_slice(&buf[3..]);
+        let decoded = codec.decode(&mut input).unwrap().unwrap();
+        assert_eq!(decoded.into_asm(false), "03/4 1F/7 RQRY 001 01");
+        assert_eq!(codec.skipped, 1);
+        assert!(input.is_empty());
+
+        let mut codec = UcgCodec::scripted(Framing::Raw);
+        let mut buf = BytesMut::new();
+        codec.encode(scripted, &mut buf).unwrap();
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap().into_asm(false), "+5 03/4 1F/7 NOP 000");
+    }
+
+    #[test]
+    fn codec_truncated_at_eof() {
+        let msg = [0x1C, 0xFF, 0x08, 0x01, 0x01, b'\r', b'\n'];
+        let mut codec = UcgCodec::immediate(Framing::Crlf);
+        let mut input = BytesMut::from(&msg[..]);
+        input.extend_from_slice(&msg[..3]);
+        assert!(codec.decode_eof(&mut input).unwrap().is_some());
+        assert!(codec.decode_eof(&mut input).unwrap().is_none());
+        assert_eq!(codec.skipped, 3);
+        assert!(input.is_empty());
+
+        // A header promising more than is left doesn't hide the message after it
+        let mut codec = UcgCodec::immediate(Framing::Crlf);
+        let mut input = BytesMut::from(&[0x1C, 0xFF, 0x0F, 0xFF][..]);
+        input.extend_from_slice(&msg);
+        assert!(codec.decode(&mut input).unwrap().is_none());
+        assert_eq!(codec.decode_eof(&mut input).unwrap().unwrap().into_asm(false), "03/4 1F/7 RQRY 001 01");
+        assert!(codec.decode_eof(&mut input).unwrap().is_none());
+        assert_eq!(codec.skipped, 4);
+    }
+
+    #[test]
+    fn codec_skips_junk() {
+        // A scripted message missing its framing, then a good one.  Bytes inside the bad one can
+        // look like a header promising more than is there, so only the end of the stream gets
+        // past them.
+        let good = [0x05, 0x00, 0x00, 0x80, 0x1C, 0xFF, 0x00, 0x00, b'\r', b'\n'];
+        let mut codec = UcgCodec::scripted(Framing::Crlf);
+        let mut input = BytesMut::from(&good[..8]);
+        input.extend_from_slice(b"XX");
+        input.extend_from_slice(&good);
+        assert!(codec.decode(&mut input).unwrap().is_none());
+        assert_eq!(codec.decode_eof(&mut input).unwrap().unwrap().into_asm(false), "+5 03/4 1F/7 NOP 000");
+        assert_eq!(codec.skipped, 10);
+        assert!(input.is_empty());
+
+        // Nothing but junk ends the stream quietly
+        let mut codec = UcgCodec::immediate(Framing::Raw);
+        let mut input = BytesMut::from(&[0xFF, 0xFF, 0xFF][..]);
+        assert!(codec.decode(&mut input).unwrap().is_none());
+        assert!(codec.decode_eof(&mut input).unwrap().is_none());
+        assert_eq!(codec.skipped, 3);
+    }
+}
@@ -0,0 +1,215 @@
+// mod config
+// Project defaults for rgas and dergas, read from an rgas.toml found in the working directory
+// or any directory above it.  Command-line flags always win over the file.
+//
+//   immediate = false          # -m
+//   hex = true                 # -x, hexadecimal output from rgas
+//   container = true           # --container, header on binary output from rgas
+//   decimal = false            # -d, decimal data from dergas
+//   framing = "crlf"           # -f, "crlf" or "raw"
+//   symbols = ["devices.sym"]  # -S, symbol files
+//   include = ["scripts"]      # -L, directories searched for input and symbol files
+//   max-duration = 86400       # --max-duration for rgas --check
+//   port = "/dev/ttyUSB0"      # --port for rgas send
+//   baud = 115200              # --baud for rgas send
+//
+//   [lint]
+//   self-addressed = "allow"   # "allow", "warn" or "deny"
+//
+// Relative paths in the file are taken relative to the directory the file is in.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use argparse::{ArgumentParser, Collect, Store, StoreTrue};
+use serde::Deserialize;
+use crate::framing::Framing;
+use crate::symbols::SymbolTable;
+use crate::Severity;
+
+pub const CONFIG_FILE_NAME: &str = "rgas.toml";
+
+#[derive(Deserialize, Default)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    pub immediate: bool,
+    pub hex: bool,
+    pub container: bool,
+    pub decimal: bool,
+    pub framing: Option<String>,
+    pub symbols: Vec<PathBuf>,
+    pub include: Vec<PathBuf>,
+    pub max_duration: Option<u64>,
+    pub port: Option<String>,
+    pub baud: Option<u32>,
+    pub lint: HashMap<String, String>,
+}
+
+impl Config {
+    // Walk upward from dir looking for rgas.toml.
+    pub fn find(dir: &Path) -> Option<PathBuf> {
+        dir.ancestors()
+            .map(|d| d.join(CONFIG_FILE_NAME))
+            .find(|p| p.is_file())
+    }
+
+    pub fn load(path: &Path) -> Result<Config, String> {
+        let text = match fs::read_to_string(path) {
+            Ok(t) => t,
+            Err(e) => return Err(format!("Unable to open {}: {}", path.display(), e)),
+        };
+        let mut config: Config = match toml::from_str(&text) {
+            Ok(c) => c,
+            Err(e) => return Err(format!("{}: {}", path.display(), e)),
+        };
+        let base = path.parent().unwrap_or_else(|| Path::new("."));
+        for p in config.symbols.iter_mut().chain(config.include.iter_mut()) {
+            if p.is_relative() {
+                *p = base.join(&p);
+            }
+        }
+        // Catch mistakes now rather than when the setting is first used
+        config.framing()?;
+        config.lint_levels()?;
+        Ok(config)
+    }
+
+    // The config that applies from the working directory, or the defaults if there isn't one.
+    pub fn discover() -> Result<Config, String> {
+        let cwd = env::current_dir().map_err(|e| format!("Unable to get working directory: {}", e))?;
+        match Config::find(&cwd) {
+            Some(path) => Config::load(&path),
+            None => Ok(Config::default()),
+        }
+    }
+
+    // The config for a command line: --no-config skips the file and --config names one explicitly,
+    // either as --config path or --config=path.  The tools still have to accept both options in
+    // their own parsers, which add_options does.
+    pub fn for_args(args: &[String]) -> Result<Config, String> {
+        if args.iter().any(|a| a == "--no-config") {
+            return Ok(Config::default());
+        }
+        for (i, arg) in args.iter().enumerate() {
+            if let Some(path) = arg.strip_prefix("--config=") {
+                return Config::load(Path::new(path));
+            }
+            if arg == "--config" {
+                return match args.get(i + 1) {
+                    Some(path) => Config::load(Path::new(path)),
+                    None => Err(String::from("--config needs a file name.")),
+                };
+            }
+        }
+        Config::discover()
+    }
+
+    // --config and --no-config, so a parser accepts what for_args has already acted on.
+    pub fn add_options<'a>(ap: &mut ArgumentParser<'a>, file: &'a mut String, skip: &'a mut bool) {
+        ap.refer(file)
+            .add_option(&["--config"], Store, "Read defaults from this file instead of searching for rgas.toml.");
+        ap.refer(skip)
+            .add_option(&["--no-config"], StoreTrue, "Ignore rgas.toml.");
+    }
+
+    // -S and -L.  Once the arguments are parsed, add_paths puts what they collected after the
+    // symbol files and include paths from rgas.toml.
+    pub fn add_path_options<'a>(ap: &mut ArgumentParser<'a>, symbols: &'a mut Vec<String>, include: &'a mut Vec<String>) {
+        ap.refer(symbols)
+            .add_option(&["-S", "--symbols"], Collect, "Symbol file of device names to use in place of addresses.");
+        ap.refer(include)
+            .add_option(&["-L", "--include"], Collect, "Directory to search for input and symbol files.");
+    }
+
+    pub fn add_paths(&mut self, symbols: &[String], include: &[String]) {
+        self.symbols.extend(symbols.iter().map(PathBuf::from));
+        self.include.extend(include.iter().map(PathBuf::from));
+    }
+
+    pub fn framing(&self) -> Result<Framing, String> {
+        match &self.framing {
+            Some(f) => f.parse(),
+            None => Ok(Framing::default()),
+        }
+    }
+
+    // Lint rule overrides, in the form lint::LintOptions expects.
+    pub fn lint_levels(&self) -> Result<HashMap<String, Option<Severity>>, String> {
+        let mut levels = HashMap::new();
+        for (rule, level) in &self.lint {
+            if crate::lint::find_rule(rule).is_none() {
+                return Err(format!("Unknown lint rule in {}: {}", CONFIG_FILE_NAME, rule));
+            }
+            let level = match level.as_str() {
+                "allow" => None,
+                "warn" => Some(Severity::Warning),
+                "deny" => Some(Severity::Error),
+                _ => return Err(format!("Invalid lint level for {}: \"{}\".  Expected allow, warn or deny.", rule, level)),
+            };
+            levels.insert(rule.clone(), level);
+        }
+        Ok(levels)
+    }
+
+    // Find a file as given, or failing that in one of the include directories.
+    pub fn resolve(&self, name: &Path) -> PathBuf {
+        if name.is_absolute() || name.exists() {
+            return name.to_path_buf();
+        }
+        self.include.iter()
+            .map(|dir| dir.join(name))
+            .find(|p| p.exists())
+            .unwrap_or_else(|| name.to_path_buf())
+    }
+
+    pub fn load_symbols(&self) -> Result<SymbolTable, String> {
+        let mut table = SymbolTable::new();
+        for path in &self.symbols {
+            table.load_file(&self.resolve(path))?;
+        }
+        Ok(table)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::*;
+
+    #[test]
+    fn config_load_and_find() {
+        let dir = env::temp_dir().join(format!("rgas-config-test-{}", std::process::id()));
+        let nested = dir.join("scripts").join("day1");
+        fs::create_dir_all(&nested).unwrap();
+        fs::write(dir.join(CONFIG_FILE_NAME),
+            "immediate = true\nframing = \"raw\"\nsymbols = [\"devices.sym\"]\n\n[lint]\nself-addressed = \"allow\"\n").unwrap();
+        fs::write(dir.join("devices.sym"), "IMU 03/4\n").unwrap();
+
+        let path = Config::find(&nested).unwrap();
+        let config = Config::load(&path).unwrap();
+        assert!(config.immediate);
+        assert!(!config.hex);
+        assert_eq!(config.framing().unwrap(), Framing::Raw);
+        assert_eq!(config.lint_levels().unwrap().get("self-addressed"), Some(&None));
+        assert_eq!(config.load_symbols().unwrap().address("imu"), Some((3, 4)));
+
+        // -S and -L come on top of rgas.toml
+        fs::write(nested.join("more.sym"), "GROUND 1F/7\n").unwrap();
+        let mut config = config;
+        config.add_paths(&[String::from("more.sym")], &[nested.display().to_string()]);
+        let symbols = config.load_symbols().unwrap();
+        assert_eq!(symbols.address("imu"), Some((3, 4)));
+        assert_eq!(symbols.address("ground"), Some((0x1F, 7)));
+
+        let named = |arg: String| Config::for_args(&[String::from("rgas"), arg, String::from("x.rgas")]);
+        assert!(named(format!("--config={}", path.display())).unwrap().immediate);
+        assert!(!named(String::from("--no-config")).unwrap().immediate);
+        assert!(named(String::from("--config=no-such-file.toml")).is_err());
+
+        fs::write(dir.join(CONFIG_FILE_NAME), "[lint]\nno-such-rule = \"warn\"\n").unwrap();
+        assert!(Config::load(&path).is_err());
+        fs::write(dir.join(CONFIG_FILE_NAME), "colour = true\n").unwrap();
+        assert!(Config::load(&path).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,275 @@
+// mod container
+// An optional header in front of a binary file saying what is in it, so dergas doesn't have to
+// be told.  Files without one are read the old way.  All integers are little-endian:
+//
+//   magic      8 bytes   89 52 47 41 53 0D 0A 1A  (\x89RGAS\r\n\x1a)
+//   version    1 byte    layout of this header, currently 1
+//   protocol   1 byte    UCG protocol version the messages are for, 2
+//   flags      1 byte    bit 0: scripted, bit 1: raw framing rather than CRLF
+//   reserved   1 byte    0
+//   count      4 bytes   number of messages
+//   metalen    2 bytes   length of the metadata
+//   metadata   metalen   UTF-8 "key=value" lines: who made the file, when, and from what
+//   crc        4 bytes   CRC-32 of everything in the file except these 4 bytes
+//
+// The messages follow the header exactly as they would be written without it.  Offsets that
+// dergas and the source map report count from the end of the header.
+
+use std::convert::TryFrom;
+use std::io;
+use std::io::Read;
+use crate::framing::Framing;
+
+pub const MAGIC: [u8; 8] = *b"\x89RGAS\r\n\x1a";
+pub const CONTAINER_VERSION: u8 = 1;
+pub const PROTOCOL_VERSION: u8 = 2;
+
+const FLAG_SCRIPTED: u8 = 0b01;
+const FLAG_RAW: u8 = 0b10;
+
+// CRC-32 as used by zip and PNG.
+#[derive(Clone, Copy)]
+pub struct Crc32(u32);
+
+impl Default for Crc32 {
+    fn default() -> Crc32 {
+        Crc32(0xFFFFFFFF)
+    }
+}
+
+impl Crc32 {
+    pub fn new() -> Crc32 {
+        Crc32::default()
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 ^= b as u32;
+            for _ in 0..8 {
+                let mask = (self.0 & 1).wrapping_neg();
+                self.0 = (self.0 >> 1) ^ (0xEDB88320 & mask);
+            }
+        }
+    }
+
+    pub fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+pub fn is_container(sample: &[u8]) -> bool {
+    sample.starts_with(&MAGIC)
+}
+
+pub struct ContainerHeader {
+    pub version: u8,
+    pub protocol: u8,
+    pub scripted: bool,
+    pub framing: Framing,
+    pub count: u32,
+    pub metadata: Vec<(String, String)>,
+    pub crc: u32,
+    header_crc: Crc32, // CRC of the header bytes, as read or sealed
+}
+
+impl ContainerHeader {
+    pub fn new(scripted: bool, framing: Framing) -> ContainerHeader {
+        ContainerHeader {
+            version: CONTAINER_VERSION,
+            protocol: PROTOCOL_VERSION,
+            scripted,
+            framing,
+            count: 0,
+            metadata: Vec::new(),
+            crc: 0,
+            header_crc: Crc32::new(),
+        }
+    }
+
+    // Everything up to the CRC.
+    fn prefix(&self) -> Result<Vec<u8>, String> {
+        let mut flags = 0;
+        if self.scripted {
+            flags |= FLAG_SCRIPTED;
+        }
+        if self.framing == Framing::Raw {
+            flags |= FLAG_RAW;
+        }
+        let meta: String = self.metadata.iter().map(|(k, v)| format!("{}={}\n", k, v)).collect();
+        let metalen = match u16::try_from(meta.len()) {
+            Ok(n) => n,
+            Err(_) => return Err(format!("Container metadata is {} bytes, but the header only has room for {}.", meta.len(), u16::MAX)),
+        };
+        let mut out = MAGIC.to_vec();
+        out.extend_from_slice(&[self.version, self.protocol, flags, 0]);
+        out.extend_from_slice(&self.count.to_le_bytes());
+        out.extend_from_slice(&metalen.to_le_bytes());
+        out.extend_from_slice(meta.as_bytes());
+        Ok(out)
+    }
+
+    // The CRC state after the header, ready to take the messages.
+    pub fn crc_start(&self) -> Crc32 {
+        self.header_crc
+    }
+
+    // Fill in the count and CRC for a finished set of messages.
+    pub fn seal(&mut self, count: usize, body: &[u8]) -> Result<(), String> {
+        self.count = count as u32;
+        let mut crc = Crc32::new();
+        crc.update(&self.prefix()?);
+        self.header_crc = crc;
+        crc.update(body);
+        self.crc = crc.finish();
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
+        let mut out = self.prefix()?;
+        out.extend_from_slice(&self.crc.to_le_bytes());
+        Ok(out)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.metadata.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+
+    // Read a header, leaving the reader at the first message.
+    pub fn read_from<R: Read>(input: &mut R) -> Result<ContainerHeader, String> {
+        let fail = |e: io::Error| format!("Unable to read container header: {}", e);
+        let mut fixed = [0u8; 18];
+        input.read_exact(&mut fixed).map_err(fail)?;
+        if !is_container(&fixed) {
+            return Err(String::from("Not an rgas container (bad magic number)."));
+        }
+        let (version, protocol, flags) = (fixed[8], fixed[9], fixed[10]);
+        if version != CONTAINER_VERSION {
+            return Err(format!("Container version {} is not supported; this rgas reads version {}.", version, CONTAINER_VERSION));
+        }
+        if protocol != PROTOCOL_VERSION {
+            return Err(format!("Container holds UCG protocol version {}, but rgas only knows version {}.", protocol, PROTOCOL_VERSION));
+        }
+        let count = u32::from_le_bytes([fixed[12], fixed[13], fixed[14], fixed[15]]);
+        let mut meta = vec![0u8; u16::from_le_bytes([fixed[16], fixed[17]]) as usize];
+        input.read_exact(&mut meta).map_err(fail)?;
+        let mut crc = [0u8; 4];
+        input.read_exact(&mut crc).map_err(fail)?;
+        // The messages are checked against the header as it was written, not as we would write it
+        let mut header_crc = Crc32::new();
+        header_crc.update(&fixed);
+        header_crc.update(&meta);
+        let meta = match String::from_utf8(meta) {
+            Ok(m) => m,
+            Err(_) => return Err(String::from("Container metadata is not valid UTF-8.")),
+        };
+        let metadata = meta.lines()
+            .filter_map(|l| l.split_once('='))
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        Ok(ContainerHeader {
+            version,
+            protocol,
+            scripted: flags & FLAG_SCRIPTED != 0,
+            framing: if flags & FLAG_RAW != 0 { Framing::Raw } else { Framing::Crlf },
+            count,
+            metadata,
+            crc: u32::from_le_bytes(crc),
+            header_crc,
+        })
+    }
+}
+
+// Passes reads through while keeping a CRC of everything read, to check the messages against
+// the header once they have all gone by.
+pub struct CrcReader<R: Read> {
+    inner: R,
+    crc: Crc32,
+}
+
+impl<R: Read> CrcReader<R> {
+    pub fn new(inner: R, start: Crc32) -> CrcReader<R> {
+        CrcReader { inner, crc: start }
+    }
+
+    pub fn crc(&self) -> u32 {
+        self.crc.finish()
+    }
+}
+
+impl<R: Read> Read for CrcReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.crc.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::container::*;
+
+    #[test]
+    fn container_round_trip() {
+        let mut c = Crc32::new();
+        c.update(b"123456789");
+        assert_eq!(c.finish(), 0xCBF43926);
+
+        let body = [0x1C, 0xFF, 0x08, 0x01, 0x01];
+        let mut header = ContainerHeader::new(false, Framing::Raw);
+        header.metadata.push((String::from("creator"), String::from("rgas test")));
+        header.seal(1, &body).unwrap();
+        let mut file = header.to_bytes().unwrap();
+        file.extend_from_slice(&body);
+        assert!(is_container(&file));
+
+        let mut input = &file[..];
+        let read = ContainerHeader::read_from(&mut input).unwrap();
+        assert!(!read.scripted);
+        assert_eq!(read.framing, Framing::Raw);
+        assert_eq!(read.count, 1);
+        assert_eq!(read.get("creator"), Some("rgas test"));
+        let mut reader = CrcReader::new(input, read.crc_start());
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, body);
+        assert_eq!(reader.crc(), read.crc);
+
+        file[2] = b'X';
+        assert!(ContainerHeader::read_from(&mut &file[..]).is_err());
+
+        let mut big = ContainerHeader::new(true, Framing::Crlf);
+        big.metadata.push((String::from("notes"), "x".repeat(70000)));
+        assert!(big.seal(0, &[]).is_err());
+        assert!(big.to_bytes().is_err());
+    }
+
+    #[test]
+    fn container_crc_covers_header_as_written() {
+        // Metadata lines without an "=" are dropped when read, so a rebuilt header would differ
+        let body = [0x1C, 0xFF, 0x08, 0x01, 0x01];
+        let mut file = MAGIC.to_vec();
+        file.extend_from_slice(&[CONTAINER_VERSION, PROTOCOL_VERSION, 0, 0, 1, 0, 0, 0, 6, 0]);
+        file.extend_from_slice(b"notes\n");
+        let mut crc = Crc32::new();
+        crc.update(&file);
+        crc.update(&body);
+        file.extend_from_slice(&crc.finish().to_le_bytes());
+        file.extend_from_slice(&body);
+
+        let mut input = &file[..];
+        let read = ContainerHeader::read_from(&mut input).unwrap();
+        assert!(read.metadata.is_empty());
+        let mut reader = CrcReader::new(input, read.crc_start());
+        reader.read_to_end(&mut Vec::new()).unwrap();
+        assert_eq!(reader.crc(), read.crc);
+
+        // A damaged message no longer matches
+        let last = file.len() - 1;
+        file[last] ^= 0xFF;
+        let mut input = &file[..];
+        let read = ContainerHeader::read_from(&mut input).unwrap();
+        let mut reader = CrcReader::new(input, read.crc_start());
+        reader.read_to_end(&mut Vec::new()).unwrap();
+        assert_ne!(reader.crc(), read.crc);
+    }
+}
@@ -0,0 +1,259 @@
+// mod correlate
+// Pairs replies with the commands that caused them.  A reply answers the oldest outstanding
+// command it could belong to: one sent from the address the reply goes to, to the address it
+// comes from, whose opcode allows that reply.  When a reply starts with a register or
+// subroutine number and several commands are waiting, the one about the same number wins.
+//
+// Commands not answered within their opcode's timeout are handed back to be sent again, as
+// many times as the opcode's retries allow, and then reported as timed out.  SRUN is answered
+// by OPOK when the subroutine starts, and is then completed by its SRET, which has no timeout.
+
+use std::collections::HashMap;
+use std::time::Duration;
+use crate::{UCGMessage, UCGMessageInternal};
+
+// Replies each command can get.  FAIL, NSUP and DERR can answer anything.
+pub fn expected_replies(op: &str) -> &'static [&'static str] {
+    match op {
+        "NOP" => &["MACK"],
+        "RQRY" | "RTYP" => &["RVAL", "MACK", "FAIL", "NSUP", "DERR"],
+        "SQST" => &["STAT", "MACK", "FAIL", "NSUP", "DERR"],
+        "RRTC" => &["RVAL", "OPOK", "MACK", "FAIL", "NSUP", "DERR"],
+        _ => &["OPOK", "MACK", "FAIL", "NSUP", "DERR"],
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Policy {
+    pub timeout: Duration,
+    pub retries: u32, // times to send again after the first attempt
+}
+
+impl Default for Policy {
+    fn default() -> Policy {
+        Policy { timeout: Duration::from_secs(1), retries: 0 }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub id: usize,
+    pub ground: (u8, u8), // where the command came from, and where replies go
+    pub device: (u8, u8), // where the command went
+    pub op: String,
+    pub arg: Option<u8>,  // register or subroutine number, if the command has one
+    pub bytes: Vec<u8>,   // the command, to send again on a retry
+    pub sent: Duration,   // when the latest attempt went
+    pub attempts: u32,
+}
+
+impl Request {
+    pub fn describe(&self) -> String {
+        match self.arg {
+            Some(a) => format!("{} {:02X} to {:02X}/{:X}", self.op, a, self.device.0, self.device.1),
+            None => format!("{} to {:02X}/{:X}", self.op, self.device.0, self.device.1),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Event {
+    Answered { request: Request, reply: String, latency: Duration },
+    Completed { request: Request, latency: Duration }, // SRET for a running SRUN
+    Retry(Request),                                    // send request.bytes again
+    TimedOut(Request),
+    Unexpected,                                        // the reply answers nothing outstanding
+}
+
+#[derive(Default)]
+pub struct Correlator {
+    pub default: Policy,
+    pub policies: HashMap<String, Policy>, // by opcode, in capitals
+    pending: Vec<Request>,
+    running: Vec<Request>, // SRUNs that have started but not returned
+    next_id: usize,
+}
+
+impl Correlator {
+    pub fn new() -> Correlator {
+        Correlator::default()
+    }
+
+    pub fn policy(&self, op: &str) -> Policy {
+        self.policies.get(op).copied().unwrap_or(self.default)
+    }
+
+    pub fn set_policy(&mut self, op: &str, policy: Policy) {
+        self.policies.insert(op.to_ascii_uppercase(), policy);
+    }
+
+    // Commands waiting for a reply, oldest first.
+    pub fn outstanding(&self) -> &[Request] {
+        &self.pending
+    }
+
+    // Note a command going out.  Returns its id.
+    pub fn send(&mut self, msg: &UCGMessageInternal, now: Duration) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.pending.push(Request {
+            id,
+            ground: (msg.source, msg.subsource),
+            device: (msg.target, msg.subtarget),
+            op: msg.op_to_text(),
+            arg: msg.data.first().copied(),
+            bytes: msg.into_byte_vec(),
+            sent: now,
+            attempts: 1,
+        });
+        id
+    }
+
+    // Match a message coming back from a device.
+    pub fn receive(&mut self, reply: &UCGMessageInternal, now: Duration) -> Event {
+        let op = reply.op_to_text();
+        let from = (reply.source, reply.subsource);
+        let to = (reply.target, reply.subtarget);
+        let arg = reply.data.first().copied();
+        let fits = |r: &Request| r.device == from && r.ground == to;
+        if op == "SRET" {
+            let found = self.running.iter().position(|r| fits(r) && r.arg == arg);
+            return match found {
+                Some(i) => {
+                    let request = self.running.remove(i);
+                    Event::Completed { latency: now - request.sent, request }
+                }
+                None => Event::Unexpected,
+            };
+        }
+        let candidates: Vec<usize> = (0..self.pending.len())
+            .filter(|&i| fits(&self.pending[i]) && expected_replies(&self.pending[i].op).contains(&op.as_str()))
+            .collect();
+        let chosen = candidates.iter().copied()
+            .find(|&i| arg.is_some() && self.pending[i].arg == arg)
+            .or_else(|| candidates.first().copied());
+        let request = match chosen {
+            Some(i) => self.pending.remove(i),
+            None => return Event::Unexpected,
+        };
+        if request.op == "SRUN" && op == "OPOK" {
+            self.running.push(request.clone());
+        }
+        Event::Answered { latency: now - request.sent, reply: op, request }
+    }
+
+    // Retry or give up on commands whose time is up.
+    pub fn poll(&mut self, now: Duration) -> Vec<Event> {
+        let mut events = Vec::new();
+        let mut still = Vec::new();
+        for mut r in std::mem::take(&mut self.pending) {
+            let policy = self.policy(&r.op);
+            if now < r.sent + policy.timeout {
+                still.push(r);
+            } else if r.attempts <= policy.retries {
+                r.attempts += 1;
+                r.sent = now;
+                events.push(Event::Retry(r.clone()));
+                still.push(r);
+            } else {
+                events.push(Event::TimedOut(r));
+            }
+        }
+        self.pending = still;
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::correlate::*;
+    use crate::{UCGMessage, UCGMessageInternal};
+
+    fn msg(line: &str) -> UCGMessageInternal {
+        let m = UCGMessageInternal::parse_asm_line(line, false).unwrap();
+        m.as_any().downcast_ref::<UCGMessageInternal>().unwrap().clone()
+    }
+
+    #[test]
+    fn correlate_replies() {
+        let ms = Duration::from_millis;
+        let mut c = Correlator::new();
+        c.set_policy("rwrt", Policy { timeout: ms(100), retries: 1 });
+        c.send(&msg("03/4 1F/7 RQRY 001 01"), ms(0));
+        c.send(&msg("03/4 1F/7 RQRY 001 02"), ms(0));
+        c.send(&msg("05/0 1F/7 RWRT 002 01 09"), ms(0));
+        assert_eq!(c.outstanding().len(), 3);
+
+        // The second query is answered first, and is picked out by register number
+        match c.receive(&msg("1F/7 03/4 RVAL 002 02 2A"), ms(20)) {
+            Event::Answered { request, reply, latency } => {
+                assert_eq!((request.arg, reply.as_str(), latency), (Some(2), "RVAL", ms(20)));
+            }
+            e => panic!("{:?}", e),
+        }
+        assert!(matches!(c.receive(&msg("1F/7 03/4 FAIL 001 01"), ms(30)), Event::Answered { .. }));
+        assert!(matches!(c.receive(&msg("1F/7 03/4 RVAL 002 01 2A"), ms(40)), Event::Unexpected));
+
+        // The write goes unanswered: one retry, then it times out
+        assert!(c.poll(ms(99)).is_empty());
+        match &c.poll(ms(100))[..] {
+            [Event::Retry(r)] => assert_eq!((r.describe().as_str(), r.attempts), ("RWRT 01 to 05/0", 2)),
+            e => panic!("{:?}", e),
+        }
+        assert!(matches!(&c.poll(ms(200))[..], [Event::TimedOut(_)]));
+        assert!(c.outstanding().is_empty());
+
+        c.send(&msg("03/4 1F/7 SRUN 001 05"), ms(300));
+        assert!(matches!(c.receive(&msg("1F/7 03/4 OPOK 001 05"), ms(310)), Event::Answered { .. }));
+        match c.receive(&msg("1F/7 03/4 SRET 002 05 00"), ms(1300)) {
+            Event::Completed { request, latency } => assert_eq!((request.op.as_str(), latency), ("SRUN", ms(1000))),
+            e => panic!("{:?}", e),
+        }
+    }
+
+    #[test]
+    fn correlate_retries_run_out() {
+        let ms = Duration::from_millis;
+        let mut c = Correlator::new();
+        c.default = Policy { timeout: ms(50), retries: 2 };
+        c.set_policy("NOP", Policy { timeout: ms(10), retries: 0 });
+        c.send(&msg("05/0 1F/7 RWRT 002 01 09"), ms(0));
+        c.send(&msg("05/0 1F/7 NOP 000"), ms(0));
+
+        // The NOP has no retries, so it times out at once; the write is sent twice more
+        let events = c.poll(ms(10));
+        assert!(matches!(&events[..], [Event::TimedOut(r)] if r.op == "NOP" && r.attempts == 1));
+        assert!(matches!(&c.poll(ms(50))[..], [Event::Retry(r)] if r.attempts == 2 && r.sent == ms(50)));
+        assert!(c.poll(ms(99)).is_empty());
+        match &c.poll(ms(100))[..] {
+            [Event::Retry(r)] => assert_eq!((r.attempts, &r.bytes[..]), (3, &[0x28, 0xFF, 0x30, 0x02, 0x01, 0x09][..])),
+            e => panic!("{:?}", e),
+        }
+        assert!(matches!(&c.poll(ms(150))[..], [Event::TimedOut(r)] if r.attempts == 3));
+        assert!(c.poll(ms(1000)).is_empty());
+
+        // A reply that turns up after the command gave up answers nothing
+        assert!(matches!(c.receive(&msg("1F/7 05/0 OPOK 001 01"), ms(160)), Event::Unexpected));
+    }
+
+    #[test]
+    fn correlate_unexpected_replies() {
+        let ms = Duration::from_millis;
+        let mut c = Correlator::new();
+        c.send(&msg("03/4 1F/7 RWRT 002 01 09"), ms(0));
+        // From the wrong device, to the wrong ground address, and a reply a write can't get
+        assert!(matches!(c.receive(&msg("1F/7 03/5 OPOK 001 01"), ms(1)), Event::Unexpected));
+        assert!(matches!(c.receive(&msg("1F/6 03/4 OPOK 001 01"), ms(1)), Event::Unexpected));
+        assert!(matches!(c.receive(&msg("1F/7 03/4 RVAL 002 01 00"), ms(1)), Event::Unexpected));
+        // An SRET with no SRUN running
+        assert!(matches!(c.receive(&msg("1F/7 03/4 SRET 001 05"), ms(1)), Event::Unexpected));
+        assert_eq!(c.outstanding().len(), 1);
+
+        // An SRUN answered with FAIL never starts, so its SRET is unexpected too
+        c.send(&msg("03/4 1F/7 SRUN 001 05"), ms(2));
+        assert!(matches!(c.receive(&msg("1F/7 03/4 FAIL 001 05"), ms(3)), Event::Answered { request, .. } if request.op == "SRUN"));
+        assert!(matches!(c.receive(&msg("1F/7 03/4 SRET 001 05"), ms(4)), Event::Unexpected));
+        assert!(matches!(c.receive(&msg("1F/7 03/4 OPOK 001 01"), ms(5)), Event::Answered { .. }));
+        assert!(c.outstanding().is_empty());
+    }
+}
@@ -0,0 +1,264 @@
+// mod disassembler
+// Decodes a byte stream into messages and renders them as assembly text.  This is dergas
+// without the command line, for tools that want to show or log traffic themselves.
+//
+// Disassembler::open works out what it has been given: hex text or binary, and whether there
+// is a container header saying the mode and framing.  Disassembler::new takes plain binary.
+
+use std::io::{BufRead, Read};
+use crate::annotate::annotate;
+use crate::container::{is_container, ContainerHeader, Crc32, CrcReader};
+use crate::framing::{Frame, FrameError, FrameReader, Framing};
+use crate::hex::{looks_like_hex, HexReader};
+use crate::symbols::SymbolTable;
+use crate::timeline::ScriptClock;
+use crate::{maps, UCGMessage, UCGMessageInternal, UCGScriptedMessageInternal};
+
+pub struct DecodedMessage {
+    pub index: usize,      // position among the decoded messages, counting from 0
+    pub offset: usize,     // where the message starts in the (decoded) stream
+    pub length: usize,     // bytes taken up in the stream, including framing
+    pub time: Option<u64>, // when it runs, in seconds from the start of the script
+    pub bytes: Vec<u8>,    // the message itself, without framing
+    pub message: Box<dyn UCGMessage>,
+}
+
+#[derive(Default, Clone)]
+pub struct RenderOptions {
+    pub decimal: bool,        // decimal rather than hex data
+    pub symbols: SymbolTable, // names to print in place of addresses
+    pub annotate: bool,       // add a comment with offset, time and payload readings
+}
+
+impl DecodedMessage {
+    // The message as assembly, without any comment.
+    pub fn asm(&self, opts: &RenderOptions) -> String {
+        let scripted = self.message.as_any().is::<UCGScriptedMessageInternal>();
+        opts.symbols.name_line(&self.message.into_asm(opts.decimal), scripted)
+    }
+
+    pub fn annotation(&self) -> String {
+        annotate(self.message.as_ref(), self.offset, self.time)
+    }
+
+    // The full line, annotated if asked for.
+    pub fn render(&self, opts: &RenderOptions) -> String {
+        if opts.annotate {
+            format!("{}  # {}", self.asm(opts), self.annotation())
+        } else {
+            self.asm(opts)
+        }
+    }
+}
+
+pub struct Disassembler<R: Read> {
+    frames: FrameReader<CrcReader<R>>,
+    immediate: bool,
+    clock: ScriptClock,
+    count: usize,
+    pub hex: bool,                           // input was hex text
+    pub container: Option<ContainerHeader>,  // header the input started with, if any
+}
+
+impl<R: Read> Disassembler<R> {
+    pub fn new(input: R, immediate: bool, framing: Framing) -> Disassembler<R> {
+        Disassembler::with_header(input, immediate, framing, None)
+    }
+
+    fn with_header(input: R, immediate: bool, framing: Framing, container: Option<ContainerHeader>) -> Disassembler<R> {
+        let crc = container.as_ref().map_or(Crc32::new(), |h| h.crc_start());
+        Disassembler {
+            frames: FrameReader::new(CrcReader::new(input, crc), framing, !immediate),
+            immediate,
+            clock: ScriptClock::new(),
+            count: 0,
+            hex: false,
+            container,
+        }
+    }
+
+    // Skip over damaged input instead of stopping; see framing.
+    pub fn recovering(mut self, recover: bool) -> Disassembler<R> {
+        self.frames = self.frames.recovering(recover);
+        self
+    }
+
+    pub fn immediate(&self) -> bool {
+        self.immediate
+    }
+
+    // Once everything has been read, check it against the container header.
+    pub fn check_container(&self) -> Result<(), String> {
+        let h = match &self.container {
+            Some(h) => h,
+            None => return Ok(()),
+        };
+        if self.count != h.count as usize {
+            Err(format!("Container header promises {} messages, but {} were decoded.", h.count, self.count))
+        } else if self.frames.get_ref().crc() != h.crc {
+            Err(String::from("Container CRC does not match; the file is damaged."))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn decode(&mut self, frame: Frame) -> Result<DecodedMessage, FrameError> {
+        let mut buf = frame.bytes.clone();
+        let message = if self.immediate {
+            UCGMessageInternal::from_byte_vec(&mut buf)
+        } else {
+            UCGScriptedMessageInternal::from_byte_vec(&mut buf)
+        };
+        let message = match message {
+            Some(m) => m,
+            None => {
+                return Err(FrameError {
+                    offset: frame.offset,
+                    length: frame.length,
+                    message: invalid_reason(&frame.bytes, !self.immediate),
+                });
+            }
+        };
+        let decoded = DecodedMessage {
+            index: self.count,
+            offset: frame.offset,
+            length: frame.length,
+            time: self.clock.advance_message(message.as_ref()),
+            bytes: frame.bytes,
+            message,
+        };
+        self.count += 1;
+        Ok(decoded)
+    }
+}
+
+// Says why from_byte_vec turned a message down.
+fn invalid_reason(bytes: &[u8], scripted: bool) -> String {
+    let start = if scripted { 4 } else { 0 };
+    let header = match bytes.get(start..start + 4) {
+        Some(h) => h,
+        None => return String::from("Message is shorter than its header."),
+    };
+    let op = header[2] >> 3;
+    let len = (((header[2] & 0x07) as usize) << 8) | header[3] as usize;
+    let payload = bytes.len() - start - 4;
+    if op > maps::MAX_OPCODE {
+        format!("Unknown opcode {}.", op)
+    } else if payload != len {
+        format!("Header says {} payload bytes, but the message has {}.", len, payload)
+    } else {
+        String::from("Not a valid message.")
+    }
+}
+
+impl Disassembler<Box<dyn Read>> {
+    // Take input in any form dergas understands.  hex forces hex or binary, or is detected if
+    // None.  A container header overrides immediate and framing; hex text is always unframed
+    // once decoded, since line breaks are just whitespace.
+    pub fn open<B: BufRead + 'static>(mut input: B, hex: Option<bool>, immediate: bool, framing: Framing)
+        -> Result<Disassembler<Box<dyn Read>>, String>
+    {
+        let peek = |input: &mut B| input.fill_buf().map(|b| b.to_vec()).map_err(|e| format!("Read error: {}", e));
+        let hex = match hex {
+            Some(h) => h,
+            None => looks_like_hex(&peek(&mut input)?),
+        };
+        if hex {
+            let mut d = Disassembler::new(Box::new(HexReader::new(input)) as Box<dyn Read>, immediate, Framing::Raw);
+            d.hex = true;
+            return Ok(d);
+        }
+        if is_container(&peek(&mut input)?) {
+            let header = ContainerHeader::read_from(&mut input)?;
+            let (immediate, framing) = (!header.scripted, header.framing);
+            return Ok(Disassembler::with_header(Box::new(input), immediate, framing, Some(header)));
+        }
+        Ok(Disassembler::new(Box::new(input), immediate, framing))
+    }
+}
+
+impl<R: Read> Iterator for Disassembler<R> {
+    type Item = Result<DecodedMessage, FrameError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.frames.next()? {
+            Ok(frame) => Some(self.decode(frame)),
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::disassembler::*;
+
+    #[test]
+    fn disassemble_stream() {
+        let stream: Vec<u8> = vec![
+            0x05, 0x00, 0x00, 0x80, 0x1C, 0xFF, 0x08, 0x01, 0x01, b'\r', b'\n',
+            0x02, 0x00, 0x00, 0x80, 0x1C, 0xFF, 0xF8, 0x00, b'\r', b'\n',
+        ];
+        let mut d = Disassembler::new(&stream[..], false, Framing::Crlf);
+        let first = d.next().unwrap().unwrap();
+        assert_eq!((first.index, first.offset, first.time), (0, 0, Some(5)));
+        let mut opts = RenderOptions::default();
+        opts.symbols.define("IMU", 3, 4);
+        assert_eq!(first.render(&opts), "+5 IMU 1F/7 RQRY 001 01");
+        opts.annotate = true;
+        assert_eq!(first.render(&opts), "+5 IMU 1F/7 RQRY 001 01  # offset 0; t=5s");
+        let e = d.next().unwrap().err().unwrap();
+        assert_eq!((e.offset, e.message.as_str()), (11, "Unknown opcode 31."));
+        assert!(d.next().is_none());
+
+        let text = b"1cff080101\n";
+        let mut d = Disassembler::open(&text[..], None, true, Framing::Crlf).unwrap();
+        assert!(d.hex);
+        assert_eq!(d.next().unwrap().unwrap().asm(&RenderOptions::default()), "03/4 1F/7 RQRY 001 01");
+        assert!(d.check_container().is_ok());
+    }
+
+    #[test]
+    fn disassemble_recovers() {
+        // A message, five bytes of damage, then a message that still runs at the right time
+        let mut stream = vec![0x05, 0x00, 0x00, 0x80, 0x1C, 0xFF, 0x08, 0x01, 0x01, b'\r', b'\n'];
+        stream.extend_from_slice(&[0x00, 0xFF, 0xFF, 0xF8, 0x00]);
+        stream.extend_from_slice(&[0x02, 0x00, 0x00, 0x80, 0x1C, 0xFF, 0x00, 0x00, b'\r', b'\n']);
+
+        // Without recovery the damage is one bad message and the rest is lost with it
+        let results: Vec<Result<DecodedMessage, FrameError>> = Disassembler::new(&stream[..], false, Framing::Crlf).collect();
+        assert!(results[0].is_ok());
+        assert!(results[1..].iter().all(|r| r.is_err()));
+
+        let mut d = Disassembler::new(&stream[..], false, Framing::Crlf).recovering(true);
+        assert!(d.next().unwrap().is_ok());
+        let e = d.next().unwrap().err().unwrap();
+        assert_eq!((e.offset, e.length), (11, 5));
+        assert!(e.to_string().ends_with("Skipped 5 bytes, offsets 11 to 15. (at byte offset 11)"), "{}", e);
+        let last = d.next().unwrap().unwrap();
+        assert_eq!((last.index, last.offset, last.time), (1, 16, Some(7)));
+        assert!(d.next().is_none());
+    }
+
+    #[test]
+    fn disassemble_checks_container() {
+        let body = [0x1C, 0xFF, 0x08, 0x01, 0x01];
+        let mut header = ContainerHeader::new(false, Framing::Raw);
+        header.seal(2, &body).unwrap();
+        let mut file = header.to_bytes().unwrap();
+        file.extend_from_slice(&body);
+        let decode = |file: Vec<u8>| {
+            let mut d = Disassembler::open(std::io::Cursor::new(file), Some(false), false, Framing::Crlf).unwrap();
+            assert!(d.immediate());
+            assert_eq!(d.by_ref().filter(|m| m.is_ok()).count(), 1);
+            d.check_container()
+        };
+        assert_eq!(decode(file.clone()), Err(String::from("Container header promises 2 messages, but 1 were decoded.")));
+
+        header.seal(1, &body).unwrap();
+        let mut file = header.to_bytes().unwrap();
+        file.extend_from_slice(&body);
+        assert_eq!(decode(file.clone()), Ok(()));
+        let last = file.len() - 1;
+        file[last] = 0x02;
+        assert_eq!(decode(file), Err(String::from("Container CRC does not match; the file is damaged.")));
+    }
+}
@@ -0,0 +1,174 @@
+// mod filter
+// Picks messages out of a decoded stream by address, opcode, payload length and script time.
+// Filters only decide what gets shown, so everything is decoded (and timed) either way.
+//
+//   address   03 matches every subaddress of 03, 03/4 only that one, or a symbol name
+//   opcodes   RQRY,RVAL
+//   ranges    4..8, 4.., ..8 or just 4; both ends are inclusive
+
+use crate::symbols::SymbolTable;
+use crate::{maps, UCGMessageInternal};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AddressPattern {
+    pub main: u8,
+    pub sub: Option<u8>,
+}
+
+impl AddressPattern {
+    pub fn parse(s: &str, symbols: &SymbolTable) -> Result<AddressPattern, String> {
+        if let Some((main, sub)) = symbols.address(s) {
+            return Ok(AddressPattern { main, sub: Some(sub) });
+        }
+        let bad = || format!("Invalid address \"{}\".  Expected TT, TT/S or a symbol name.", s);
+        let (main, sub) = match s.split_once('/') {
+            Some((m, s)) => (m, Some(s)),
+            None => (s, None),
+        };
+        let main = u8::from_str_radix(main, 16).map_err(|_| bad())?;
+        let sub = match sub {
+            Some(s) => Some(u8::from_str_radix(s, 16).map_err(|_| bad())?),
+            None => None,
+        };
+        if main > 0x1F || sub.is_some_and(|s| s > 7) {
+            return Err(bad());
+        }
+        Ok(AddressPattern { main, sub })
+    }
+
+    pub fn matches(&self, main: u8, sub: u8) -> bool {
+        self.main == main && self.sub.map_or(true, |s| s == sub)
+    }
+}
+
+// An inclusive range; either end may be left open.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Range {
+    pub min: Option<u64>,
+    pub max: Option<u64>,
+}
+
+impl Range {
+    pub fn parse(s: &str) -> Result<Range, String> {
+        let num = |t: &str| -> Result<Option<u64>, String> {
+            if t.is_empty() {
+                Ok(None)
+            } else {
+                t.parse().map(Some).map_err(|_| format!("Invalid range \"{}\".  Expected A..B, A.., ..B or A.", s))
+            }
+        };
+        let range = match s.split_once("..") {
+            Some((a, b)) => Range { min: num(a)?, max: num(b)? },
+            None => {
+                let n = num(s)?;
+                Range { min: n, max: n }
+            }
+        };
+        if let (Some(a), Some(b)) = (range.min, range.max) {
+            if a > b {
+                return Err(format!("Range \"{}\" is empty.", s));
+            }
+        }
+        Ok(range)
+    }
+
+    pub fn contains(&self, n: u64) -> bool {
+        self.min.map_or(true, |m| n >= m) && self.max.map_or(true, |m| n <= m)
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Filter {
+    pub targets: Vec<AddressPattern>, // empty means any
+    pub sources: Vec<AddressPattern>,
+    pub opcodes: Vec<u8>,
+    pub length: Range,
+    pub time: Range, // script time in seconds; immediate messages have none and always pass
+}
+
+impl Filter {
+    pub fn new() -> Filter {
+        Filter::default()
+    }
+
+    // A filter from the --target, --source, --op and --length options dergas and rgas monitor
+    // share.  An empty length means any.
+    pub fn from_options(targets: &[String], sources: &[String], opcodes: &[String], length: &str,
+                        symbols: &SymbolTable) -> Result<Filter, String> {
+        let mut filter = Filter::new();
+        for t in targets {
+            filter.targets.push(AddressPattern::parse(t, symbols)?);
+        }
+        for s in sources {
+            filter.sources.push(AddressPattern::parse(s, symbols)?);
+        }
+        for o in opcodes {
+            filter.add_opcodes(o)?;
+        }
+        if !length.is_empty() {
+            filter.length = Range::parse(length)?;
+        }
+        Ok(filter)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.targets.is_empty() && self.sources.is_empty() && self.opcodes.is_empty()
+            && self.length == Range::default() && self.time == Range::default()
+    }
+
+    // Add opcodes from a comma-separated list of mnemonics.
+    pub fn add_opcodes(&mut self, list: &str) -> Result<(), String> {
+        for name in list.split(',').map(|n| n.trim().to_ascii_uppercase()).filter(|n| !n.is_empty()) {
+            match maps::OPCODE_TO_NUM.get(name.as_str()) {
+                Some(&op) => self.opcodes.push(op),
+                None => return Err(format!("Invalid opcode: \"{}\".", name)),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn matches(&self, msg: &UCGMessageInternal, time: Option<u64>) -> bool {
+        (self.targets.is_empty() || self.targets.iter().any(|p| p.matches(msg.target, msg.subtarget)))
+            && (self.sources.is_empty() || self.sources.iter().any(|p| p.matches(msg.source, msg.subsource)))
+            && (self.opcodes.is_empty() || self.opcodes.contains(&msg.op))
+            && self.length.contains(msg.len as u64)
+            && time.map_or(true, |t| self.time.contains(t))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::filter::*;
+    use crate::{immediate_part, UCGMessage};
+
+    #[test]
+    fn filter_messages() {
+        let mut syms = SymbolTable::new();
+        syms.define("GROUND", 0x1F, 7);
+        let mut f = Filter::new();
+        assert!(f.is_empty());
+        f.targets.push(AddressPattern::parse("03", &syms).unwrap());
+        f.sources.push(AddressPattern::parse("ground", &syms).unwrap());
+        f.add_opcodes("rqry, RVAL").unwrap();
+        f.length = Range::parse("1..4").unwrap();
+        f.time = Range::parse("10..").unwrap();
+
+        let m = UCGMessageInternal::parse_asm_line("03/4 1F/7 RQRY 001 01", false).unwrap();
+        let m = immediate_part(m.as_ref()).unwrap();
+        assert!(f.matches(m, Some(10)));
+        assert!(f.matches(m, None));
+        assert!(!f.matches(m, Some(9)));
+        let other = UCGMessageInternal::parse_asm_line("04/4 1F/7 RQRY 001 01", false).unwrap();
+        assert!(!f.matches(immediate_part(other.as_ref()).unwrap(), Some(10)));
+
+        let opts = |op: &str| Filter::from_options(&[String::from("03")], &[String::from("ground")], &[String::from(op)], "1..4", &syms);
+        let g = opts("rqry, RVAL").unwrap();
+        assert!(g.targets == f.targets && g.sources == f.sources && g.opcodes == f.opcodes && g.length == f.length);
+        assert!(opts("NOPE").is_err());
+
+        assert!(f.add_opcodes("NOPE").is_err());
+        assert!(AddressPattern::parse("20/0", &syms).is_err());
+        assert!(Range::parse("8..4").is_err());
+        assert_eq!(Range::parse("..4").unwrap(), Range { min: None, max: Some(4) });
+    }
+}
@@ -0,0 +1,186 @@
+// mod formatter
+// Rewrites assembly sources into one canonical layout:
+//
+//   +5   03/4 1F/7 RQRY 001 01  # comment
+//   +120 03/4 1F/7 RWRT 003 02 D300
+//
+// Mnemonics and data are uppercased, addresses are written as two hex digits and one subaddress
+// digit, lengths as three decimal digits, and the header columns are padded to line up across
+// the file.  Data tokens keep their own notation, so D300 stays D300 rather than becoming 012C.
+// Comments and blank lines are kept where they are.
+
+use crate::symbols::SymbolTable;
+use crate::{split_comment, strip_time_unit, UCGMessage, UCGMessageInternal, UCGScriptedMessageInternal};
+
+#[derive(Default)]
+pub struct FormatOptions {
+    pub immediate: bool,
+    pub symbols: SymbolTable, // names used in place of addresses are kept, uppercased
+}
+
+// A message line broken into its columns, already normalized.
+struct Statement {
+    header: Vec<String>,
+    data: Vec<String>,
+    comment: Option<String>,
+}
+
+enum Line {
+    Text(String),
+    Message(Statement),
+}
+
+fn parse(line: &str, opts: &FormatOptions) -> Result<Box<dyn UCGMessage>, String> {
+    let line = opts.symbols.expand_line(line, !opts.immediate);
+    if opts.immediate {
+        UCGMessageInternal::parse_asm_line(&line, false)
+    } else {
+        UCGScriptedMessageInternal::parse_asm_line(&line, false)
+    }
+}
+
+fn normalize_address(tok: &str, symbols: &SymbolTable) -> Option<String> {
+    if symbols.address(tok).is_some() {
+        return Some(tok.to_string());
+    }
+    let (main, sub) = tok.split_once('/')?;
+    let main = u8::from_str_radix(main, 16).ok()?;
+    let sub = u8::from_str_radix(sub, 16).ok()?;
+    Some(format!("{:02X}/{:X}", main, sub))
+}
+
+fn normalize(code: &str, opts: &FormatOptions) -> Option<(Vec<String>, Vec<String>)> {
+    let upper = code.to_ascii_uppercase();
+    let mut tokens = upper.split_whitespace();
+    let mut header = Vec::new();
+    if !opts.immediate {
+        let ts = tokens.next()?;
+        header.push(match ts.strip_prefix('+') {
+            Some(rel) => format!("+{}", strip_time_unit(rel).parse::<u32>().ok()?),
+            None => format!("{}", strip_time_unit(ts).parse::<u32>().ok()?),
+        });
+    }
+    header.push(normalize_address(tokens.next()?, &opts.symbols)?);
+    header.push(normalize_address(tokens.next()?, &opts.symbols)?);
+    header.push(tokens.next()?.to_string());
+    header.push(format!("{:03}", tokens.next()?.parse::<u16>().ok()?));
+    Some((header, tokens.map(String::from).collect()))
+}
+
+fn render(stmt: &Statement, widths: &[usize]) -> String {
+    let mut out = String::new();
+    for (i, col) in stmt.header.iter().enumerate() {
+        let width = widths.get(i).copied().unwrap_or(0);
+        out.push_str(&format!("{:<w$} ", col, w = width));
+    }
+    out.push_str(&stmt.data.join(" "));
+    let mut out = out.trim_end().to_string();
+    if let Some(c) = &stmt.comment {
+        out.push_str("  #");
+        out.push_str(c.trim_end());
+    }
+    out
+}
+
+// Returns the formatted source, or the (line number, error) pairs for lines that don't assemble.
+pub fn format_source(src: &str, opts: &FormatOptions) -> Result<String, Vec<(usize, String)>> {
+    let mut lines = Vec::new();
+    let mut errors = Vec::new();
+    for (i, line) in src.lines().enumerate() {
+        let (code, comment) = split_comment(line);
+        if code.trim().is_empty() {
+            lines.push(Line::Text(match comment {
+                Some(c) => format!("#{}", c.trim_end()),
+                None => String::new(),
+            }));
+            continue;
+        }
+        let original = match parse(line, opts) {
+            Ok(m) => m.into_byte_vec(),
+            Err(e) => {
+                errors.push((i + 1, e));
+                continue;
+            }
+        };
+        match normalize(code, opts) {
+            Some((header, data)) => lines.push(Line::Message(Statement {
+                header,
+                data,
+                comment: comment.map(String::from),
+            })),
+            None => errors.push((i + 1, String::from("Unable to normalize statement."))),
+        }
+        // Formatting must never change what the line assembles to.
+        if let Some(Line::Message(stmt)) = lines.last() {
+            let formatted = render(stmt, &[]);
+            match parse(&formatted, opts) {
+                Ok(m) if m.into_byte_vec() == original => (),
+                _ => errors.push((i + 1, format!("Formatting would change the assembled bytes: \"{}\"", formatted))),
+            }
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    // Pad each header column to the widest entry in the file
+    let mut widths: Vec<usize> = Vec::new();
+    for line in &lines {
+        if let Line::Message(stmt) = line {
+            widths.resize(widths.len().max(stmt.header.len()), 0);
+            for (w, col) in widths.iter_mut().zip(&stmt.header) {
+                *w = (*w).max(col.len());
+            }
+        }
+    }
+    let mut out = String::new();
+    for line in &lines {
+        match line {
+            Line::Text(t) => out.push_str(t),
+            Line::Message(stmt) => out.push_str(&render(stmt, &widths)),
+        }
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::formatter::*;
+
+    #[test]
+    fn format_canonical_layout() {
+        let src = "# Start-up   \n\
+                   +5 3/4  1f/7 rqry 1 01\n\
+                   \n\
+                   +0120 03/4 1F/7 RWRT 3 02 d300   #  write\n\
+                   \t#indented\n";
+        let expected = "# Start-up\n\
+                        +5   03/4 1F/7 RQRY 001 01\n\
+                        \n\
+                        +120 03/4 1F/7 RWRT 003 02 D300  #  write\n\
+                        #indented\n";
+        let formatted = format_source(src, &FormatOptions::default()).unwrap();
+        assert_eq!(formatted, expected);
+        // Already-formatted sources come back unchanged
+        assert_eq!(format_source(&formatted, &FormatOptions::default()).unwrap(), formatted);
+    }
+
+    #[test]
+    fn format_reports_bad_lines() {
+        let src = "03/4 1F/7 NOP 0\n03/4 1F/7 NOPE 0\n";
+        let errors = format_source(src, &FormatOptions { immediate: true, ..Default::default() }).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, 2);
+    }
+
+    #[test]
+    fn format_drops_seconds_unit() {
+        let src = "+5s 03/4 1F/7 RQRY 001 01
+10S 03/4 1F/7 RQRY 001 01
+";
+        let expected = "+5 03/4 1F/7 RQRY 001 01
+10 03/4 1F/7 RQRY 001 01
+";
+        assert_eq!(format_source(src, &FormatOptions::default()).unwrap(), expected);
+    }
+}
@@ -0,0 +1,384 @@
+// mod framing
+// How messages are laid out one after another in a file or on a link, and a reader that splits
+// a byte stream back into messages.
+//
+// Messages carry their own length, so the reader always goes by the header rather than by
+// looking for separators.  Payload bytes of 0x0A are therefore safe in either framing.
+//
+// A damaged stream normally loses its place at the first bad message.  In recovery mode the
+// reader also checks each header is plausible, and when one isn't it scans forward a byte at a
+// time for the next spot that looks like the start of a message, reporting what it skipped.
+
+use std::io;
+use std::io::Read;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Framing {
+    #[default]
+    Crlf, // each message is followed by "\r\n"; what rgas has always written
+    Raw,  // messages are written back to back
+}
+
+impl Framing {
+    // Bytes written after every message.
+    pub fn trailer(&self) -> &'static [u8] {
+        match self {
+            Framing::Crlf => b"\r\n",
+            Framing::Raw => b"",
+        }
+    }
+}
+
+impl FromStr for Framing {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Framing, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "crlf" => Ok(Framing::Crlf),
+            "raw" | "none" => Ok(Framing::Raw),
+            _ => Err(format!("Unknown framing \"{}\".  Expected \"crlf\" or \"raw\".", s)),
+        }
+    }
+}
+
+// Size of everything before the payload: the timestamp in scripted mode, then the 4 byte header.
+pub fn header_size(scripted: bool) -> usize {
+    if scripted { 8 } else { 4 }
+}
+
+// Payload length from a header, which starts after the timestamp in scripted mode.
+pub fn payload_length(header: &[u8]) -> usize {
+    (((header[2] & 0b00000111) as usize) << 8) | header[3] as usize
+}
+
+// Whether a header's opcode is one we know, i.e. whether it could really be a header.
+pub fn known_opcode(header: &[u8]) -> bool {
+    header[2] >> 3 <= crate::maps::MAX_OPCODE
+}
+
+#[derive(Debug)]
+pub struct Frame {
+    pub offset: usize,  // where the message starts in the stream
+    pub bytes: Vec<u8>, // the message itself, without framing
+    pub length: usize,  // bytes taken up in the stream, including framing
+}
+
+#[derive(Debug)]
+pub struct FrameError {
+    pub offset: usize,
+    pub length: usize, // bytes dropped from the stream because of the error
+    pub message: String,
+}
+
+impl std::fmt::Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} (at byte offset {})", self.message, self.offset)
+    }
+}
+
+pub struct FrameReader<R: Read> {
+    inner: R,
+    framing: Framing,
+    scripted: bool,
+    recover: bool,
+    buf: Vec<u8>,  // read from inner but not yet handed out
+    offset: usize, // stream offset of buf[0]
+    eof: bool,
+}
+
+// What the bytes at some position in the buffer look like.
+enum Candidate {
+    Frame(usize),        // a whole message, with this many bytes including framing
+    Bad(String, usize),  // not a message; the bytes a non-recovering reader gives up on
+    Short(String),       // the stream ends before the message does
+}
+
+impl<R: Read> FrameReader<R> {
+    pub fn new(inner: R, framing: Framing, scripted: bool) -> FrameReader<R> {
+        FrameReader {
+            inner,
+            framing,
+            scripted,
+            recover: false,
+            buf: Vec::new(),
+            offset: 0,
+            eof: false,
+        }
+    }
+
+    // Read until at least n bytes are buffered.  Returns false if the stream ends first.
+    fn fill(&mut self, n: usize) -> io::Result<bool> {
+        let mut chunk = [0u8; 4096];
+        while self.buf.len() < n && !self.eof {
+            match self.inner.read(&mut chunk) {
+                Ok(0) => self.eof = true,
+                Ok(k) => self.buf.extend_from_slice(&chunk[..k]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(self.buf.len() >= n)
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    // Turn recovery mode on or off.
+    pub fn recovering(mut self, recover: bool) -> FrameReader<R> {
+        self.recover = recover;
+        self
+    }
+
+    fn error(&self, message: String, length: usize) -> FrameError {
+        FrameError { offset: self.offset, length, message }
+    }
+
+    // Could a header start at pos?  Only checked in recovery mode; otherwise the header is trusted
+    // and a bad opcode is left for the message parser to report.
+    fn plausible_header(&self, pos: usize) -> bool {
+        let hdr = header_size(self.scripted);
+        let header = &self.buf[pos + hdr - 4..pos + hdr];
+        !self.recover || known_opcode(header)
+    }
+
+    fn candidate(&mut self, pos: usize) -> io::Result<Candidate> {
+        let hdr = header_size(self.scripted);
+        let trailer = self.framing.trailer();
+        if !self.fill(pos + hdr)? {
+            return Ok(Candidate::Short(format!(
+                "Stream ends partway through a message header ({} of {} bytes).", self.buf.len() - pos, hdr)));
+        }
+        if !self.plausible_header(pos) {
+            return Ok(Candidate::Bad(String::from("Message header has an unknown opcode."), hdr));
+        }
+        let msg_len = hdr + payload_length(&self.buf[pos + hdr - 4..pos + hdr]);
+        let total = msg_len + trailer.len();
+        if !self.fill(pos + total)? {
+            return Ok(Candidate::Short(format!(
+                "Stream ends partway through a message ({} of {} bytes).", self.buf.len() - pos, total)));
+        }
+        if &self.buf[pos + msg_len..pos + total] != trailer {
+            return Ok(Candidate::Bad(format!("Message of {} bytes is not followed by the expected framing.", msg_len), total));
+        }
+        Ok(Candidate::Frame(total))
+    }
+
+    // After skipping garbage, is pos a good place to start again?  With no framing bytes to check,
+    // a lone header is easy to find by chance, so raw streams also need another plausible header
+    // straight after the message, unless the stream ends first.
+    fn resync_point(&mut self, pos: usize) -> io::Result<bool> {
+        let total = match self.candidate(pos)? {
+            Candidate::Frame(total) => total,
+            _ => return Ok(false),
+        };
+        if self.framing != Framing::Raw {
+            return Ok(true);
+        }
+        let next = pos + total;
+        if self.fill(next + header_size(self.scripted))? {
+            Ok(self.plausible_header(next))
+        } else {
+            Ok(true)
+        }
+    }
+
+    // Scan past bad bytes at the front of the buffer and report them.
+    fn skip_garbage(&mut self, reason: String) -> FrameError {
+        let mut skip = 1;
+        while skip < self.buf.len() {
+            match self.resync_point(skip) {
+                Ok(true) => break,
+                Ok(false) => skip += 1,
+                Err(e) => return self.error(format!("Read failed: {}", e), 0),
+            }
+        }
+        let err = self.error(format!("{}  Skipped {} bytes, offsets {} to {}.",
+                                     reason, skip, self.offset, self.offset + skip - 1), skip);
+        self.consume(skip);
+        err
+    }
+
+    // Drop n bytes from the front of the stream.
+    fn consume(&mut self, n: usize) -> Vec<u8> {
+        let rest = self.buf.split_off(n);
+        self.offset += n;
+        std::mem::replace(&mut self.buf, rest)
+    }
+
+    pub fn next_frame(&mut self) -> Option<Result<Frame, FrameError>> {
+        let found = match self.fill(1).and_then(|_| self.candidate(0)) {
+            Err(e) => return Some(Err(self.error(format!("Read failed: {}", e), 0))),
+            Ok(_) if self.buf.is_empty() => return None,
+            Ok(c) => c,
+        };
+        match found {
+            Candidate::Frame(total) => {
+                let offset = self.offset;
+                let mut bytes = self.consume(total);
+                bytes.truncate(total - self.framing.trailer().len());
+                Some(Ok(Frame { offset, bytes, length: total }))
+            }
+            Candidate::Bad(reason, _) | Candidate::Short(reason) if self.recover => {
+                Some(Err(self.skip_garbage(reason)))
+            }
+            Candidate::Bad(reason, n) => {
+                let err = self.error(reason, n);
+                self.consume(n);
+                Some(Err(err))
+            }
+            Candidate::Short(reason) => {
+                let n = self.buf.len();
+                let err = self.error(reason, n);
+                self.consume(n);
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+impl<R: Read> Iterator for FrameReader<R> {
+    type Item = Result<Frame, FrameError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_frame()
+    }
+}
+
+// What the front of a partly arrived stream holds.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Scan {
+    Incomplete(usize),  // nothing can be said until there are this many bytes
+    Junk(&'static str), // the first byte can't be the start of a message, for this reason
+    Message(usize),     // a whole message of this many bytes, followed by its framing
+}
+
+// Look at the front of a stream that turns up in pieces.  A header with an unknown opcode, or a
+// message not followed by its framing, means the stream has lost its place, and the caller should
+// drop the first byte and look again.  FrameBuffer and the tokio codec both split this way.
+pub fn scan(buf: &[u8], framing: Framing, scripted: bool) -> Scan {
+    let head = header_size(scripted);
+    let trailer = framing.trailer();
+    if buf.len() < head {
+        return Scan::Incomplete(head);
+    }
+    if !known_opcode(&buf[head - 4..head]) {
+        return Scan::Junk("not the start of a message");
+    }
+    let size = head + payload_length(&buf[head - 4..]);
+    if buf.len() < size + trailer.len() {
+        return Scan::Incomplete(size + trailer.len());
+    }
+    if &buf[size..size + trailer.len()] != trailer {
+        return Scan::Junk("no framing where expected");
+    }
+    Scan::Message(size)
+}
+
+// Splits messages out of a stream that turns up in pieces, such as a serial line or a socket.
+// Unlike FrameReader it never waits: push whatever has arrived, then take the whole messages.
+pub struct FrameBuffer {
+    pub framing: Framing,
+    scripted: bool,
+    buf: Vec<u8>,
+}
+
+impl FrameBuffer {
+    pub fn new(framing: Framing, scripted: bool) -> FrameBuffer {
+        FrameBuffer { framing, scripted, buf: Vec::new() }
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    // The next whole message, without framing, or None until more arrives.
+    pub fn next_frame(&mut self) -> Option<Result<Vec<u8>, String>> {
+        match scan(&self.buf, self.framing, self.scripted) {
+            Scan::Incomplete(_) => None,
+            Scan::Junk(reason) => {
+                let dropped = self.buf.remove(0);
+                Some(Err(format!("Dropped byte {:02X}: {}.", dropped, reason)))
+            }
+            Scan::Message(size) => {
+                let mut bytes: Vec<u8> = self.buf.drain(..size + self.framing.trailer().len()).collect();
+                bytes.truncate(size);
+                Some(Ok(bytes))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::framing::*;
+
+    #[test]
+    fn frames_by_length() {
+        // The second message has a payload byte of 0x0A, which used to split it in two
+        let stream: Vec<u8> = vec![
+            0x1C, 0xFF, 0x08, 0x01, 0x01, b'\r', b'\n',
+            0x1C, 0xFF, 0x28, 0x02, 0x01, 0x0A, b'\r', b'\n',
+        ];
+        let frames: Vec<Frame> = FrameReader::new(stream.as_slice(), Framing::Crlf, false)
+            .map(|f| f.unwrap())
+            .collect();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[1].offset, 7);
+        assert_eq!(frames[1].bytes, vec![0x1C, 0xFF, 0x28, 0x02, 0x01, 0x0A]);
+        assert_eq!(frames[1].length, 8);
+
+        let raw = [0x05, 0x00, 0x00, 0x80, 0x1C, 0xFF, 0x08, 0x01, 0x01];
+        let mut reader = FrameReader::new(&raw[..], Framing::Raw, true);
+        assert_eq!(reader.next().unwrap().unwrap().bytes, raw.to_vec());
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn frame_errors() {
+        let stream = [0x1C, 0xFF, 0x08, 0x01, 0x01, b'X', b'X', 0x1C];
+        let mut reader = FrameReader::new(&stream[..], Framing::Crlf, false);
+        assert_eq!(reader.next().unwrap().unwrap_err().offset, 0);
+        assert_eq!(reader.next().unwrap().unwrap_err().offset, 7);
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn frame_recovery() {
+        // Two good messages with junk between them, one byte of which looks like a bad header
+        let good = [0x1C, 0xFF, 0x08, 0x01, 0x01, b'\r', b'\n'];
+        let mut stream = good.to_vec();
+        stream.extend_from_slice(&[0xFF, 0xFF, 0xF8, 0x00, 0x13]);
+        stream.extend_from_slice(&good);
+        let mut reader = FrameReader::new(&stream[..], Framing::Crlf, false).recovering(true);
+        assert_eq!(reader.next().unwrap().unwrap().offset, 0);
+        let err = reader.next().unwrap().unwrap_err();
+        assert_eq!((err.offset, err.length), (7, 5));
+        assert_eq!(reader.next().unwrap().unwrap().offset, 12);
+        assert!(reader.next().is_none());
+
+        // Raw framing, with a truncated message at the end
+        let raw = [0x1C, 0xFF, 0x08, 0x01, 0x01, 0x07, 0x1C, 0xFF, 0x08, 0x01, 0x01, 0x1C, 0xFF, 0x08];
+        let results: Vec<Result<Frame, FrameError>> = FrameReader::new(&raw[..], Framing::Raw, false).recovering(true).collect();
+        assert_eq!(results.len(), 4);
+        assert_eq!(results[1].as_ref().unwrap_err().length, 1);
+        assert_eq!(results[2].as_ref().unwrap().offset, 6);
+        assert_eq!(results[3].as_ref().unwrap_err().length, 3);
+    }
+
+    #[test]
+    fn frame_buffer_drops() {
+        // Without the opcode check, 00 1C FF 08 would pass for a header and the buffer would sit
+        // waiting for an 1800 byte payload
+        let mut frames = FrameBuffer::new(Framing::Crlf, false);
+        frames.push(&[0x00, 0x1C, 0xFF, 0x08, 0x01, 0x01]);
+        assert_eq!(frames.next_frame(), Some(Err(String::from("Dropped byte 00: not the start of a message."))));
+        assert_eq!(frames.next_frame(), None);
+        frames.push(b"\r\n");
+        assert_eq!(frames.next_frame(), Some(Ok(vec![0x1C, 0xFF, 0x08, 0x01, 0x01])));
+
+        frames.push(&[0x1C, 0xFF, 0x08, 0x01, 0x01, b'X', b'X']);
+        assert_eq!(frames.next_frame(), Some(Err(String::from("Dropped byte 1C: no framing where expected."))));
+        assert_eq!(scan(&[0xFF, 0x08, 0x01, 0x01], Framing::Crlf, false), Scan::Incomplete(263));
+        assert_eq!(frames.next_frame(), None);
+    }
+}
@@ -0,0 +1,104 @@
+// mod hex
+// The hexadecimal text form rgas writes with -x, and a reader that turns it back into bytes.
+
+use std::io;
+use std::io::Read;
+
+pub fn hexlify(vec:&[u8]) -> Vec<u8> {
+    let hex = b"0123456789abcdef";
+    let mut ret: Vec<u8> = Vec::with_capacity(vec.len()*2);
+    for ch in vec {
+        ret.push(hex[((ch & 0xf0) >> 4) as usize]);
+        ret.push(hex[(ch & 0x0f) as usize]);
+    }
+    ret
+}
+
+// Guess whether the start of a file is hex text rather than binary.
+pub fn looks_like_hex(sample: &[u8]) -> bool {
+    sample.iter().any(|b| b.is_ascii_hexdigit())
+        && sample.iter().all(|b| b.is_ascii_hexdigit() || b.is_ascii_whitespace())
+}
+
+// Decodes hex text as it is read.  Digits may be either case, and whitespace anywhere (spaces,
+// tabs, LF or CRLF line endings) is skipped, so the decoded stream has no framing bytes in it.
+pub struct HexReader<R: Read> {
+    inner: R,
+    high: Option<u8>, // first digit of a pair we have only half of
+    pos: usize,       // offset in the text, for error messages
+}
+
+impl<R: Read> HexReader<R> {
+    pub fn new(inner: R) -> HexReader<R> {
+        HexReader { inner, high: None, pos: 0 }
+    }
+}
+
+fn digit_value(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|d| d as u8)
+}
+
+impl<R: Read> Read for HexReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if out.is_empty() {
+            return Ok(0);
+        }
+        let mut text = vec![0u8; out.len() * 2];
+        let mut produced = 0;
+        // Keep reading until we have at least one byte to hand back, or the text runs out
+        while produced == 0 {
+            // Don't read more digits than there is room to decode
+            let want = (out.len() - produced) * 2 - self.high.is_some() as usize;
+            let n = self.inner.read(&mut text[..want])?;
+            if n == 0 {
+                if self.high.is_some() {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                        format!("Hex input ends with half a byte at offset {}.", self.pos)));
+                }
+                break;
+            }
+            for &c in &text[..n] {
+                if c.is_ascii_whitespace() {
+                    self.pos += 1;
+                    continue;
+                }
+                let d = match digit_value(c) {
+                    Some(d) => d,
+                    None => {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                            format!("Invalid character {:?} in hex input at offset {}.", c as char, self.pos)));
+                    }
+                };
+                self.pos += 1;
+                match self.high.take() {
+                    Some(h) => {
+                        out[produced] = (h << 4) | d;
+                        produced += 1;
+                    }
+                    None => self.high = Some(d),
+                }
+            }
+        }
+        Ok(produced)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hex::*;
+
+    #[test]
+    fn hex_decode() {
+        let text = b"1cff080101\r\n1C FF 08 01 01\n\t1cFf0801\r\n01";
+        assert!(looks_like_hex(text));
+        assert!(!looks_like_hex(&[0x1C, 0xFF, 0x08, 0x01, 0x01]));
+        let mut bytes = Vec::new();
+        HexReader::new(&text[..]).read_to_end(&mut bytes).unwrap();
+        assert_eq!(bytes, [0x1C, 0xFF, 0x08, 0x01, 0x01].repeat(3));
+        assert_eq!(hexlify(&bytes[..5]), b"1cff080101".to_vec());
+
+        let mut bytes = Vec::new();
+        assert!(HexReader::new(&b"1cf"[..]).read_to_end(&mut bytes).is_err());
+        assert!(HexReader::new(&b"1cfg"[..]).read_to_end(&mut bytes).is_err());
+    }
+}
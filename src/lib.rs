@@ -4,16 +4,77 @@ use std::convert::TryInto;
 use std::any::Any;
 
 mod maps;
+pub mod annotate;
+pub mod assembler;
+pub mod bridge;
+pub mod capture;
+#[cfg(feature = "tokio")]
+pub mod codec;
+pub mod config;
+pub mod container;
+pub mod correlate;
+pub mod disassembler;
+pub mod filter;
+pub mod formatter;
+pub mod framing;
+pub mod hex;
+pub mod lint;
+pub mod monitor;
+pub mod pcapng;
+pub mod player;
+pub mod router;
+pub mod sim;
+pub mod sourcemap;
+pub mod stats;
+pub mod symbols;
+pub mod timeline;
+pub mod transport;
 
+// Unwrap a Result or Option in the command-line tools, panicking with the message otherwise.
+#[macro_export]
+macro_rules! check {
+    ($result:expr, $message:literal) => {
+        match($result) {Ok(val) => {val} Err(err) => panic!($message, err)}
+    };
+}
+
+#[macro_export]
+macro_rules! checkNone {
+    ($result:expr, $message:literal) => {
+        match($result) {Some(val) => {val} None => panic!($message)}
+    };
+}
+
+// Largest value that fits in the 31-bit timestamp of a scripted message.
+pub const MAX_TIMESTAMP: u32 = 0x7FFFFFFF;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Severity::Warning => f.pad("warning"),
+            Severity::Error => f.pad("error"),
+        }
+    }
+}
+
+// into_byte_vec and into_asm borrow self, but they are public names that callers already use.
+#[allow(clippy::wrong_self_convention)]
 pub trait UCGMessage {
     fn from_byte_vec(b: &mut Vec<u8>) -> Option<Box<dyn UCGMessage>> where Self: Sized;
-    fn parse_asm_line(line: &String, print_comments: bool) -> Result<Box<dyn UCGMessage>, String> where Self: Sized;
+    fn parse_asm_line(line: &str, print_comments: bool) -> Result<Box<dyn UCGMessage>, String> where Self: Sized;
     fn into_byte_vec(&self) -> Vec<u8>;
     fn into_asm(&self, print_decimal_data: bool) -> String;
     fn get_time(&self) -> &u32;
     fn as_any(&self) -> &dyn Any;
 }
 
+#[derive(Clone)]
 pub struct UCGMessageInternal {
     target: u8,
     subtarget: u8,
@@ -31,7 +92,7 @@ pub struct UCGScriptedMessageInternal {
 }
 
 impl UCGMessage for UCGScriptedMessageInternal {
-    fn parse_asm_line(line: &String, print_comments: bool) -> Result<Box<dyn UCGMessage>, String> {
+    fn parse_asm_line(line: &str, print_comments: bool) -> Result<Box<dyn UCGMessage>, String> {
         /* The first token in the string should be the timestamp, with the rest of them being
            the message that we should pass to UCGMessageInternal.parse_asm_line().
            To have this be simple to do, split the entire string, take the first token, and
@@ -39,34 +100,50 @@ impl UCGMessage for UCGScriptedMessageInternal {
         */
         let rel; 
         let ts: u32;
-        let mut my_line = line.clone();
+        let mut my_line = line.to_string();
         my_line.make_ascii_uppercase();
         let mut tokens: Vec<&str> = my_line.split_whitespace().collect();
+        // Blank lines and comments have no timestamp; let the message parser report them the usual way
+        if tokens.is_empty() || tokens[0].starts_with('#') {
+            return UCGMessageInternal::parse_asm_line(line, print_comments);
+        }
         // Parse the first token.  It needs to either begin with a number or a plus sign and then a number.
         let ts_tok = tokens[0];
         if ts_tok.chars().nth(0).unwrap() == '+' {
             // This is an offset timestamp, which is the type we currently support.
             rel = true;
-            ts = match ts_tok.split_at(1).1.parse() {
+            // Older dergas output wrote "+5s", so allow the unit on the end
+            ts = match strip_time_unit(ts_tok.split_at(1).1).parse() {
                 Ok(u) => u,
                 Err(e) => {
                     return Err(format!("Failed to parse relative time offset \"{}\": {}", ts_tok, e));
                 }
             };
-        } else if ts_tok.chars().nth(0).unwrap().is_digit(10) {
-            // This is an absolute timestamp, which we don't yet support.
+        } else if ts_tok.chars().nth(0).unwrap().is_ascii_digit() {
+            // This is an absolute timestamp, in seconds from the start of the script.
             rel = false;
-            // TODO: Fill this in.
-            return Err(format!("Absolute timestamp not supported in this version of rgas: \"{}\"", ts_tok));
+            ts = match strip_time_unit(ts_tok).parse() {
+                Ok(u) => u,
+                Err(e) => {
+                    return Err(format!("Failed to parse absolute time \"{}\": {}", ts_tok, e));
+                }
+            };
         } else {
             return Err(format!("Not a vaild timestamp: \"{}\".  Did you mean to use immediate mode?", ts_tok));
         };
+        // The top bit of the timestamp is the relative flag, so only 31 bits are usable
+        if ts > MAX_TIMESTAMP {
+            return Err(format!("Timestamp \"{}\" does not fit in 31 bits.", ts_tok));
+        }
         // Re-assemble the other strings back into one string
-        tokens.remove(0);
+        let ts_tok = tokens.remove(0);
+        if tokens.is_empty() || tokens[0].starts_with('#') {
+            return Err(format!("Timestamp \"{}\" is not followed by a message.", ts_tok));
+        }
         let mut asm_string = String::new();
         for tok in &tokens {
-            asm_string.push_str(*tok);
-            asm_string.push_str(" ");
+            asm_string.push_str(tok);
+            asm_string.push(' ');
         }
         let asm = match UCGMessageInternal::parse_asm_line(&String::from(asm_string.trim_end()), print_comments) {
             Ok(m) => m,
@@ -99,6 +176,9 @@ impl UCGMessage for UCGScriptedMessageInternal {
 
     fn from_byte_vec(b: &mut Vec<u8>) -> Option<Box<dyn UCGMessage>> {
         // Take the first 4 bytes off of the front, since they should be the timestamp.
+        if b.len() < 4 {
+            return None;
+        }
         let mut msg: Vec<u8> = b.split_off(4);
         // Now b contains the timestamp and msg contains the message
         let mut ts: u32 = u32::from_le_bytes(b.as_slice().try_into().unwrap());
@@ -118,9 +198,9 @@ impl UCGMessage for UCGScriptedMessageInternal {
 
     fn into_asm(&self, print_decimal_data: bool) -> String {
         let mut base_string: String = if self.rel {
-            format!{"+{}s ", self.ts}
+            format!{"+{} ", self.ts}
         } else {
-            format!{"ABSOLUTE "}
+            format!{"{} ", self.ts}
         };
         // Append the other string onto this one
         let asm_string = self.msg.into_asm(print_decimal_data);
@@ -158,8 +238,8 @@ impl UCGMessage for UCGMessageInternal{
         let mut len: u16 = b[3] as u16;
         // Combine length variables into one
         len += (lrlen as u16) << 8;
-        // Check to make sure the op isn't too big or something and return
-        if op > maps::MAX_OPCODE {
+        // Check to make sure the op isn't too big and the payload is all there, and return
+        if op > maps::MAX_OPCODE || data.len() != len as usize {
             None
         } else {
             Some(Box::new(Self {
@@ -178,7 +258,7 @@ impl UCGMessage for UCGMessageInternal{
         let mut result = Vec::new();
         result.push(into_address_byte(&self.target, &self.subtarget));
         result.push(into_address_byte(&self.source, &self.subsource));
-        let lrlen: u8 = (self.len & 0xFF00) as u8; // Get the upper 3 bits of len
+        let lrlen: u8 = (self.len >> 8) as u8; // Get the upper 3 bits of len
         result.push(into_address_byte(&self.op, &lrlen));
         result.push(self.len as u8);
         result.append(&mut self.data.clone());
@@ -195,41 +275,38 @@ impl UCGMessage for UCGMessageInternal{
                             self.len);
         // We should format the data nicely to make it easier to read
         // If there is data at all, the first one is likely a register or subroutine number
-        // so we should split it. If len is even after that, chunk them into 2-byte hex values,
-        // otherwise print them out as single bytes. 
-        if self.len == 0 {
-            ()
-        } else {
-            // Do first argument
-            result = format!("{} {:02X}", result, self.data[0]);
-            // Judge if even or odd
-            if (self.len - 1) % 2 == 0 {
-                // Even, so print in groups of 2 bytes, little-endian
-                for i in 0..(self.data.len()-1)/2 {
-                    let twobyte: u16 = (self.data[(2*i + 1) as usize] as u16) + ((self.data[(2*i + 2) as usize] as u16) << 8);
-                    if print_decimal_data {
-                        result = format!("{} D{}", result, twobyte);
+        // so we should split it. If the rest is an even number of bytes, chunk them into 2-byte
+        // values, otherwise print them out as single bytes.
+        // Everything printed here has to assemble back to the same bytes, and the assembler sizes
+        // integers by value.  So a pair only becomes a word if its high byte is set, and hex
+        // starting with C, D or F gets a leading 0 so it isn't read as a string, decimal or float.
+        if let Some((first, rest)) = self.data.split_first() {
+            result = format!("{} {}", result, hex_token(*first as u16, 2));
+            let mut values: Vec<(u16, usize)> = Vec::new();
+            if rest.len() % 2 == 0 {
+                for pair in rest.chunks(2) {
+                    if pair[1] == 0 {
+                        values.push((pair[0] as u16, 2));
+                        values.push((0, 2));
                     } else {
-                        result = format!("{} {:04X}", result, twobyte);
+                        values.push((u16::from_le_bytes([pair[0], pair[1]]), 4));
                     }
                 }
             } else {
-                // Odd, so print one at a time.  No fancy grouping. 
-                for i in 1..self.data.len() {
-                    if print_decimal_data {
-                        result = format!("{} D{}", result, self.data[i]);
-                    } else {
-                        result = format!("{} {:02X}", result, self.data[i]);
-                    }
+                values.extend(rest.iter().map(|b| (*b as u16, 2)));
+            }
+            for (value, width) in values {
+                if print_decimal_data {
+                    result = format!("{} D{}", result, value);
+                } else {
+                    result = format!("{} {}", result, hex_token(value, width));
                 }
             }
         }
-        // Append a newline to result
-        format!("{}{}", result, '\n');
         result
     } 
     
-    fn parse_asm_line(line: &String, print_comments: bool) -> Result<Box<dyn UCGMessage>, String> {
+    fn parse_asm_line(line: &str, print_comments: bool) -> Result<Box<dyn UCGMessage>, String> {
         let mut result: Self = Self {
             target: 0,
             subtarget: 0,
@@ -239,11 +316,15 @@ impl UCGMessage for UCGMessageInternal{
             len: 0,
             data: Vec::new(),
         };
-        let mut my_line = line.clone();
+        let mut my_line = line.to_string();
         // Uppercase the whole line to make parsing more uniform
         my_line.make_ascii_uppercase();
         // Get all of the tokens from the line
-        let tokens: Vec<&str> = my_line.split_whitespace().collect();
+        let mut tokens: Vec<&str> = my_line.split_whitespace().collect();
+        // Blank lines carry nothing, so treat them the same as a comment
+        if tokens.is_empty() {
+            return Err(String::from(""));
+        }
         // Begin parsing the tokens: first token should be either a comment (begins with #) or the target address
         if tokens[0].chars().nth(0) == Some('#') {
             // This is a comment
@@ -253,6 +334,13 @@ impl UCGMessage for UCGMessageInternal{
                 return Err(String::from(""));
             }
         }
+        // Anything after a token beginning with # is a trailing comment, so drop it
+        if let Some(pos) = tokens.iter().position(|t| t.starts_with('#')) {
+            tokens.truncate(pos);
+        }
+        if tokens.len() < 4 {
+            return Err(format!("Incomplete message: expected target, source, opcode and length, got \"{}\".", tokens.join(" ")));
+        }
         if tokens[0].len() < 3  || tokens[0].len() > 4 || !tokens[0].contains('/') {
             // This first token isn't valid. 
             return Err(format!("Invalid target address syntax: \"{}\".", tokens[0]));
@@ -285,8 +373,8 @@ impl UCGMessage for UCGMessageInternal{
         }
         // Fourth should be the length.  This one's not too bad, we just have to make sure it's valid. 
         // Length field should always be written in decimal. 
-        if let Ok(len) = u16::from_str_radix(tokens[3], 10) {
-            if len < 0x07FF {
+        if let Ok(len) = tokens[3].parse::<u16>() {
+            if len <= 0x07FF {
                 result.len = len;
             } else {
                 return Err(format!("Payload length {} too large.", len));
@@ -301,16 +389,16 @@ impl UCGMessage for UCGMessageInternal{
         // L: double
         // C: character string (until the next space)
         // other: hexadecimal argument
-        for i in 4..tokens.len() {
-            if let Some(first) = tokens[i].chars().nth(0) {
+        for (i, tok) in tokens.iter().enumerate().skip(4) {
+            if let Some(first) = tok.chars().nth(0) {
                 match first {
                     'D' => {
                         // read this into an i128, then downsize depending on size
-                        let just_num = tokens[i].trim_start_matches('D');
-                        let num_big = match i128::from_str_radix(just_num, 10) {
+                        let just_num = tok.trim_start_matches('D');
+                        let num_big = match just_num.parse::<i128>() {
                             Ok(num) => num,
                             Err(_) => {
-                                return Err(format!("Malformed decimal data argument: \"{}\"", tokens[i]));
+                                return Err(format!("Malformed decimal data argument: \"{}\"", tok));
                             }
                         };
                         let num_bytes = determine_integer_size(num_big);
@@ -319,22 +407,22 @@ impl UCGMessage for UCGMessageInternal{
                     },
                     'F' => {
                         // Fortunately we know how big a float is.
-                        let just_num = tokens[i].trim_start_matches('F');
+                        let just_num = tok.trim_start_matches('F');
                         let num_float = match just_num.parse::<f32>() {
                             Ok(num) => num,
                             Err(_) => {
-                                return Err(format!("Malformed floating-point data argument: \"{}\"", tokens[i]));
+                                return Err(format!("Malformed floating-point data argument: \"{}\"", tok));
                             }
                         };
                         result.data.extend_from_slice(&num_float.to_le_bytes());
                     },
                     'L' => {
                         // Fortunately we know how big a double is.
-                        let just_num = tokens[i].trim_start_matches('L');
+                        let just_num = tok.trim_start_matches('L');
                         let num_float = match just_num.parse::<f64>() {
                             Ok(num) => num,
                             Err(_) => {
-                                return Err(format!("Malformed double-precision data argument: \"{}\"", tokens[i]));
+                                return Err(format!("Malformed double-precision data argument: \"{}\"", tok));
                             }
                         };
                         result.data.extend_from_slice(&num_float.to_le_bytes());
@@ -342,19 +430,19 @@ impl UCGMessage for UCGMessageInternal{
                     'C' => {
                         // We also know how big the character string is (probably)
                         // TODO: Fix this so strings can start with C. 
-                        let just_string = tokens[i].trim_start_matches('C');
+                        let just_string = tok.trim_start_matches('C');
                         result.data.extend_from_slice(just_string.as_bytes());
                     },
                     _ => {
                         // Interpret this as a hex integer
                         // If it's too long to be a u128, error.  This is 32 hex characters
-                        if tokens[i].len() > 32 {
-                            return Err(format!("Integer argument too large for rgas: \"{}\"", tokens[i]));
+                        if tok.len() > 32 {
+                            return Err(format!("Integer argument too large for rgas: \"{}\"", tok));
                         }
-                        let num_big = match u128::from_str_radix(tokens[i], 16) {
+                        let num_big = match u128::from_str_radix(tok, 16) {
                             Ok(num) => num,
                             Err(_) => {
-                                return Err(format!("Malformed hexadecimal data argument: \"{}\"", tokens[i]));
+                                return Err(format!("Malformed hexadecimal data argument: \"{}\"", tok));
                             }
                         };
                         let num_bytes = determine_integer_size(num_big as i128);
@@ -383,6 +471,18 @@ impl UCGMessage for UCGMessageInternal{
 }
 
 impl UCGMessageInternal {
+    pub fn target(&self) -> (u8, u8) {
+        (self.target, self.subtarget)
+    }
+    pub fn source(&self) -> (u8, u8) {
+        (self.source, self.subsource)
+    }
+    pub fn op(&self) -> u8 {
+        self.op
+    }
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
     pub fn op_to_text(&self) -> String {
         String::from(maps::NUM_TO_OPCODE[self.op as usize])
     }
@@ -397,6 +497,33 @@ impl UCGMessageInternal {
     }
 }
 
+// Gets at the addressed message itself, whether it was parsed in immediate or scripted mode.
+pub fn immediate_part(m: &dyn UCGMessage) -> Option<&UCGMessageInternal> {
+    if let Some(s) = m.as_any().downcast_ref::<UCGScriptedMessageInternal>() {
+        s.msg.as_any().downcast_ref::<UCGMessageInternal>()
+    } else {
+        m.as_any().downcast_ref::<UCGMessageInternal>()
+    }
+}
+
+// Splits a source line into the statement and its trailing comment, if it has one.
+// A comment starts at the first whitespace-separated token beginning with #.
+pub fn split_comment(line: &str) -> (&str, Option<&str>) {
+    let mut prev_space = true;
+    for (i, c) in line.char_indices() {
+        if c == '#' && prev_space {
+            return (&line[..i], Some(&line[i + 1..]));
+        }
+        prev_space = c.is_whitespace();
+    }
+    (line, None)
+}
+
+// Drops the seconds unit from a timestamp token, as older dergas output wrote "+5s".
+pub fn strip_time_unit(ts: &str) -> &str {
+    ts.strip_suffix(['S', 's']).unwrap_or(ts)
+}
+
 fn split_address_byte(b: &u8) -> (u8, u8) {
     let main = (b & 0b11111000) >> 3;
     let sub = b & 0b00000111;
@@ -432,31 +559,41 @@ fn address_byte_from_string(s: String) -> Option<(u8, u8)> {
     }
 }
 
+// A hex data argument that the assembler will read back as the same value.
+fn hex_token(value: u16, width: usize) -> String {
+    let digits = format!("{:0width$X}", value, width = width);
+    if digits.starts_with(['C', 'D', 'F']) {
+        format!("0{}", digits)
+    } else {
+        digits
+    }
+}
+
 fn determine_integer_size(a: i128) -> usize {
     if a < 0 {
         // Do signed comparisons
-        if a < i8::MAX as i128 && a > i8::MIN as i128 {
-            return 1;
-        } else if a < i16::MAX as i128 && a > i16::MIN as i128 {
-            return 2;
-        } else if a < i32::MAX as i128 && a > i32::MIN as i128 {
-            return 4;
+        if a >= i8::MIN as i128 {
+            1
+        } else if a >= i16::MIN as i128 {
+            2
+        } else if a >= i32::MIN as i128 {
+            4
         } else {
-            return 8;
+            8
         }
     } else {
         // Do unsigned comparisons
         let b: u128 = a as u128;
-        if b < u8::MAX as u128 {
-            return 1;
-        } else if b < u16::MAX as u128 {
-            return 2;
-        } else if b < u32::MAX as u128 {
-            return 4;
+        if b <= u8::MAX as u128 {
+            1
+        } else if b <= u16::MAX as u128 {
+            2
+        } else if b <= u32::MAX as u128 {
+            4
         } else {
-            return 8;
+            8
         }
-    };
+    }
 }
 
 #[cfg(test)]
@@ -478,7 +615,7 @@ mod tests {
         a.data = vec![1, 0x00, 0xFF];
         a.len = 3;
         let result = a.into_asm(false);
-        assert_eq!(result, "03/4 1F/7 RQRY 003 01 FF00");
+        assert_eq!(result, "03/4 1F/7 RQRY 003 01 0FF00");
         a.data = vec![1, 0x39, 0x30];
         a.len = 3;
         let result = a.into_asm(true);
@@ -539,6 +676,57 @@ mod tests {
         }
     }
 
+    #[test]
+    fn struct_from_assembly_trailing_comment() {
+        let test_str = "+5 03/4 1F/7 RQRY 001 01 # read the gyro";
+        let m = UCGScriptedMessageInternal::parse_asm_line(test_str, false).unwrap();
+        assert_eq!(m.into_byte_vec(), vec![0x05, 0x00, 0x00, 0x80, 0x1C, 0xFF, 0x08, 0x01, 0x01]);
+        assert_eq!(split_comment(test_str), ("+5 03/4 1F/7 RQRY 001 01 ", Some(" read the gyro")));
+        assert_eq!(split_comment("03/4 1F/7 NOP 000"), ("03/4 1F/7 NOP 000", None));
+        // Blank lines are skipped like comments rather than rejected
+        match UCGScriptedMessageInternal::parse_asm_line("   ", false) {
+            Err(e) => assert_eq!(e, ""),
+            Ok(_) => panic!("blank line parsed as a message"),
+        }
+    }
+
+    #[test]
+    fn assembly_round_trip() {
+        // Zero high bytes, hex that looks like a prefix, 0xFF and an absolute timestamp
+        let bytes = vec![0x2C, 0x01, 0x00, 0x00, 0x1C, 0xFF, 0x28, 0x07,
+                         0x01, 0x05, 0x00, 0xD0, 0xFF, 0xFF, 0x00];
+        let m = UCGScriptedMessageInternal::from_byte_vec(&mut bytes.clone()).unwrap();
+        for decimal in [false, true] {
+            let asm = m.into_asm(decimal);
+            let again = UCGScriptedMessageInternal::parse_asm_line(&asm, false).unwrap();
+            assert_eq!(again.into_byte_vec(), bytes, "{}", asm);
+        }
+        assert_eq!(m.into_asm(false), "300 03/4 1F/7 RVAL 007 01 05 00 0FFD0 0FF 00");
+    }
+
+    #[test]
+    fn binary_round_trip_long_payload() {
+        // 300 bytes puts 1 in the upper length bits, which share a byte with the opcode
+        let mut m = UCGMessageInternal {
+            target: 3,
+            subtarget: 4,
+            source: 0x1f,
+            subsource: 7,
+            op: 5,
+            len: 300,
+            data: (0..300).map(|i| i as u8).collect(),
+        };
+        let bytes = m.into_byte_vec();
+        assert_eq!(bytes[..4], [0x1C, 0xFF, 0x29, 0x2C]);
+        let again = UCGMessageInternal::from_byte_vec(&mut bytes.clone()).unwrap();
+        assert_eq!(again.into_byte_vec(), bytes);
+        m.len = 0x7FF;
+        m.data = vec![0xAA; 0x7FF];
+        let bytes = m.into_byte_vec();
+        assert_eq!(bytes[2..4], [0x2F, 0xFF]);
+        assert!(UCGMessageInternal::from_byte_vec(&mut bytes.clone()).is_some());
+    }
+
     #[test]
     fn struct_from_binary_vector_basic() {
         let mut test_vec = vec![0x1C, 0xFF, 0x08, 0x01, 0x01];
@@ -0,0 +1,224 @@
+// mod lint
+// Semantic checks for assembly sources.  The parser only cares whether a line is well formed;
+// these rules look for well-formed lines that are probably mistakes.
+//
+// A rule can be silenced for one message with an allow comment, either trailing the message
+// or on the comment lines directly above it:
+//
+//   # rgas-lint: allow(self-addressed)
+//   03/4 03/4 RQRY 001 01   # rgas-lint: allow(self-addressed, missing-argument)
+
+use std::collections::HashMap;
+use crate::symbols::SymbolTable;
+use crate::{maps, split_comment, immediate_part, Severity, UCGMessage, UCGMessageInternal, UCGScriptedMessageInternal};
+
+pub struct Rule {
+    pub id: &'static str,
+    pub severity: Severity,
+    pub description: &'static str,
+}
+
+pub static RULES: &[Rule] = &[
+    Rule {
+        id: "parse-error",
+        severity: Severity::Error,
+        description: "The line could not be assembled.",
+    },
+    Rule {
+        id: "response-opcode",
+        severity: Severity::Error,
+        description: "A response opcode (RVAL, OPOK, FAIL, MACK, REDY, ...) is sent from the ground to a device.",
+    },
+    Rule {
+        id: "self-addressed",
+        severity: Severity::Error,
+        description: "The message's source address is the same as its target.",
+    },
+    Rule {
+        id: "address-range",
+        severity: Severity::Error,
+        description: "An address is outside 00/0 to 1F/7 and would be silently truncated.",
+    },
+    Rule {
+        id: "payload-length",
+        severity: Severity::Error,
+        description: "The data supplied is shorter than the declared payload length.",
+    },
+    Rule {
+        id: "missing-argument",
+        severity: Severity::Warning,
+        description: "A register or subroutine command has no register or subroutine number.",
+    },
+    Rule {
+        id: "rwrt-no-value",
+        severity: Severity::Error,
+        description: "RWRT names a register but carries no value to write.",
+    },
+];
+
+// Opcodes whose first data byte is the register or subroutine they act on.
+static ARGUMENT_OPCODES: [&str; 5] = ["RQRY", "RTYP", "RWRT", "SRUN", "SQST"];
+
+const ALLOW_MARKER: &str = "rgas-lint:";
+
+pub struct LintDiagnostic {
+    pub line: usize,
+    pub rule: &'static str,
+    pub severity: Severity,
+    pub message: String,
+}
+
+#[derive(Default)]
+pub struct LintOptions {
+    pub immediate: bool,
+    pub symbols: SymbolTable,
+    // Overrides the default severity of a rule; None turns the rule off.
+    pub levels: HashMap<String, Option<Severity>>,
+}
+
+impl LintOptions {
+    fn level(&self, rule: &Rule) -> Option<Severity> {
+        match self.levels.get(rule.id) {
+            Some(level) => *level,
+            None => Some(rule.severity),
+        }
+    }
+}
+
+pub fn find_rule(id: &str) -> Option<&'static Rule> {
+    RULES.iter().find(|r| r.id == id)
+}
+
+// Rules named in an allow comment, e.g. "rgas-lint: allow(a, b)".
+fn allowed_rules(comment: &str) -> Vec<String> {
+    let comment = comment.trim();
+    if !comment.starts_with(ALLOW_MARKER) {
+        return Vec::new();
+    }
+    let rest = comment[ALLOW_MARKER.len()..].trim();
+    if !rest.starts_with("allow(") || !rest.ends_with(')') {
+        return Vec::new();
+    }
+    rest["allow(".len()..rest.len() - 1]
+        .split(',')
+        .map(|r| r.trim().to_string())
+        .filter(|r| !r.is_empty())
+        .collect()
+}
+
+// Runs every rule over one parsed message, returning (rule id, message) pairs.
+pub fn check_message(msg: &UCGMessageInternal) -> Vec<(&'static str, String)> {
+    let mut found = Vec::new();
+    let op = msg.op_to_text();
+    if maps::RESPONSE_OPCODES.contains(&op.as_str()) {
+        found.push(("response-opcode", format!("{} is a response opcode and should not be sent to a device.", op)));
+    }
+    if msg.target == msg.source && msg.subtarget == msg.subsource {
+        found.push(("self-addressed", format!("message is addressed from {:02X}/{:X} to itself.", msg.source, msg.subsource)));
+    }
+    for (name, main, sub) in [("target", msg.target, msg.subtarget), ("source", msg.source, msg.subsource)] {
+        if main > 0x1F || sub > 7 {
+            found.push(("address-range", format!("{} address {:X}/{:X} is out of range (maximum 1F/7).", name, main, sub)));
+        }
+    }
+    if msg.data.len() < msg.len as usize {
+        found.push(("payload-length", format!("payload length is {} but only {} bytes of data are given.", msg.len, msg.data.len())));
+    }
+    if ARGUMENT_OPCODES.contains(&op.as_str()) && msg.data.is_empty() {
+        found.push(("missing-argument", format!("{} has no register or subroutine number.", op)));
+    }
+    if op == "RWRT" && msg.data.len() < 2 {
+        found.push(("rwrt-no-value", String::from("RWRT has no value to write.")));
+    }
+    found
+}
+
+pub fn lint_source(src: &str, opts: &LintOptions) -> Vec<LintDiagnostic> {
+    let mut diags = Vec::new();
+    let mut allowed: Vec<String> = Vec::new();
+    for (i, line) in src.lines().enumerate() {
+        let lineno = i + 1;
+        let (code, comment) = split_comment(line);
+        if code.trim().is_empty() {
+            // Comment-only lines can carry an allow for the next message; blank lines end it.
+            match comment {
+                Some(c) => allowed.extend(allowed_rules(c)),
+                None => allowed.clear(),
+            }
+            continue;
+        }
+        if let Some(c) = comment {
+            allowed.extend(allowed_rules(c));
+        }
+        let line = opts.symbols.expand_line(line, !opts.immediate);
+        let parsed = if opts.immediate {
+            UCGMessageInternal::parse_asm_line(&line, false)
+        } else {
+            UCGScriptedMessageInternal::parse_asm_line(&line, false)
+        };
+        let found = match parsed {
+            Ok(m) => check_message(immediate_part(m.as_ref()).unwrap()),
+            Err(e) => vec![("parse-error", e)],
+        };
+        for (id, message) in found {
+            let rule = find_rule(id).unwrap();
+            if allowed.iter().any(|a| a == id) {
+                continue;
+            }
+            if let Some(severity) = opts.level(rule) {
+                diags.push(LintDiagnostic { line: lineno, rule: rule.id, severity, message });
+            }
+        }
+        allowed.clear();
+    }
+    diags
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lint::*;
+
+    fn rules_hit(src: &str, opts: &LintOptions) -> Vec<(usize, &'static str)> {
+        lint_source(src, opts).iter().map(|d| (d.line, d.rule)).collect()
+    }
+
+    #[test]
+    fn lint_rules() {
+        let src = "+1 03/4 1F/7 RQRY 001 01\n\
+                   +1 03/4 1F/7 OPOK 000\n\
+                   +1 03/4 03/4 NOP 000\n\
+                   +1 03/4 1F/7 RWRT 001 05\n\
+                   +1 03/4 1F/7 SRUN 000\n\
+                   +1 03/4 1F/7 RWRT 004 05 01\n\
+                   +1 23/4 1F/7 NOP 000\n\
+                   +1 03/4 1F/7 BOGUS 000\n\
+                   +1 03/4 1F/7 RWRT 000\n";
+        assert_eq!(rules_hit(src, &LintOptions::default()), vec![
+            (2, "response-opcode"),
+            (3, "self-addressed"),
+            (4, "rwrt-no-value"),
+            (5, "missing-argument"),
+            (6, "payload-length"),
+            (7, "address-range"),
+            (8, "parse-error"),
+            (9, "missing-argument"),
+            (9, "rwrt-no-value"),
+        ]);
+    }
+
+    #[test]
+    fn lint_suppression_and_levels() {
+        let src = "# rgas-lint: allow(response-opcode)\n\
+                   +1 03/4 1F/7 OPOK 000\n\
+                   +1 03/4 1F/7 FAIL 000\n\
+                   +1 03/4 03/4 MACK 000 # rgas-lint: allow(self-addressed)\n";
+        assert_eq!(rules_hit(src, &LintOptions::default()), vec![(3, "response-opcode"), (4, "response-opcode")]);
+
+        let mut opts = LintOptions::default();
+        opts.levels.insert(String::from("response-opcode"), Some(Severity::Warning));
+        opts.levels.insert(String::from("self-addressed"), None);
+        let diags = lint_source("03/4 03/4 REDY 000", &LintOptions { immediate: true, ..opts });
+        assert_eq!(diags.len(), 1);
+        assert_eq!(diags[0].severity, Severity::Warning);
+    }
+}
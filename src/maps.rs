@@ -27,7 +27,7 @@ pub static OPCODE_TO_NUM: Map<&'static str, u8> = phf_map! {
 
 pub static MAX_OPCODE: u8 = 18;
 
-pub static NUM_TO_OPCODE: [&'static str; 19] = [
+pub static NUM_TO_OPCODE: [&str; 19] = [
     "NOP",
     "RQRY",
     "SQST",
@@ -47,4 +47,19 @@ pub static NUM_TO_OPCODE: [&'static str; 19] = [
     "DERR",
     "DDIE",
     "REDY",
-];
\ No newline at end of file
+];
+
+// Opcodes a device sends back to the ground, as opposed to commands the ground sends out.
+pub static RESPONSE_OPCODES: [&str; 11] = [
+    "SVAL",
+    "RVAL",
+    "STAT",
+    "SRET",
+    "MACK",
+    "OPOK",
+    "FAIL",
+    "NSUP",
+    "DERR",
+    "DDIE",
+    "REDY",
+];
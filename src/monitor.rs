@@ -0,0 +1,192 @@
+// mod monitor
+// One-line summaries of live traffic for rgas monitor:
+//
+//   [    1.204s] > GROUND → 03/4  RWRT  r01 = 09 00 (9)
+//   [    1.209s] < 03/4 → GROUND  OPOK  01
+//
+// Commands are marked > as sent by the ground, and responses < as coming back from a device,
+// going by the opcode rather than by which way the bytes travelled, since a monitor tapping a
+// link or reading a dump can't tell.  The first payload byte is shown as the register (r) or
+// subroutine (s) number where the opcode has one, followed by its value in hex and, when it
+// fits a whole integer, in decimal.  Other payloads are shown in hex.  Bytes that aren't a
+// message are reported with a !.
+
+use std::time::Duration;
+use crate::capture::Direction;
+use crate::filter::Filter;
+use crate::framing::{FrameBuffer, Framing};
+use crate::symbols::SymbolTable;
+use crate::{immediate_part, maps, UCGMessage, UCGMessageInternal};
+
+const RESET: &str = "\x1b[0m";
+const DIM: &str = "\x1b[2m";
+const CYAN: &str = "\x1b[36m";
+const GREEN: &str = "\x1b[32m";
+const RED: &str = "\x1b[31m";
+const YELLOW: &str = "\x1b[33m";
+
+const SUBROUTINE_STATES: [&str; 4] = ["idle", "running", "finished", "stopped"];
+
+pub fn direction(op: &str) -> Direction {
+    if maps::RESPONSE_OPCODES.contains(&op) {
+        Direction::Received
+    } else {
+        Direction::Sent
+    }
+}
+
+// The payload as the summary shows it.
+pub fn describe_payload(msg: &UCGMessageInternal) -> String {
+    let op = msg.op_to_text();
+    let prefix = match op.as_str() {
+        "RQRY" | "RTYP" | "RVAL" | "RWRT" => "r",
+        "SQST" | "SVAL" | "SRUN" | "STAT" | "STOP" | "SRET" => "s",
+        _ => "",
+    };
+    let (first, rest) = match (prefix, msg.data.split_first()) {
+        ("", _) | (_, None) => (None, &msg.data[..]),
+        (_, Some((first, rest))) => (Some(*first), rest),
+    };
+    let mut parts = Vec::new();
+    if let Some(n) = first {
+        parts.push(format!("{}{:02X}", prefix, n));
+    }
+    if op == "STAT" && rest.len() == 1 {
+        parts.push(SUBROUTINE_STATES.get(rest[0] as usize).map_or_else(|| format!("state {}", rest[0]), |s| s.to_string()));
+    } else if !rest.is_empty() {
+        if first.is_some() {
+            parts.push(String::from("="));
+        }
+        parts.extend(rest.iter().map(|b| format!("{:02X}", b)));
+        if first.is_some() && rest.len() <= 8 {
+            let value = rest.iter().rev().fold(0u64, |v, &b| v << 8 | b as u64);
+            parts.push(format!("({})", value));
+        }
+    }
+    parts.join(" ")
+}
+
+fn address(symbols: &SymbolTable, (main, sub): (u8, u8)) -> String {
+    symbols.name(main, sub).map_or_else(|| format!("{:02X}/{:X}", main, sub), String::from)
+}
+
+fn paint(text: &str, colour: &str, on: bool) -> String {
+    if on {
+        format!("{}{}{}", colour, text, RESET)
+    } else {
+        text.to_string()
+    }
+}
+
+pub struct Monitor<'a> {
+    pub filter: Filter,
+    pub symbols: &'a SymbolTable,
+    pub colour: bool,
+    frames: FrameBuffer,
+}
+
+impl<'a> Monitor<'a> {
+    pub fn new(framing: Framing, symbols: &'a SymbolTable) -> Monitor<'a> {
+        Monitor { filter: Filter::new(), symbols, colour: false, frames: FrameBuffer::new(framing, false) }
+    }
+
+    pub fn summary(&self, msg: &UCGMessageInternal, time: Duration) -> String {
+        let op = msg.op_to_text();
+        let dir = direction(&op);
+        let op_colour = match op.as_str() {
+            "FAIL" | "NSUP" | "DERR" | "DDIE" => RED,
+            _ if dir == Direction::Received => GREEN,
+            _ => CYAN,
+        };
+        format!("{} {} {} → {}  {}  {}",
+                paint(&format!("[{:>9.3}s]", time.as_secs_f64()), DIM, self.colour),
+                dir.arrow(),
+                address(self.symbols, msg.source()),
+                address(self.symbols, msg.target()),
+                paint(&format!("{:<4}", op), op_colour, self.colour),
+                describe_payload(msg)).trim_end().to_string()
+    }
+
+    pub fn notice(&self, text: &str, time: Duration) -> String {
+        format!("{} {}",
+                paint(&format!("[{:>9.3}s]", time.as_secs_f64()), DIM, self.colour),
+                paint(&format!("! {}", text), YELLOW, self.colour))
+    }
+
+    // Take bytes as they arrive, and return a line for each message they complete that passes
+    // the filter, and for anything thrown away.
+    pub fn feed(&mut self, bytes: &[u8], time: Duration) -> Vec<String> {
+        self.frames.push(bytes);
+        let mut lines = Vec::new();
+        while let Some(frame) = self.frames.next_frame() {
+            let mut frame = match frame {
+                Ok(f) => f,
+                Err(e) => {
+                    lines.push(self.notice(&e, time));
+                    continue;
+                }
+            };
+            let len = frame.len();
+            let msg = UCGMessageInternal::from_byte_vec(&mut frame);
+            match msg.as_deref().and_then(immediate_part) {
+                Some(m) if self.filter.matches(m, None) => lines.push(self.summary(m, time)),
+                Some(_) => (),
+                None => lines.push(self.notice(&format!("{} bytes that aren't a message.", len), time)),
+            }
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::monitor::*;
+
+    #[test]
+    fn monitor_lines() {
+        let mut syms = SymbolTable::new();
+        syms.define("GROUND", 0x1F, 7);
+        let mut m = Monitor::new(Framing::Crlf, &syms);
+        m.filter.add_opcodes("RWRT,OPOK,STAT").unwrap();
+        let t = Duration::from_millis(1204);
+        // A write in two pieces, a query the filter drops, then two replies
+        assert!(m.feed(&[0x1C, 0xFF, 0x30, 0x03, 0x01], t).is_empty());
+        let lines = m.feed(&[0x09, 0x00, b'\r', b'\n', 0x1C, 0xFF, 0x08, 0x01, 0x01, b'\r', b'\n'], t);
+        assert_eq!(lines, vec!["[    1.204s] > GROUND → 03/4  RWRT  r01 = 09 00 (9)"]);
+        let lines = m.feed(&[0xFF, 0x1C, 0x68, 0x01, 0x01, b'\r', b'\n', 0xFF, 0x1C, 0x48, 0x02, 0x02, 0x01, b'\r', b'\n'], t);
+        assert_eq!(lines, vec!["[    1.204s] < 03/4 → GROUND  OPOK  01", "[    1.204s] < 03/4 → GROUND  STAT  s02 running"]);
+
+        m.colour = true;
+        let lines = m.feed(&[0xFF, 0x1C, 0x68, 0x00, b'\r', b'\n'], t);
+        assert_eq!(lines, vec!["\x1b[2m[    1.204s]\x1b[0m < 03/4 → GROUND  \x1b[32mOPOK\x1b[0m"]);
+    }
+
+    #[test]
+    fn monitor_odd_input() {
+        let syms = SymbolTable::new();
+        let mut m = Monitor::new(Framing::Crlf, &syms);
+        let t = Duration::ZERO;
+        // A stray byte before a message, a state the monitor doesn't know, and a message without
+        // its framing
+        let lines = m.feed(&[0x00, 0x1C, 0xFF, 0x08, 0x01, 0x01, b'\r', b'\n', 0xFF, 0x1C, 0x48, 0x02, 0x02, 0x09, b'\r', b'\n'], t);
+        assert_eq!(lines, vec![
+            "[    0.000s] ! Dropped byte 00: not the start of a message.",
+            "[    0.000s] > 1F/7 → 03/4  RQRY  r01",
+            "[    0.000s] < 03/4 → 1F/7  STAT  s02 state 9",
+        ]);
+        let lines = m.feed(&[0x1C, 0xFF, 0x00, 0x00, b'X', b'X'], t);
+        assert_eq!(lines[0], "[    0.000s] ! Dropped byte 1C: no framing where expected.");
+
+        // Too long for a decimal value, and a reply that fails
+        let value = UCGMessageInternal::parse_asm_line("1F/7 03/4 RVAL 010 01 01 02 03 04 05 06 07 08 09", false).unwrap();
+        let value = value.as_any().downcast_ref::<UCGMessageInternal>().unwrap();
+        assert_eq!(describe_payload(value), "r01 = 01 02 03 04 05 06 07 08 09");
+        let fail = UCGMessageInternal::parse_asm_line("1F/7 03/4 FAIL 001 01", false).unwrap();
+        let fail = fail.as_any().downcast_ref::<UCGMessageInternal>().unwrap();
+        assert_eq!(describe_payload(fail), "01");
+        m.colour = true;
+        assert!(m.summary(fail, t).contains("\x1b[31mFAIL\x1b[0m"));
+        assert_eq!(direction("FAIL"), Direction::Received);
+        assert_eq!(direction("SRUN"), Direction::Sent);
+    }
+}
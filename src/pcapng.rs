@@ -0,0 +1,353 @@
+// mod pcapng
+// Captures as pcapng, so they can be browsed in Wireshark and other packet tools.  Each UCG
+// message is one packet, with no framing, on an interface with link type USER0 (147).  The
+// direction goes in the packet's flags: outbound for messages the recording host sent,
+// inbound for ones it received.  Metadata goes in the section header as "key=value" comments.
+//
+// Written files are little-endian with microsecond timestamps.  The reader also takes
+// big-endian files, other timestamp resolutions and blocks it doesn't know, which it skips.
+// Packets on interfaces of any other link type are reported as errors.  Packet times are
+// absolute in pcapng, so a capture read back counts its times from its first packet.
+
+use std::convert::{TryFrom, TryInto};
+use std::io;
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+use crate::capture::{CaptureHeader, CaptureRecord, Direction, RecordSink, CAPTURE_VERSION};
+
+pub const PCAPNG_MAGIC: [u8; 4] = [0x0A, 0x0D, 0x0D, 0x0A];
+pub const LINKTYPE_USER0: u16 = 147;
+
+const SECTION_HEADER: u32 = 0x0A0D0D0A;
+const INTERFACE_DESCRIPTION: u32 = 1;
+const ENHANCED_PACKET: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B3C4D;
+
+const OPT_END: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const IF_NAME: u16 = 2;
+const IF_TSRESOL: u16 = 9;
+const EPB_FLAGS: u16 = 2;
+const FLAG_INBOUND: u32 = 1;
+const FLAG_OUTBOUND: u32 = 2;
+
+// Longest block the reader takes, as libpcap limits it, so a corrupt length can't ask for gigabytes.
+const MAX_BLOCK_LENGTH: usize = 16 * 1024 * 1024;
+// Finest decimal if_tsresol whose divisor still fits a u128.
+const MAX_DECIMAL_TSRESOL: u8 = 38;
+
+pub fn is_pcapng(sample: &[u8]) -> bool {
+    sample.starts_with(&PCAPNG_MAGIC)
+}
+
+// Options and blocks too long for their length fields, or for a reader to accept, are refused
+// rather than written with a length that doesn't match.
+fn option(out: &mut Vec<u8>, code: u16, value: &[u8]) -> io::Result<()> {
+    let len = u16::try_from(value.len()).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput,
+        format!("pcapng option of {} bytes is too long.", value.len())))?;
+    out.extend_from_slice(&code.to_le_bytes());
+    out.extend_from_slice(&len.to_le_bytes());
+    out.extend_from_slice(value);
+    out.resize((out.len() + 3) & !3, 0);
+    Ok(())
+}
+
+fn block(block_type: u32, body: &[u8]) -> io::Result<Vec<u8>> {
+    if 12 + body.len() > MAX_BLOCK_LENGTH {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("pcapng block of {} bytes is too long.", 12 + body.len())));
+    }
+    let len = (12 + body.len() as u32).to_le_bytes();
+    Ok([&block_type.to_le_bytes()[..], &len, body, &len].concat())
+}
+
+pub struct PcapngWriter<W: Write> {
+    out: W,
+    start: u64, // microseconds since the Unix epoch that record times count from
+    started: Instant,
+}
+
+impl<W: Write> PcapngWriter<W> {
+    // Write a section header and one interface for each in the capture header.
+    pub fn new(mut out: W, header: &CaptureHeader) -> io::Result<PcapngWriter<W>> {
+        let mut body = BYTE_ORDER_MAGIC.to_le_bytes().to_vec();
+        body.extend_from_slice(&[1, 0, 0, 0]); // version 1.0
+        body.extend_from_slice(&u64::MAX.to_le_bytes()); // section length not given
+        for (key, value) in &header.metadata {
+            option(&mut body, OPT_COMMENT, format!("{}={}", key, value).as_bytes())?;
+        }
+        option(&mut body, OPT_END, &[])?;
+        out.write_all(&block(SECTION_HEADER, &body)?)?;
+        for name in &header.interfaces {
+            let mut body = LINKTYPE_USER0.to_le_bytes().to_vec();
+            body.extend_from_slice(&[0, 0, 0, 0, 0, 0]); // reserved, then no snap length
+            option(&mut body, IF_NAME, name.as_bytes())?;
+            option(&mut body, IF_TSRESOL, &[6])?;
+            option(&mut body, OPT_END, &[])?;
+            out.write_all(&block(INTERFACE_DESCRIPTION, &body)?)?;
+        }
+        out.flush()?;
+        Ok(PcapngWriter { out, start: header.start, started: Instant::now() })
+    }
+
+    pub fn record_at(&mut self, r: &CaptureRecord) -> io::Result<()> {
+        let time = self.start + r.time.as_micros() as u64;
+        let mut body = (r.interface as u32).to_le_bytes().to_vec();
+        body.extend_from_slice(&((time >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(time as u32).to_le_bytes());
+        body.extend_from_slice(&(r.bytes.len() as u32).to_le_bytes());
+        body.extend_from_slice(&(r.bytes.len() as u32).to_le_bytes());
+        body.extend_from_slice(&r.bytes);
+        body.resize((body.len() + 3) & !3, 0);
+        let flags = if r.direction == Direction::Sent { FLAG_OUTBOUND } else { FLAG_INBOUND };
+        option(&mut body, EPB_FLAGS, &flags.to_le_bytes())?;
+        option(&mut body, OPT_END, &[])?;
+        self.out.write_all(&block(ENHANCED_PACKET, &body)?)?;
+        self.out.flush()
+    }
+}
+
+impl<W: Write> RecordSink for PcapngWriter<W> {
+    fn record(&mut self, direction: Direction, interface: u8, message: &[u8]) -> io::Result<()> {
+        let time = self.started.elapsed();
+        self.record_at(&CaptureRecord { time, direction, interface, bytes: message.to_vec() })
+    }
+}
+
+struct Interface {
+    link_type: u16,
+    tsresol: u8, // as in if_tsresol: 10^-n seconds, or 2^-n if the top bit is set
+}
+
+impl Interface {
+    fn micros(&self, ticks: u64) -> u64 {
+        let ticks = ticks as u128;
+        let n = (self.tsresol & 0x7F) as u32;
+        let micros = if self.tsresol & 0x80 == 0 {
+            ticks * 1_000_000 / 10u128.pow(n)
+        } else {
+            (ticks * 1_000_000) >> n
+        };
+        micros as u64
+    }
+}
+
+pub struct PcapngReader<R: Read> {
+    input: R,
+    big_endian: bool,
+    interfaces: Vec<Interface>,
+    pending: Option<Result<CaptureRecord, String>>, // the first packet, read to find the start
+    pub header: CaptureHeader,
+}
+
+impl<R: Read> PcapngReader<R> {
+    // Read the section header, and the interfaces and first packet after it.
+    pub fn new(input: R) -> Result<PcapngReader<R>, String> {
+        let mut reader = PcapngReader {
+            input,
+            big_endian: false,
+            interfaces: Vec::new(),
+            pending: None,
+            header: CaptureHeader { version: CAPTURE_VERSION, start: 0, metadata: Vec::new(), interfaces: Vec::new() },
+        };
+        match reader.next_block()? {
+            Some((SECTION_HEADER, _)) => (),
+            _ => return Err(String::from("Not a pcapng file (no section header).")),
+        }
+        reader.pending = reader.next_packet(true);
+        Ok(reader)
+    }
+
+    fn u16_at(&self, b: &[u8], at: usize) -> u16 {
+        let b = b[at..at + 2].try_into().unwrap();
+        if self.big_endian { u16::from_be_bytes(b) } else { u16::from_le_bytes(b) }
+    }
+
+    fn u32_at(&self, b: &[u8], at: usize) -> u32 {
+        let b = b[at..at + 4].try_into().unwrap();
+        if self.big_endian { u32::from_be_bytes(b) } else { u32::from_le_bytes(b) }
+    }
+
+    // Options from at to the end of a block body, as (code, value).
+    fn options<'a>(&self, body: &'a [u8], mut at: usize) -> Vec<(u16, &'a [u8])> {
+        let mut options = Vec::new();
+        while at + 4 <= body.len() {
+            let code = self.u16_at(body, at);
+            let len = self.u16_at(body, at + 2) as usize;
+            if code == OPT_END || at + 4 + len > body.len() {
+                break;
+            }
+            options.push((code, &body[at + 4..at + 4 + len]));
+            at += 4 + ((len + 3) & !3);
+        }
+        options
+    }
+
+    // The next block's type and body.  A section header sets the byte order and its comments
+    // become metadata; interface descriptions are taken note of.
+    fn next_block(&mut self) -> Result<Option<(u32, Vec<u8>)>, String> {
+        let mut fixed = [0u8; 12];
+        match self.input.read(&mut fixed[..1]) {
+            Ok(0) => return Ok(None),
+            Ok(_) => (),
+            Err(e) => return Err(format!("Read error: {}", e)),
+        }
+        let truncated = |e: io::Error| format!("pcapng file ends partway through a block: {}", e);
+        self.input.read_exact(&mut fixed[1..8]).map_err(truncated)?;
+        let block_type = self.u32_at(&fixed, 0); // the section header type reads the same either way
+        if block_type == SECTION_HEADER {
+            self.input.read_exact(&mut fixed[8..]).map_err(truncated)?;
+            self.big_endian = match u32::from_le_bytes(fixed[8..].try_into().unwrap()) {
+                BYTE_ORDER_MAGIC => false,
+                m if m.swap_bytes() == BYTE_ORDER_MAGIC => true,
+                _ => return Err(String::from("pcapng section header has a bad byte-order magic.")),
+            };
+            self.interfaces.clear();
+            self.header.interfaces.clear();
+        }
+        let len = self.u32_at(&fixed, 4) as usize;
+        let read = if block_type == SECTION_HEADER { 12 } else { 8 };
+        if len < 12 || len % 4 != 0 || len > MAX_BLOCK_LENGTH {
+            return Err(format!("pcapng block has a bad length of {}.", len));
+        }
+        let mut body = fixed[8..read].to_vec();
+        body.resize(len - 8, 0);
+        self.input.read_exact(&mut body[read - 8..]).map_err(truncated)?;
+        body.truncate(len - 12); // the length again, at the end
+        match block_type {
+            SECTION_HEADER if body.len() >= 16 => {
+                let comments: Vec<String> = self.options(&body, 16).into_iter()
+                    .filter(|(code, _)| *code == OPT_COMMENT)
+                    .map(|(_, v)| String::from_utf8_lossy(v).into_owned())
+                    .collect();
+                self.header.metadata = comments.iter()
+                    .filter_map(|c| c.split_once('='))
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect();
+            }
+            INTERFACE_DESCRIPTION if body.len() >= 8 => {
+                let mut interface = Interface { link_type: self.u16_at(&body, 0), tsresol: 6 };
+                let mut name = format!("if{}", self.interfaces.len());
+                for (code, value) in self.options(&body, 8) {
+                    match code {
+                        IF_NAME => name = String::from_utf8_lossy(value).into_owned(),
+                        IF_TSRESOL if !value.is_empty() => interface.tsresol = value[0],
+                        _ => (),
+                    }
+                }
+                if interface.tsresol & 0x80 == 0 && interface.tsresol > MAX_DECIMAL_TSRESOL {
+                    return Err(format!("pcapng interface {} has an unsupported timestamp resolution of 10^-{} seconds.",
+                                       name, interface.tsresol));
+                }
+                self.interfaces.push(interface);
+                self.header.interfaces.push(name);
+            }
+            _ => (),
+        }
+        Ok(Some((block_type, body)))
+    }
+
+    // Read up to the next packet.  The first one read sets the start of the capture.
+    fn next_packet(&mut self, first: bool) -> Option<Result<CaptureRecord, String>> {
+        let body = loop {
+            match self.next_block() {
+                Ok(Some((ENHANCED_PACKET, body))) => break body,
+                Ok(Some(_)) => (),
+                Ok(None) => return None,
+                Err(e) => return Some(Err(e)),
+            }
+        };
+        if body.len() < 20 {
+            return Some(Err(String::from("pcapng packet block is too short.")));
+        }
+        let index = self.u32_at(&body, 0) as usize;
+        let interface = match self.interfaces.get(index) {
+            Some(i) => i,
+            None => return Some(Err(format!("pcapng packet is on interface {}, which isn't described.", index))),
+        };
+        if interface.link_type != LINKTYPE_USER0 {
+            return Some(Err(format!("pcapng packet on interface {} has link type {}, not UCG ({}).",
+                                    index, interface.link_type, LINKTYPE_USER0)));
+        }
+        let ticks = (self.u32_at(&body, 4) as u64) << 32 | self.u32_at(&body, 8) as u64;
+        let time = interface.micros(ticks);
+        let captured = self.u32_at(&body, 12) as usize;
+        if 20 + captured > body.len() {
+            return Some(Err(String::from("pcapng packet is longer than its block.")));
+        }
+        let flags = self.options(&body, 20 + ((captured + 3) & !3)).into_iter()
+            .find(|(code, v)| *code == EPB_FLAGS && v.len() == 4)
+            .map_or(0, |(_, v)| self.u32_at(v, 0));
+        if first {
+            self.header.start = time;
+        }
+        Some(Ok(CaptureRecord {
+            time: Duration::from_micros(time.saturating_sub(self.header.start)),
+            // Packets that don't say are taken as received, as a packet tool would have seen them
+            direction: if flags & 3 == FLAG_OUTBOUND { Direction::Sent } else { Direction::Received },
+            interface: index.min(u8::MAX as usize) as u8,
+            bytes: body[20..20 + captured].to_vec(),
+        }))
+    }
+}
+
+impl<R: Read> Iterator for PcapngReader<R> {
+    type Item = Result<CaptureRecord, String>;
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.pending.take() {
+            Some(r) => Some(r),
+            None => self.next_packet(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pcapng::*;
+
+    #[test]
+    fn pcapng_round_trip() {
+        let mut header = CaptureHeader::new(&["/dev/ttyUSB0", "udp:0.0.0.0:5001"]);
+        header.metadata.push((String::from("creator"), String::from("rgas test")));
+        let records = [
+            CaptureRecord { time: Duration::from_millis(5), direction: Direction::Sent, interface: 0, bytes: vec![0x1C, 0xFF, 0x08, 0x01, 0x01] },
+            CaptureRecord { time: Duration::from_millis(12), direction: Direction::Received, interface: 1, bytes: vec![0xFF, 0x1C, 0x28, 0x02, 0x01, 0x2A] },
+        ];
+        let mut file = Vec::new();
+        let mut writer = PcapngWriter::new(&mut file, &header).unwrap();
+        for r in &records {
+            writer.record_at(r).unwrap();
+        }
+        assert!(is_pcapng(&file));
+        assert_eq!(file.len() % 4, 0);
+
+        let reader = PcapngReader::new(&file[..]).unwrap();
+        assert_eq!(reader.header.start, header.start + 5000);
+        assert_eq!(reader.header.get("creator"), Some("rgas test"));
+        assert_eq!(reader.header.interface_name(1), "udp:0.0.0.0:5001");
+        let read: Vec<CaptureRecord> = reader.map(|r| r.unwrap()).collect();
+        assert_eq!(read.len(), 2);
+        assert_eq!((read[0].time, read[0].direction, read[0].interface), (Duration::ZERO, Direction::Sent, 0));
+        assert_eq!((read[1].time, read[1].direction, read[1].interface), (Duration::from_millis(7), Direction::Received, 1));
+        assert_eq!(read[1].message().unwrap().into_asm(false), "1F/7 03/4 RVAL 002 01 2A");
+        assert!(PcapngReader::new(&file[..file.len() - 4]).unwrap().last().unwrap().is_err());
+
+        // A timestamp resolution of 10^-39 seconds, which used to overflow working out the time
+        let at = file.windows(5).position(|w| w == [0x09, 0x00, 0x01, 0x00, 0x06]).unwrap();
+        let mut bad = file.clone();
+        bad[at + 4] = 39;
+        let err = PcapngReader::new(&bad[..]).unwrap().next().unwrap().unwrap_err();
+        assert!(err.contains("10^-39"), "{}", err);
+
+        // A section header claiming to be nearly 4 GiB long
+        let mut bad = file.clone();
+        bad[4..8].copy_from_slice(&0xFFFF_FFF0u32.to_le_bytes());
+        assert!(PcapngReader::new(&bad[..]).is_err());
+
+        // Nor are they written
+        let name = "x".repeat(70000);
+        assert!(PcapngWriter::new(Vec::new(), &CaptureHeader::new(&[name.as_str()])).is_err());
+        let big = CaptureRecord { bytes: vec![0; MAX_BLOCK_LENGTH], ..records[0].clone() };
+        let mut writer = PcapngWriter::new(Vec::new(), &header).unwrap();
+        assert_eq!(writer.record_at(&big).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,317 @@
+// mod player
+// Runs a script from the ground: each message's immediate part is sent when its time comes.
+//
+// Times follow timeline::ScriptClock, divided by the time scale, so a scale of 10 plays an
+// hour-long script in six minutes.  Starting part way through skips the earlier messages
+// without waiting for them; the first message played still waits its own delay.  Time spent
+// paused pushes the rest of the script back rather than making it catch up.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::framing::Framing;
+use crate::timeline::ScriptClock;
+use crate::transport::Transport;
+use crate::UCGScriptedMessageInternal;
+
+// How often a waiting player checks whether it has been paused or stopped.
+const TICK: Duration = Duration::from_millis(10);
+
+// Where the player gets the time from, so tests don't have to wait for real.
+pub trait Clock {
+    fn now(&self) -> Duration; // time since some fixed point
+    fn sleep(&self, d: Duration);
+}
+
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> SystemClock {
+        SystemClock { start: Instant::now() }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> SystemClock {
+        SystemClock::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    fn sleep(&self, d: Duration) {
+        thread::sleep(d)
+    }
+}
+
+// Pause, resume or stop a player from another thread.
+#[derive(Clone, Default)]
+pub struct PlayerControl {
+    paused: Arc<AtomicBool>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl PlayerControl {
+    pub fn new() -> PlayerControl {
+        PlayerControl::default()
+    }
+
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+    }
+
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+}
+
+// Waits for points in a schedule measured from when it was made, allowing for time spent
+// paused.  Shared by anything that has to send things at set times.
+pub struct Pacer<'a, C: Clock> {
+    clock: &'a C,
+    control: &'a PlayerControl,
+    started: Duration,
+    paused_for: Duration,
+}
+
+impl<'a, C: Clock> Pacer<'a, C> {
+    pub fn new(clock: &'a C, control: &'a PlayerControl) -> Pacer<'a, C> {
+        Pacer { clock, control, started: clock.now(), paused_for: Duration::ZERO }
+    }
+
+    // Wait until offset into the schedule.  Returns false if stopped first.
+    pub fn wait_for(&mut self, offset: Duration) -> bool {
+        loop {
+            if self.control.is_stopped() {
+                return false;
+            }
+            if self.control.is_paused() {
+                let before = self.clock.now();
+                self.clock.sleep(TICK);
+                self.paused_for += self.clock.now() - before;
+                continue;
+            }
+            let elapsed = self.elapsed() - self.paused_for;
+            if elapsed >= offset {
+                return true;
+            }
+            self.clock.sleep((offset - elapsed).min(TICK));
+        }
+    }
+
+    // When something at offset should go, pauses included.
+    pub fn due(&self, offset: Duration) -> Duration {
+        offset + self.paused_for
+    }
+
+    // Time since the schedule started, pauses included.
+    pub fn elapsed(&self) -> Duration {
+        self.clock.now() - self.started
+    }
+}
+
+pub struct PlayerOptions {
+    pub dry_run: bool,      // go through the timing but send nothing
+    pub time_scale: f64,    // how many times faster than real time to play
+    pub start_index: usize, // first message to play
+    pub framing: Framing,
+}
+
+impl Default for PlayerOptions {
+    fn default() -> PlayerOptions {
+        PlayerOptions { dry_run: false, time_scale: 1.0, start_index: 0, framing: Framing::default() }
+    }
+}
+
+pub struct SendRecord {
+    pub index: usize,       // position of the message in the script
+    pub script_time: u64,   // when the script says it runs, in seconds
+    pub due: Duration,      // when it should have gone, from the start of play, allowing for pauses
+    pub sent: Duration,     // when it actually went
+    pub bytes: Vec<u8>,     // what was (or in a dry run, would have been) sent
+}
+
+pub struct Player<C: Clock> {
+    pub opts: PlayerOptions,
+    pub control: PlayerControl,
+    clock: C,
+}
+
+impl<C: Clock> Player<C> {
+    pub fn new(opts: PlayerOptions, clock: C) -> Player<C> {
+        Player { opts, control: PlayerControl::new(), clock }
+    }
+
+    // Play a script, calling on_send after each message goes.  Returns the log of everything
+    // sent, which stops short if the player is stopped.  A transport is only needed when
+    // actually sending.
+    pub fn play<'a, I, F>(&mut self, script: I, mut transport: Option<&mut dyn Transport>, mut on_send: F)
+        -> io::Result<Vec<SendRecord>>
+    where
+        I: IntoIterator<Item = &'a UCGScriptedMessageInternal>,
+        F: FnMut(&SendRecord),
+    {
+        if self.opts.time_scale <= 0.0 || !self.opts.time_scale.is_finite() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Time scale must be a positive number."));
+        }
+        if !self.opts.dry_run && transport.is_none() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Nothing to send to; use a dry run."));
+        }
+        let mut script_clock = ScriptClock::new();
+        let mut base = 0; // script time reached before the first message played
+        let mut pacer = Pacer::new(&self.clock, &self.control);
+        let mut log = Vec::new();
+        for (index, msg) in script.into_iter().enumerate() {
+            if index < self.opts.start_index {
+                base = script_clock.advance(msg.rel, msg.ts);
+                continue;
+            }
+            let script_time = script_clock.advance(msg.rel, msg.ts);
+            let offset = Duration::from_secs_f64((script_time - base) as f64 / self.opts.time_scale);
+            if !pacer.wait_for(offset) {
+                return Ok(log);
+            }
+            let mut bytes = msg.msg.into_byte_vec();
+            bytes.extend_from_slice(self.opts.framing.trailer());
+            if !self.opts.dry_run {
+                if let Some(t) = transport.as_mut() {
+                    t.send(&bytes)?;
+                }
+            }
+            let record = SendRecord {
+                index,
+                script_time,
+                due: pacer.due(offset),
+                sent: pacer.elapsed(),
+                bytes,
+            };
+            on_send(&record);
+            log.push(record);
+        }
+        Ok(log)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::player::*;
+    use crate::transport::Transport;
+    use crate::{UCGMessage, UCGScriptedMessageInternal};
+    use std::cell::Cell;
+
+    // Time passes only when the player sleeps, and the test can pause it at a set time.
+    struct FakeClock {
+        now: Cell<Duration>,
+        pause_at: Duration,
+        resume_at: Duration,
+        control: PlayerControl,
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Duration {
+            self.now.get()
+        }
+
+        fn sleep(&self, d: Duration) {
+            self.now.set(self.now.get() + d);
+            if self.now.get() >= self.pause_at && self.now.get() < self.resume_at {
+                self.control.pause();
+            } else {
+                self.control.resume();
+            }
+        }
+    }
+
+    #[test]
+    fn play_with_scale_pause_and_start() {
+        let script: Vec<Box<dyn UCGMessage>> = ["+5 03/4 1F/7 RQRY 001 01", "+10 03/4 1F/7 RQRY 001 02", "40 03/4 1F/7 NOP 000"]
+            .iter().map(|l| UCGScriptedMessageInternal::parse_asm_line(l, false).unwrap()).collect();
+        let entries = || script.iter().map(|m| m.as_any().downcast_ref::<UCGScriptedMessageInternal>().unwrap());
+
+        let opts = PlayerOptions { dry_run: true, time_scale: 10.0, ..PlayerOptions::default() };
+        let control = PlayerControl::new();
+        let clock = FakeClock { now: Cell::new(Duration::ZERO), pause_at: Duration::from_secs(1), resume_at: Duration::from_secs(3), control: control.clone() };
+        let mut player = Player::new(opts, clock);
+        player.control = control;
+        let mut seen = 0;
+        let log = player.play(entries(), None, |_| seen += 1).unwrap();
+        assert_eq!(seen, 3);
+        assert_eq!(log[0].sent, Duration::from_millis(500));
+        assert_eq!(log[0].bytes, vec![0x1C, 0xFF, 0x08, 0x01, 0x01, b'\r', b'\n']);
+        // Paused from 1s to 3s, so the message due at 1.5s goes at 3.5s and the last at 6s
+        assert_eq!(log[1].sent, Duration::from_millis(3500));
+        assert_eq!(log[1].due, log[1].sent);
+        assert_eq!((log[2].script_time, log[2].sent), (40, Duration::from_millis(6000)));
+
+        let opts = PlayerOptions { dry_run: true, start_index: 2, ..PlayerOptions::default() };
+        let clock = FakeClock { now: Cell::new(Duration::ZERO), pause_at: Duration::MAX, resume_at: Duration::MAX, control: PlayerControl::new() };
+        let log = Player::new(opts, clock).play(entries(), None, |_| ()).unwrap();
+        assert_eq!(log.len(), 1);
+        assert_eq!((log[0].index, log[0].sent), (2, Duration::from_secs(25)));
+    }
+
+    // Takes a set number of messages, then fails.
+    struct FailingLink(usize);
+
+    impl Transport for FailingLink {
+        fn send(&mut self, _bytes: &[u8]) -> io::Result<()> {
+            if self.0 == 0 {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "link down"));
+            }
+            self.0 -= 1;
+            Ok(())
+        }
+
+        fn receive(&mut self, _buf: &mut [u8], _timeout: Duration) -> io::Result<usize> {
+            Ok(0)
+        }
+    }
+
+    #[test]
+    fn play_refuses_and_stops() {
+        let script: Vec<Box<dyn UCGMessage>> = ["+5 03/4 1F/7 NOP 000", "+5 03/4 1F/7 NOP 000", "+5 03/4 1F/7 NOP 000"]
+            .iter().map(|l| UCGScriptedMessageInternal::parse_asm_line(l, false).unwrap()).collect();
+        let entries = || script.iter().map(|m| m.as_any().downcast_ref::<UCGScriptedMessageInternal>().unwrap());
+        let clock = || FakeClock { now: Cell::new(Duration::ZERO), pause_at: Duration::MAX, resume_at: Duration::MAX, control: PlayerControl::new() };
+
+        for scale in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            let opts = PlayerOptions { dry_run: true, time_scale: scale, ..PlayerOptions::default() };
+            let err = Player::new(opts, clock()).play(entries(), None, |_| ()).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+        let err = Player::new(PlayerOptions::default(), clock()).play(entries(), None, |_| ()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        // A send that fails ends play with its error
+        let mut link = FailingLink(1);
+        let mut sent = 0;
+        let err = Player::new(PlayerOptions::default(), clock()).play(entries(), Some(&mut link), |_| sent += 1).err().unwrap();
+        assert_eq!((err.kind(), sent), (io::ErrorKind::BrokenPipe, 1));
+
+        // Stopping keeps what was already sent
+        let mut player = Player::new(PlayerOptions { dry_run: true, ..PlayerOptions::default() }, clock());
+        let control = player.control.clone();
+        let log = player.play(entries(), None, |r| if r.index == 1 { control.stop() }).unwrap();
+        assert_eq!(log.len(), 2);
+        assert_eq!(log[1].sent, Duration::from_secs(10));
+    }
+}
@@ -0,0 +1,138 @@
+// mod router
+// Hands decoded messages to whichever part of a program wants them, by target address and
+// opcode.  Routes are tried in the order they were added and the first that matches takes the
+// message, so add specific routes before general ones; a route for 03 takes every subtarget
+// of 03.  Messages no route takes go to the default handler, if there is one.
+
+use crate::filter::{AddressPattern, Filter};
+use crate::symbols::SymbolTable;
+use crate::{immediate_part, UCGMessage, UCGMessageInternal};
+
+pub trait UCGHandler {
+    fn handle(&mut self, msg: &UCGMessageInternal);
+}
+
+impl<F: FnMut(&UCGMessageInternal)> UCGHandler for F {
+    fn handle(&mut self, msg: &UCGMessageInternal) {
+        self(msg)
+    }
+}
+
+struct Route<'a> {
+    filter: Filter,
+    handler: Box<dyn UCGHandler + 'a>,
+}
+
+#[derive(Default)]
+pub struct Router<'a> {
+    pub symbols: SymbolTable, // names route targets may use
+    routes: Vec<Route<'a>>,
+    default: Option<Box<dyn UCGHandler + 'a>>,
+}
+
+impl<'a> Router<'a> {
+    pub fn new() -> Router<'a> {
+        Router::default()
+    }
+
+    // Route messages that pass a filter.  Only the filter's targets, sources, opcodes and
+    // length are looked at.
+    pub fn add<H: UCGHandler + 'a>(&mut self, filter: Filter, handler: H) {
+        self.routes.push(Route { filter, handler: Box::new(handler) });
+    }
+
+    // Route messages for a target (TT, TT/S, a symbol name, or * for any) with any of a
+    // comma-separated list of opcodes, or any opcode if the list is empty.
+    pub fn on<H: UCGHandler + 'a>(&mut self, target: &str, opcodes: &str, handler: H) -> Result<(), String> {
+        let mut filter = Filter::new();
+        if target != "*" {
+            filter.targets.push(AddressPattern::parse(target, &self.symbols)?);
+        }
+        filter.add_opcodes(opcodes)?;
+        self.add(filter, handler);
+        Ok(())
+    }
+
+    pub fn set_default<H: UCGHandler + 'a>(&mut self, handler: H) {
+        self.default = Some(Box::new(handler));
+    }
+
+    // Pass a message to its handler.  Scripted messages are routed by the message inside.
+    // Returns false if nothing took it, not even a default handler.
+    pub fn dispatch(&mut self, msg: &dyn UCGMessage) -> bool {
+        let msg = match immediate_part(msg) {
+            Some(m) => m,
+            None => return false,
+        };
+        if let Some(route) = self.routes.iter_mut().find(|r| r.filter.matches(msg, None)) {
+            route.handler.handle(msg);
+            return true;
+        }
+        match self.default.as_mut() {
+            Some(h) => {
+                h.handle(msg);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::router::*;
+    use crate::UCGScriptedMessageInternal;
+    use std::cell::RefCell;
+
+    struct Counter(usize);
+
+    impl UCGHandler for Counter {
+        fn handle(&mut self, _msg: &UCGMessageInternal) {
+            self.0 += 1;
+        }
+    }
+
+    #[test]
+    fn route_messages() {
+        let seen = RefCell::new(Vec::new());
+        let mut router = Router::new();
+        router.symbols.define("IMU", 3, 4);
+        router.on("IMU", "RQRY,RTYP", |m: &UCGMessageInternal| seen.borrow_mut().push(format!("imu {}", m.op_to_text()))).unwrap();
+        router.on("03", "", |m: &UCGMessageInternal| seen.borrow_mut().push(format!("03/{} {:?}", m.target().1, m.data()))).unwrap();
+        router.add(Filter::new(), Counter(0));
+        assert!(router.on("03/9", "", Counter(0)).is_err());
+        assert!(router.on("*", "NOPE", Counter(0)).is_err());
+
+        let parse = |l: &str| UCGMessageInternal::parse_asm_line(l, false).unwrap();
+        assert!(router.dispatch(parse("03/4 1F/7 RQRY 001 01").as_ref()));
+        assert!(router.dispatch(parse("03/4 1F/7 RWRT 002 01 05").as_ref()));
+        assert!(router.dispatch(UCGScriptedMessageInternal::parse_asm_line("+5 03/2 1F/7 NOP 000", false).unwrap().as_ref()));
+        assert!(router.dispatch(parse("05/0 1F/7 NOP 000").as_ref()));
+        assert_eq!(*seen.borrow(), vec!["imu RQRY", "03/4 [1, 5]", "03/2 []"]);
+
+        let mut fallback = Router::new();
+        assert!(!fallback.dispatch(parse("05/0 1F/7 NOP 000").as_ref()));
+        let mut missed = 0;
+        fallback.set_default(|_: &UCGMessageInternal| missed += 1);
+        assert!(fallback.dispatch(parse("05/0 1F/7 NOP 000").as_ref()));
+        drop(fallback);
+        assert_eq!(missed, 1);
+    }
+
+    #[test]
+    fn route_default_takes_the_rest() {
+        let seen = RefCell::new(Vec::new());
+        let mut router = Router::new();
+        router.on("03", "", |m: &UCGMessageInternal| seen.borrow_mut().push(format!("03 {}", m.op_to_text()))).unwrap();
+        // Never reached: the route for 03 was added first and takes every subtarget
+        router.on("03/4", "RQRY", |_: &UCGMessageInternal| seen.borrow_mut().push(String::from("03/4"))).unwrap();
+        router.set_default(|_: &UCGMessageInternal| seen.borrow_mut().push(String::from("first default")));
+        router.set_default(|m: &UCGMessageInternal| seen.borrow_mut().push(format!("default {}", m.op_to_text())));
+
+        let parse = |l: &str| UCGMessageInternal::parse_asm_line(l, false).unwrap();
+        for line in ["03/4 1F/7 RQRY 001 01", "1F/7 03/4 RVAL 002 01 00", "05/0 1F/7 NOP 000"] {
+            assert!(router.dispatch(parse(line).as_ref()));
+        }
+        assert_eq!(*seen.borrow(), vec!["03 RQRY", "default RVAL", "default NOP"]);
+    }
+}
@@ -0,0 +1,452 @@
+// mod sim
+// Pretend devices for trying scripts and tools without hardware.  Each device sits at one
+// address with a register file and a table of subroutines, and answers immediate commands the
+// way the simulator understands the protocol:
+//
+//   RQRY r         RVAL r value...   or FAIL r if there is no such register
+//   RTYP r         RVAL r type       the register's type code
+//   RWRT r value   OPOK r            FAIL r if there is no such register or it is read-only,
+//                                    DERR r if the value is the wrong length
+//   SRUN s args    OPOK s, then SRET s result... when it finishes; FAIL s if there is no such
+//                                    subroutine or it is already running
+//   STOP s         OPOK s            FAIL s if it isn't running
+//   SQST s         STAT s state      0 never run, 1 running, 2 finished, 3 stopped
+//   NOP            MACK
+//
+// Commands missing their register or subroutine number get DERR, and anything else gets NSUP
+// with the opcode.  Messages for addresses with no device are ignored, as on a real bus.
+//
+// Devices can be described in a TOML file:
+//
+//   [[device]]
+//   address = "03/4"               # TT/S, or a symbol name
+//   [[device.register]]
+//   number = 1
+//   type = 2                       # type code RTYP reports, 0 if not given
+//   value = [0x2A, 0x00]           # initial contents, which also fix its length
+//   writable = false               # true if not given
+//   [[device.subroutine]]
+//   number = 1
+//   duration-ms = 500              # how long it runs, 0 if not given
+//   result = [0]                   # data after the subroutine number in SRET
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use serde::Deserialize;
+use crate::filter::AddressPattern;
+use crate::framing::{FrameBuffer, Framing};
+use crate::maps;
+use crate::symbols::SymbolTable;
+use crate::transport::Transport;
+use crate::{immediate_part, UCGMessage, UCGMessageInternal};
+
+// How long serve waits for traffic before checking on running subroutines.
+const POLL: Duration = Duration::from_millis(10);
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct SimConfig {
+    pub device: Vec<DeviceSpec>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct DeviceSpec {
+    pub address: String,
+    #[serde(default)]
+    pub register: Vec<RegisterSpec>,
+    #[serde(default)]
+    pub subroutine: Vec<SubroutineSpec>,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct RegisterSpec {
+    pub number: u8,
+    #[serde(default, rename = "type")]
+    pub kind: u8,
+    pub value: Vec<u8>,
+    #[serde(default = "writable_default")]
+    pub writable: bool,
+}
+
+fn writable_default() -> bool {
+    true
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct SubroutineSpec {
+    pub number: u8,
+    #[serde(default)]
+    pub duration_ms: u64,
+    #[serde(default)]
+    pub result: Vec<u8>,
+}
+
+impl SimConfig {
+    pub fn load_str(src: &str) -> Result<SimConfig, String> {
+        toml::from_str(src).map_err(|e| format!("Bad device file: {}", e))
+    }
+
+    pub fn load(path: &Path) -> Result<SimConfig, String> {
+        match fs::read_to_string(path) {
+            Ok(text) => SimConfig::load_str(&text).map_err(|e| format!("{}: {}", path.display(), e)),
+            Err(e) => Err(format!("Unable to open {}: {}", path.display(), e)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SubroutineState {
+    Idle,
+    Running,
+    Finished,
+    Stopped,
+}
+
+impl SubroutineState {
+    // The code SQST reports.
+    pub fn code(&self) -> u8 {
+        match self {
+            SubroutineState::Idle => 0,
+            SubroutineState::Running => 1,
+            SubroutineState::Finished => 2,
+            SubroutineState::Stopped => 3,
+        }
+    }
+}
+
+pub struct Subroutine {
+    pub spec: SubroutineSpec,
+    pub state: SubroutineState,
+    pub args: Vec<u8>,       // data the last SRUN carried after the subroutine number
+    done_at: Duration,
+    caller: (u8, u8),
+}
+
+pub struct Device {
+    pub address: (u8, u8),
+    pub registers: BTreeMap<u8, RegisterSpec>,
+    pub subroutines: BTreeMap<u8, Subroutine>,
+}
+
+impl Device {
+    pub fn new(address: (u8, u8)) -> Device {
+        Device { address, registers: BTreeMap::new(), subroutines: BTreeMap::new() }
+    }
+
+    // Sixteen one-byte writable registers, all 0, and four subroutines that take a second and
+    // return 0.  Enough to try most scripts against.
+    pub fn with_defaults(address: (u8, u8)) -> Device {
+        let mut d = Device::new(address);
+        for number in 0..16 {
+            d.add_register(RegisterSpec { number, kind: 0, value: vec![0], writable: true });
+        }
+        for number in 0..4 {
+            d.add_subroutine(SubroutineSpec { number, duration_ms: 1000, result: vec![0] });
+        }
+        d
+    }
+
+    // A device sits at one address, so unlike a filter it needs the subaddress.
+    pub fn parse_address(text: &str, symbols: &SymbolTable) -> Result<(u8, u8), String> {
+        let address = AddressPattern::parse(text, symbols)?;
+        match address.sub {
+            Some(s) => Ok((address.main, s)),
+            None => Err(format!("Device address \"{}\" needs a subaddress, e.g. 03/4.", text)),
+        }
+    }
+
+    pub fn from_spec(spec: &DeviceSpec, symbols: &SymbolTable) -> Result<Device, String> {
+        let mut d = Device::new(Device::parse_address(&spec.address, symbols)?);
+        for r in &spec.register {
+            d.add_register(r.clone());
+        }
+        for s in &spec.subroutine {
+            d.add_subroutine(s.clone());
+        }
+        Ok(d)
+    }
+
+    pub fn add_register(&mut self, spec: RegisterSpec) {
+        self.registers.insert(spec.number, spec);
+    }
+
+    pub fn add_subroutine(&mut self, spec: SubroutineSpec) {
+        let s = Subroutine { spec, state: SubroutineState::Idle, args: Vec::new(), done_at: Duration::ZERO, caller: (0, 0) };
+        self.subroutines.insert(s.spec.number, s);
+    }
+
+    pub fn register(&self, number: u8) -> Option<&[u8]> {
+        self.registers.get(&number).map(|r| r.value.as_slice())
+    }
+
+    fn message(&self, to: (u8, u8), op: &str, data: Vec<u8>) -> UCGMessageInternal {
+        UCGMessageInternal {
+            target: to.0,
+            subtarget: to.1,
+            source: self.address.0,
+            subsource: self.address.1,
+            op: maps::OPCODE_TO_NUM[op],
+            len: data.len() as u16,
+            data,
+        }
+    }
+
+    // Answer one command addressed to this device.
+    pub fn handle(&mut self, msg: &UCGMessageInternal, now: Duration) -> Vec<UCGMessageInternal> {
+        let caller = (msg.source, msg.subsource);
+        let op = msg.op_to_text();
+        if op == "NOP" {
+            return vec![self.message(caller, "MACK", Vec::new())];
+        }
+        if !["RQRY", "RTYP", "RWRT", "SRUN", "STOP", "SQST"].contains(&op.as_str()) {
+            return vec![self.message(caller, "NSUP", vec![msg.op])];
+        }
+        let (n, rest) = match msg.data.split_first() {
+            Some((n, rest)) => (*n, rest),
+            None => return vec![self.message(caller, "DERR", Vec::new())],
+        };
+        let (op, data) = match op.as_str() {
+            "RQRY" | "RTYP" | "RWRT" => match self.registers.get_mut(&n) {
+                None => ("FAIL", vec![n]),
+                Some(r) if op == "RQRY" => ("RVAL", [&[n], &r.value[..]].concat()),
+                Some(r) if op == "RTYP" => ("RVAL", vec![n, r.kind]),
+                Some(r) if !r.writable => ("FAIL", vec![n]),
+                Some(r) if rest.len() != r.value.len() => ("DERR", vec![n]),
+                Some(r) => {
+                    r.value = rest.to_vec();
+                    ("OPOK", vec![n])
+                }
+            },
+            _ => match self.subroutines.get_mut(&n) {
+                None => ("FAIL", vec![n]),
+                Some(s) if op == "SQST" => ("STAT", vec![n, s.state.code()]),
+                Some(s) if op == "STOP" && s.state == SubroutineState::Running => {
+                    s.state = SubroutineState::Stopped;
+                    ("OPOK", vec![n])
+                }
+                Some(_) if op == "STOP" => ("FAIL", vec![n]),
+                Some(s) if s.state == SubroutineState::Running => ("FAIL", vec![n]),
+                Some(s) => {
+                    s.state = SubroutineState::Running;
+                    s.args = rest.to_vec();
+                    s.done_at = now + Duration::from_millis(s.spec.duration_ms);
+                    s.caller = caller;
+                    ("OPOK", vec![n])
+                }
+            },
+        };
+        let mut replies = vec![self.message(caller, op, data)];
+        // Subroutines that take no time finish straight away
+        replies.extend(self.poll(now));
+        replies
+    }
+
+    // SRET for every subroutine that has finished by now.
+    pub fn poll(&mut self, now: Duration) -> Vec<UCGMessageInternal> {
+        let mut done = Vec::new();
+        for s in self.subroutines.values_mut() {
+            if s.state == SubroutineState::Running && now >= s.done_at {
+                s.state = SubroutineState::Finished;
+                done.push((s.caller, [&[s.spec.number], &s.spec.result[..]].concat()));
+            }
+        }
+        done.into_iter().map(|(caller, data)| self.message(caller, "SRET", data)).collect()
+    }
+}
+
+// What went by on the link, for logging.
+pub enum Traffic {
+    Received(Box<dyn UCGMessage>),
+    Sent(Box<dyn UCGMessage>),
+    Bad(String),
+}
+
+pub struct Simulator {
+    pub devices: Vec<Device>,
+    pub framing: Framing,
+    frames: FrameBuffer, // received bytes not yet making up a whole message
+}
+
+impl Simulator {
+    pub fn new(devices: Vec<Device>, framing: Framing) -> Simulator {
+        Simulator { devices, framing, frames: FrameBuffer::new(framing, false) }
+    }
+
+    pub fn from_config(config: &SimConfig, symbols: &SymbolTable, framing: Framing) -> Result<Simulator, String> {
+        let devices = config.device.iter().map(|d| Device::from_spec(d, symbols)).collect::<Result<_, _>>()?;
+        Ok(Simulator::new(devices, framing))
+    }
+
+    pub fn device(&self, main: u8, sub: u8) -> Option<&Device> {
+        self.devices.iter().find(|d| d.address == (main, sub))
+    }
+
+    // Take bytes off the link and answer any whole messages among them.
+    pub fn feed(&mut self, bytes: &[u8], now: Duration) -> Vec<Traffic> {
+        // framing is public, so pick up any change before splitting
+        self.frames.framing = self.framing;
+        self.frames.push(bytes);
+        let mut traffic = Vec::new();
+        while let Some(frame) = self.frames.next_frame() {
+            let mut bytes = match frame {
+                Ok(b) => b,
+                Err(e) => {
+                    traffic.push(Traffic::Bad(e));
+                    continue;
+                }
+            };
+            match UCGMessageInternal::from_byte_vec(&mut bytes) {
+                Some(msg) => {
+                    let replies = self.dispatch(immediate_part(msg.as_ref()).unwrap(), now);
+                    traffic.push(Traffic::Received(msg));
+                    traffic.extend(replies.into_iter().map(|r| Traffic::Sent(Box::new(r))));
+                }
+                None => traffic.push(Traffic::Bad(String::from("Dropped a message with an unknown opcode."))),
+            }
+        }
+        traffic
+    }
+
+    fn dispatch(&mut self, msg: &UCGMessageInternal, now: Duration) -> Vec<UCGMessageInternal> {
+        match self.devices.iter_mut().find(|d| d.address == (msg.target, msg.subtarget)) {
+            Some(d) => d.handle(msg, now),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn poll(&mut self, now: Duration) -> Vec<Traffic> {
+        self.devices.iter_mut()
+            .flat_map(|d| d.poll(now))
+            .map(|r| Traffic::Sent(Box::new(r)))
+            .collect()
+    }
+
+    // Answer whatever comes over a link until stop is set or the link fails, passing all the
+    // traffic to log.
+    pub fn serve<F: FnMut(&Traffic)>(&mut self, link: &mut dyn Transport, stop: &AtomicBool, mut log: F) -> io::Result<()> {
+        let started = Instant::now();
+        let mut chunk = [0u8; 1024];
+        while !stop.load(Ordering::SeqCst) {
+            let n = link.receive(&mut chunk, POLL)?;
+            let now = started.elapsed();
+            let mut traffic = self.feed(&chunk[..n], now);
+            traffic.extend(self.poll(now));
+            for t in &traffic {
+                if let Traffic::Sent(m) = t {
+                    let mut bytes = m.into_byte_vec();
+                    bytes.extend_from_slice(self.framing.trailer());
+                    link.send(&bytes)?;
+                }
+                log(t);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sim::*;
+    use crate::transport::{receive_until_quiet, ChannelTransport};
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn simulated_device() {
+        let config = SimConfig::load_str("[[device]]\naddress = \"03/4\"\n\
+            [[device.register]]\nnumber = 1\nvalue = [0x2A, 0]\n\
+            [[device.register]]\nnumber = 2\ntype = 7\nvalue = [1]\nwritable = false\n\
+            [[device.subroutine]]\nnumber = 5\nduration-ms = 100\nresult = [0xEE]\n").unwrap();
+        let mut sim = Simulator::from_config(&config, &SymbolTable::new(), Framing::Raw).unwrap();
+        let send = |sim: &mut Simulator, line: &str, now: u64| -> Vec<String> {
+            let msg = UCGMessageInternal::parse_asm_line(line, false).unwrap();
+            sim.feed(&msg.into_byte_vec(), Duration::from_millis(now)).iter()
+                .filter_map(|t| match t { Traffic::Sent(m) => Some(m.into_asm(false)), _ => None })
+                .collect()
+        };
+        assert_eq!(send(&mut sim, "03/4 1F/7 RQRY 001 01", 0), vec!["1F/7 03/4 RVAL 003 01 2A 00"]);
+        assert_eq!(send(&mut sim, "03/4 1F/7 RTYP 001 02", 0), vec!["1F/7 03/4 RVAL 002 02 07"]);
+        assert_eq!(send(&mut sim, "03/4 1F/7 RWRT 003 01 05 06", 0), vec!["1F/7 03/4 OPOK 001 01"]);
+        assert_eq!(sim.device(3, 4).unwrap().register(1), Some(&[5, 6][..]));
+        assert_eq!(send(&mut sim, "03/4 1F/7 RWRT 002 02 09", 0), vec!["1F/7 03/4 FAIL 001 02"]);
+        assert_eq!(send(&mut sim, "03/4 1F/7 RQRY 001 09", 0), vec!["1F/7 03/4 FAIL 001 09"]);
+        assert_eq!(send(&mut sim, "03/4 1F/7 RRTC 000", 0), vec!["1F/7 03/4 NSUP 001 07"]);
+        assert!(send(&mut sim, "04/4 1F/7 RQRY 001 01", 0).is_empty());
+
+        assert_eq!(send(&mut sim, "03/4 1F/7 SRUN 001 05", 0), vec!["1F/7 03/4 OPOK 001 05"]);
+        assert_eq!(send(&mut sim, "03/4 1F/7 SQST 001 05", 50), vec!["1F/7 03/4 STAT 002 05 01"]);
+        assert!(sim.poll(Duration::from_millis(99)).is_empty());
+        match &sim.poll(Duration::from_millis(100))[..] {
+            [Traffic::Sent(m)] => assert_eq!(m.into_asm(false), "1F/7 03/4 SRET 002 05 EE"),
+            _ => panic!("expected one SRET"),
+        }
+        assert_eq!(send(&mut sim, "03/4 1F/7 STOP 001 05", 200), vec!["1F/7 03/4 FAIL 001 05"]);
+        // A change of framing applies from the next bytes fed
+        sim.framing = Framing::Crlf;
+        let traffic = sim.feed(&[0x1C, 0xFF, 0x08, 0x01, 0x01, b'\r', b'\n'], Duration::from_millis(300));
+        assert!(matches!(&traffic[..], [Traffic::Received(_), Traffic::Sent(_)]));
+
+        // The same device over an in-process link, with CRLF framing
+        let mut sim = Simulator::new(vec![Device::with_defaults((3, 4))], Framing::Crlf);
+        let (mut ground, mut device) = ChannelTransport::pair();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_sim = stop.clone();
+        let server = thread::spawn(move || sim.serve(&mut device, &stop_sim, |_| ()));
+        ground.send(&[0x1C, 0xFF, 0x08, 0x01, 0x01, b'\r', b'\n']).unwrap();
+        let reply = receive_until_quiet(&mut ground, Duration::from_millis(100)).unwrap();
+        assert_eq!(reply, vec![0xFF, 0x1C, 0x28, 0x02, 0x01, 0x00, b'\r', b'\n']);
+        stop.store(true, Ordering::SeqCst);
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn device_needs_subaddress() {
+        let mut symbols = SymbolTable::new();
+        symbols.define("IMU", 3, 4);
+        assert_eq!(Device::parse_address("03/4", &symbols), Ok((3, 4)));
+        assert_eq!(Device::parse_address("imu", &symbols), Ok((3, 4)));
+        assert!(Device::parse_address("03", &symbols).unwrap_err().contains("needs a subaddress"));
+        let config = SimConfig::load_str("[[device]]\naddress = \"03\"\n").unwrap();
+        assert!(Simulator::from_config(&config, &symbols, Framing::Raw).is_err());
+    }
+
+    #[test]
+    fn simulated_device_refuses() {
+        let config = SimConfig::load_str("[[device]]\naddress = \"03/4\"\n\
+            [[device.register]]\nnumber = 1\nvalue = [0x2A, 0]\n\
+            [[device.subroutine]]\nnumber = 5\nduration-ms = 100\n").unwrap();
+        let mut device = Device::from_spec(&config.device[0], &SymbolTable::new()).unwrap();
+        let mut send = |line: &str, now: u64| -> Vec<String> {
+            let msg = UCGMessageInternal::parse_asm_line(line, false).unwrap();
+            let msg = msg.as_any().downcast_ref::<UCGMessageInternal>().unwrap();
+            device.handle(msg, Duration::from_millis(now)).iter().map(|m| m.into_asm(false)).collect()
+        };
+        // No register number, and a write of the wrong size
+        assert_eq!(send("03/4 1F/7 RQRY 000", 0), vec!["1F/7 03/4 DERR 000"]);
+        assert_eq!(send("03/4 1F/7 RWRT 002 01 05", 0), vec!["1F/7 03/4 DERR 001 01"]);
+        // Starting a subroutine that is already running, or stopping one that isn't
+        assert_eq!(send("03/4 1F/7 STOP 001 05", 0), vec!["1F/7 03/4 FAIL 001 05"]);
+        assert_eq!(send("03/4 1F/7 SRUN 001 05", 0), vec!["1F/7 03/4 OPOK 001 05"]);
+        assert_eq!(send("03/4 1F/7 SRUN 001 05", 10), vec!["1F/7 03/4 FAIL 001 05"]);
+        assert_eq!(send("03/4 1F/7 SQST 001 09", 10), vec!["1F/7 03/4 FAIL 001 09"]);
+        // Stopped before it finishes, it never returns
+        assert_eq!(send("03/4 1F/7 STOP 001 05", 20), vec!["1F/7 03/4 OPOK 001 05"]);
+        assert_eq!(send("03/4 1F/7 SQST 001 05", 200), vec!["1F/7 03/4 STAT 002 05 03"]);
+        assert!(device.poll(Duration::from_secs(1)).is_empty());
+
+        // Junk on the link is reported, and the message after it still answered
+        let mut sim = Simulator::new(vec![device], Framing::Crlf);
+        let traffic = sim.feed(&[0x00, 0x1C, 0xFF, 0x00, 0x00, b'\r', b'\n'], Duration::ZERO);
+        assert!(matches!(&traffic[..], [Traffic::Bad(_), Traffic::Received(_), Traffic::Sent(_)]));
+
+        assert!(SimConfig::load_str("[[device]]\naddress = \"03/4\"\ncolour = 1\n").is_err());
+        assert!(SimConfig::load_str("[[device]]\naddress = \"03/4\"\n[[device.register]]\nvalue = [1]\n").is_err());
+    }
+}
@@ -0,0 +1,195 @@
+// mod sourcemap
+// Sidecar files linking assembled messages back to the source they came from.
+//
+// The format is plain text, one record per line, with tab-separated fields:
+//
+//   # rgas source map v1
+//   file  <path>                                   switches the current source file
+//   line  <lineno>  <text>                         a comment or blank line kept for layout
+//   msg   <index>  <offset>  <length>  <lineno>  <comment>
+//
+// Records are written in source order, so the `line` records before a `msg` record are the
+// comments and blank lines that preceded that message.  Any `line` records after the last
+// message are the tail of the file.  Text is always the last field, so it may contain tabs.
+
+use std::collections::HashMap;
+use std::io;
+use std::io::BufRead;
+use std::io::Write;
+
+pub const SOURCEMAP_HEADER: &str = "# rgas source map v1";
+
+pub struct MessageLocation {
+    pub index: usize,      // position of the message in the output, counting from 0
+    pub offset: usize,     // byte offset of the message in the output file
+    pub length: usize,     // bytes written for the message, including framing
+    pub file: String,      // source file the message came from
+    pub line: usize,       // line number in that file, counting from 1
+    pub comment: String,   // trailing comment on the message line, without the #
+    pub leading: Vec<(usize, String)>, // comment and blank lines since the previous message
+}
+
+#[derive(Default)]
+pub struct SourceMap {
+    pub messages: Vec<MessageLocation>,
+    pub trailing: Vec<(usize, String)>, // lines after the last message
+    trailing_file: String,
+    by_offset: HashMap<usize, usize>,
+}
+
+impl SourceMap {
+    pub fn new() -> SourceMap {
+        SourceMap::default()
+    }
+
+    // Record a line that produced no message (a comment or a blank line).
+    pub fn add_line(&mut self, file: &str, line: usize, text: &str) {
+        self.trailing_file = file.to_string();
+        self.trailing.push((line, text.to_string()));
+    }
+
+    // Record a message, attaching any lines seen since the previous one.
+    pub fn add_message(&mut self, file: &str, line: usize, offset: usize, length: usize, comment: &str) {
+        let leading = std::mem::take(&mut self.trailing);
+        self.by_offset.insert(offset, self.messages.len());
+        self.messages.push(MessageLocation {
+            index: self.messages.len(),
+            offset,
+            length,
+            file: file.to_string(),
+            line,
+            comment: comment.trim().to_string(),
+            leading,
+        });
+    }
+
+    // Look up the message that was written at the given byte offset.
+    pub fn at_offset(&self, offset: usize) -> Option<&MessageLocation> {
+        self.by_offset.get(&offset).map(|&i| &self.messages[i])
+    }
+
+    pub fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(out, "{}", SOURCEMAP_HEADER)?;
+        let mut current_file = None;
+        for msg in &self.messages {
+            if current_file != Some(&msg.file) {
+                writeln!(out, "file\t{}", msg.file)?;
+                current_file = Some(&msg.file);
+            }
+            for (line, text) in &msg.leading {
+                writeln!(out, "line\t{}\t{}", line, text)?;
+            }
+            writeln!(out, "msg\t{}\t{}\t{}\t{}\t{}", msg.index, msg.offset, msg.length, msg.line, msg.comment)?;
+        }
+        if !self.trailing.is_empty() && current_file != Some(&self.trailing_file) {
+            writeln!(out, "file\t{}", self.trailing_file)?;
+        }
+        for (line, text) in &self.trailing {
+            writeln!(out, "line\t{}\t{}", line, text)?;
+        }
+        Ok(())
+    }
+
+    pub fn read_from<R: BufRead>(input: R) -> Result<SourceMap, String> {
+        let mut map = SourceMap::new();
+        let mut file = String::new();
+        for (lineno, line) in input.lines().enumerate() {
+            let line = match line {
+                Ok(l) => l,
+                Err(e) => {
+                    return Err(format!("Failed to read source map: {}", e));
+                }
+            };
+            let line = line.trim_end_matches('\r');
+            if lineno == 0 {
+                if line != SOURCEMAP_HEADER {
+                    return Err(String::from("Not an rgas source map (missing header)."));
+                }
+                continue;
+            }
+            let fields: Vec<&str> = line.splitn(6, '\t').collect();
+            let bad = || format!("Malformed source map record on line {}: \"{}\"", lineno + 1, line);
+            match fields[0] {
+                "file" if fields.len() == 2 => {
+                    file = fields[1].to_string();
+                }
+                "line" => {
+                    let fields: Vec<&str> = line.splitn(3, '\t').collect();
+                    if fields.len() != 3 {
+                        return Err(bad());
+                    }
+                    let n = fields[1].parse().map_err(|_| bad())?;
+                    map.add_line(&file, n, fields[2]);
+                }
+                "msg" if fields.len() == 6 => {
+                    let nums: Result<Vec<usize>, _> = fields[1..5].iter().map(|f| f.parse::<usize>()).collect();
+                    let nums = nums.map_err(|_| bad())?;
+                    if nums[0] != map.messages.len() {
+                        return Err(bad());
+                    }
+                    map.add_message(&file, nums[3], nums[1], nums[2], fields[5]);
+                }
+                "" => (),
+                _ => {
+                    return Err(bad());
+                }
+            }
+        }
+        Ok(map)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sourcemap::*;
+
+    #[test]
+    fn sourcemap_round_trip() {
+        let mut map = SourceMap::new();
+        map.add_line("a.asm", 1, "# set up the IMU");
+        map.add_line("a.asm", 2, "");
+        map.add_message("a.asm", 3, 0, 7, " first\twrite ");
+        map.add_message("a.asm", 4, 7, 6, "");
+        map.add_line("a.asm", 5, "# done");
+        let mut out: Vec<u8> = Vec::new();
+        map.write_to(&mut out).unwrap();
+        let text = String::from_utf8(out.clone()).unwrap();
+        assert!(text.starts_with(SOURCEMAP_HEADER));
+
+        let read = SourceMap::read_from(out.as_slice()).unwrap();
+        assert_eq!(read.messages.len(), 2);
+        let first = read.at_offset(0).unwrap();
+        assert_eq!(first.line, 3);
+        assert_eq!(first.file, "a.asm");
+        assert_eq!(first.comment, "first\twrite");
+        assert_eq!(first.leading, vec![(1, String::from("# set up the IMU")), (2, String::new())]);
+        let second = read.at_offset(7).unwrap();
+        assert_eq!(second.index, 1);
+        assert_eq!(second.length, 6);
+        assert!(second.leading.is_empty());
+        assert_eq!(read.trailing, vec![(5, String::from("# done"))]);
+        assert!(read.at_offset(3).is_none());
+    }
+
+    #[test]
+    fn sourcemap_rejects_malformed() {
+        let read = |text: &str| SourceMap::read_from(text.as_bytes()).err().unwrap();
+        assert_eq!(read("file\ta.asm\n"), "Not an rgas source map (missing header).");
+        let records = [
+            "msg\t0\t0\t7\t3",            // too few fields
+            "msg\t0\t0\tseven\t3\t",     // not a number
+            "msg\t1\t0\t7\t3\t",         // index out of order
+            "line\t-1\ttext",             // bad line number
+            "line\t5",                     // no text field
+            "file",                         // no path
+            "note\tsomething",              // unknown record
+        ];
+        for record in records {
+            let err = read(&format!("{}\nfile\ta.asm\n{}\n", SOURCEMAP_HEADER, record));
+            assert!(err.starts_with("Malformed source map record on line 3"), "{}", err);
+        }
+        // Blank lines and CRLF line ends are fine
+        let map = SourceMap::read_from(format!("{}\r\n\r\nfile\ta.asm\r\nmsg\t0\t0\t7\t3\t\r\n", SOURCEMAP_HEADER).as_bytes()).unwrap();
+        assert_eq!(map.messages[0].line, 3);
+    }
+}
@@ -0,0 +1,147 @@
+// mod stats
+// A summary of the traffic in a binary file, for dergas --stats.
+
+use std::collections::BTreeMap;
+use serde::Serialize;
+use crate::{immediate_part, UCGMessage, UCGScriptedMessageInternal};
+
+#[derive(Default, Serialize)]
+pub struct Stats {
+    pub messages: usize,
+    pub decode_errors: usize,
+    pub errors: Vec<String>,              // what went wrong, for the first few decode errors
+    pub opcodes: BTreeMap<String, usize>,
+    pub pairs: BTreeMap<String, usize>,   // "source -> target"
+    pub payload_lengths: BTreeMap<u16, usize>,
+    pub relative: usize,
+    pub absolute: usize,
+    pub duration: Option<u64>,            // seconds; only for scripted files
+}
+
+// Enough to show what kind of damage a file has without flooding the report.
+const MAX_ERRORS_KEPT: usize = 10;
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats::default()
+    }
+
+    // Count a message, given when it runs from timeline::ScriptClock.  The clock should see every
+    // message, even ones that are filtered out, so it is kept by the caller.
+    pub fn add_message(&mut self, msg: &dyn UCGMessage, time: Option<u64>) {
+        self.messages += 1;
+        if let Some(s) = msg.as_any().downcast_ref::<UCGScriptedMessageInternal>() {
+            if s.rel {
+                self.relative += 1;
+            } else {
+                self.absolute += 1;
+            }
+        }
+        if time.is_some() {
+            self.duration = time;
+        }
+        if let Some(m) = immediate_part(msg) {
+            *self.opcodes.entry(m.op_to_text()).or_insert(0) += 1;
+            let pair = format!("{:02X}/{:X} -> {:02X}/{:X}", m.source, m.subsource, m.target, m.subtarget);
+            *self.pairs.entry(pair).or_insert(0) += 1;
+            *self.payload_lengths.entry(m.len).or_insert(0) += 1;
+        }
+    }
+
+    pub fn add_error(&mut self, error: String) {
+        self.decode_errors += 1;
+        if self.errors.len() < MAX_ERRORS_KEPT {
+            self.errors.push(error);
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+        out.push_str(&format!("messages        {}\n", self.messages));
+        out.push_str(&format!("decode errors   {}\n", self.decode_errors));
+        if let Some(d) = self.duration {
+            out.push_str(&format!("duration        {}s\n", d));
+            out.push_str(&format!("timestamps      {} relative, {} absolute\n", self.relative, self.absolute));
+        }
+        if let (Some(min), Some(max)) = (self.payload_lengths.keys().next(), self.payload_lengths.keys().last()) {
+            let total: usize = self.payload_lengths.iter().map(|(len, n)| *len as usize * n).sum();
+            out.push_str(&format!("payload bytes   min {}, max {}, mean {:.1}\n",
+                                  min, max, total as f64 / self.messages as f64));
+        }
+        let mut section = |title: &str, rows: Vec<(String, usize)>| {
+            if rows.is_empty() {
+                return;
+            }
+            let width = rows.iter().map(|(k, _)| k.len()).max().unwrap_or(0);
+            out.push_str(&format!("\n{}\n", title));
+            for (k, n) in rows {
+                out.push_str(&format!("  {:width$}  {}\n", k, n, width = width));
+            }
+        };
+        section("opcodes", self.opcodes.iter().map(|(k, n)| (k.clone(), *n)).collect());
+        section("source -> target", self.pairs.iter().map(|(k, n)| (k.clone(), *n)).collect());
+        section("payload length", self.payload_lengths.iter().map(|(k, n)| (k.to_string(), *n)).collect());
+        if !self.errors.is_empty() {
+            out.push_str("\nerrors\n");
+            for e in &self.errors {
+                out.push_str(&format!("  {}\n", e));
+            }
+            if self.decode_errors > self.errors.len() {
+                out.push_str(&format!("  ... and {} more\n", self.decode_errors - self.errors.len()));
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stats::*;
+    use crate::timeline::ScriptClock;
+
+    #[test]
+    fn stats_summary() {
+        let mut stats = Stats::new();
+        let mut clock = ScriptClock::new();
+        for line in ["+5 03/4 1F/7 RQRY 001 01", "100 03/4 1F/7 RWRT 003 01 D300", "+2 1F/7 03/4 RVAL 003 01 D300"] {
+            let m = UCGScriptedMessageInternal::parse_asm_line(line, false).unwrap();
+            let time = clock.advance_message(m.as_ref());
+            stats.add_message(m.as_ref(), time);
+        }
+        stats.add_error(String::from("bad framing"));
+        assert_eq!(stats.messages, 3);
+        assert_eq!((stats.relative, stats.absolute), (2, 1));
+        assert_eq!(stats.duration, Some(102));
+        assert_eq!(stats.pairs.get("1F/7 -> 03/4"), Some(&2));
+        assert_eq!(stats.payload_lengths.get(&3), Some(&2));
+        let text = stats.to_text();
+        assert!(text.contains("payload bytes   min 1, max 3, mean 2.3\n"));
+        assert!(text.contains("  RWRT  1\n"));
+        let json: serde_json::Value = serde_json::from_str(&stats.to_json()).unwrap();
+        assert_eq!(json["decode_errors"], 1);
+        assert_eq!(json["opcodes"]["RQRY"], 1);
+    }
+
+    #[test]
+    fn stats_only_errors() {
+        // A file that is nothing but damage: no messages to average, and more errors than are kept
+        let mut stats = Stats::new();
+        for i in 0..MAX_ERRORS_KEPT + 2 {
+            stats.add_error(format!("bad frame {}", i));
+        }
+        assert_eq!((stats.decode_errors, stats.errors.len()), (MAX_ERRORS_KEPT + 2, MAX_ERRORS_KEPT));
+        let text = stats.to_text();
+        assert!(text.starts_with("messages        0\ndecode errors   12\n\nerrors\n"));
+        assert!(!text.contains("payload bytes"));
+        assert!(!text.contains("duration"));
+        assert!(text.contains("  bad frame 9\n  ... and 2 more\n"));
+        assert!(!text.contains("bad frame 10"));
+        let json: serde_json::Value = serde_json::from_str(&stats.to_json()).unwrap();
+        assert_eq!(json["duration"], serde_json::Value::Null);
+        assert_eq!(json["errors"].as_array().unwrap().len(), MAX_ERRORS_KEPT);
+    }
+}
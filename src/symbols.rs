@@ -0,0 +1,139 @@
+// mod symbols
+// Device names that can stand in for addresses.  A symbol file has one definition per line:
+//
+//   # name    address
+//   GROUND    1F/7
+//   IMU       03/4
+//
+// rgas swaps names for addresses before parsing; dergas can swap them back when printing.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+#[derive(Default, Clone)]
+pub struct SymbolTable {
+    by_name: HashMap<String, (u8, u8)>,
+    by_address: HashMap<(u8, u8), String>,
+}
+
+fn parse_address(s: &str) -> Option<(u8, u8)> {
+    let (main, sub) = s.split_once('/')?;
+    let main = u8::from_str_radix(main, 16).ok()?;
+    let sub = u8::from_str_radix(sub, 16).ok()?;
+    if main > 0x1F || sub > 7 {
+        return None;
+    }
+    Some((main, sub))
+}
+
+impl SymbolTable {
+    pub fn new() -> SymbolTable {
+        SymbolTable::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+
+    pub fn define(&mut self, name: &str, main: u8, sub: u8) {
+        let name = name.to_ascii_uppercase();
+        // The first name given for an address is the one dergas prints
+        self.by_address.entry((main, sub)).or_insert_with(|| name.clone());
+        self.by_name.insert(name, (main, sub));
+    }
+
+    pub fn load_str(&mut self, src: &str) -> Result<(), String> {
+        for (i, line) in src.lines().enumerate() {
+            let code = crate::split_comment(line).0;
+            let fields: Vec<&str> = code.split(|c: char| c.is_whitespace() || c == '=')
+                .filter(|f| !f.is_empty())
+                .collect();
+            match fields.as_slice() {
+                [] => (),
+                [name, address] => {
+                    if parse_address(name).is_some() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                        return Err(format!("line {}: invalid symbol name \"{}\".", i + 1, name));
+                    }
+                    match parse_address(address) {
+                        Some((main, sub)) => self.define(name, main, sub),
+                        None => return Err(format!("line {}: invalid address \"{}\".", i + 1, address)),
+                    }
+                }
+                _ => return Err(format!("line {}: expected \"NAME TT/S\".", i + 1)),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn load_file(&mut self, path: &Path) -> Result<(), String> {
+        let src = match fs::read_to_string(path) {
+            Ok(s) => s,
+            Err(e) => return Err(format!("Unable to open symbol file {}: {}", path.display(), e)),
+        };
+        self.load_str(&src).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn address(&self, name: &str) -> Option<(u8, u8)> {
+        self.by_name.get(&name.to_ascii_uppercase()).copied()
+    }
+
+    pub fn name(&self, main: u8, sub: u8) -> Option<&str> {
+        self.by_address.get(&(main, sub)).map(|s| s.as_str())
+    }
+
+    // Rewrite the target and source fields of a source line, replacing names with addresses.
+    pub fn expand_line(&self, line: &str, scripted: bool) -> String {
+        self.map_address_fields(line, scripted, |tok| {
+            self.address(tok).map(|(main, sub)| format!("{:02X}/{:X}", main, sub))
+        })
+    }
+
+    // The reverse: replace addresses that have a name with that name.
+    pub fn name_line(&self, line: &str, scripted: bool) -> String {
+        self.map_address_fields(line, scripted, |tok| {
+            parse_address(tok).and_then(|(main, sub)| self.name(main, sub)).map(String::from)
+        })
+    }
+
+    fn map_address_fields<F>(&self, line: &str, scripted: bool, f: F) -> String
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        if self.is_empty() {
+            return line.to_string();
+        }
+        let (code, comment) = crate::split_comment(line);
+        let first = if scripted { 1 } else { 0 };
+        let mut tokens: Vec<String> = code.split_whitespace().map(String::from).collect();
+        if tokens.first().map_or(true, |t| t.starts_with('#')) {
+            return line.to_string();
+        }
+        for tok in tokens.iter_mut().skip(first).take(2) {
+            if let Some(replacement) = f(tok) {
+                *tok = replacement;
+            }
+        }
+        let mut out = tokens.join(" ");
+        if let Some(c) = comment {
+            out.push_str(" #");
+            out.push_str(c);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::symbols::*;
+
+    #[test]
+    fn symbols_expand_and_name() {
+        let mut syms = SymbolTable::new();
+        syms.load_str("# devices\nimu = 03/4\nGROUND 1F/7\n").unwrap();
+        assert_eq!(syms.expand_line("+5 IMU ground RQRY 001 01 # hi", true), "+5 03/4 1F/7 RQRY 001 01 # hi");
+        assert_eq!(syms.name_line("03/4 1F/7 RQRY 001 01", false), "IMU GROUND RQRY 001 01");
+        assert!(syms.load_str("BAD 40/0").is_err());
+        assert!(syms.load_str("03/4 03/4").is_err());
+    }
+}
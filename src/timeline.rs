@@ -0,0 +1,199 @@
+// mod timeline
+// Works out when each message of a scripted file runs and checks that the result makes sense.
+//
+// Time starts at zero.  A relative message runs its offset after the one before it; an absolute
+// message runs at its timestamp, measured from the start of the script.  An absolute time that
+// has already passed can't be honoured, so the device runs that message straight away.
+
+use crate::{UCGMessage, UCGScriptedMessageInternal, Severity, MAX_TIMESTAMP};
+
+#[derive(Default)]
+pub struct TimelineOptions {
+    pub max_duration: Option<u64>, // longest the script may run, in seconds
+}
+
+pub struct TimelineEntry {
+    pub index: usize,
+    pub rel: bool,
+    pub ts: u32,
+    pub start: u64, // seconds from the start of the script
+}
+
+pub struct TimelineIssue {
+    pub index: usize, // message that triggered the issue
+    pub severity: Severity,
+    pub message: String,
+}
+
+#[derive(Default)]
+pub struct Timeline {
+    pub entries: Vec<TimelineEntry>,
+    pub issues: Vec<TimelineIssue>,
+    pub duration: u64,
+}
+
+impl Timeline {
+    pub fn has_errors(&self) -> bool {
+        self.issues.iter().any(|i| i.severity == Severity::Error)
+    }
+}
+
+// Script time as messages go by, for tools that see one message at a time.
+#[derive(Default)]
+pub struct ScriptClock {
+    pub now: u64,
+}
+
+impl ScriptClock {
+    pub fn new() -> ScriptClock {
+        ScriptClock::default()
+    }
+
+    pub fn advance(&mut self, rel: bool, ts: u32) -> u64 {
+        if rel {
+            self.now += ts as u64;
+        } else {
+            self.now = self.now.max(ts as u64);
+        }
+        self.now
+    }
+
+    // When a message runs, or None if it is an immediate message and has no time.
+    pub fn advance_message(&mut self, msg: &dyn UCGMessage) -> Option<u64> {
+        msg.as_any().downcast_ref::<UCGScriptedMessageInternal>()
+            .map(|s| self.advance(s.rel, s.ts))
+    }
+}
+
+pub fn build_timeline<'a, I>(msgs: I, opts: &TimelineOptions) -> Timeline
+where
+    I: IntoIterator<Item = &'a UCGScriptedMessageInternal>,
+{
+    let mut timeline = Timeline::default();
+    let mut clock = ScriptClock::new();
+    let mut last_abs: Option<(usize, u32)> = None;
+    let mut overflowed = false;
+    let mut too_long = false;
+    for (index, msg) in msgs.into_iter().enumerate() {
+        let mut issue = |severity, message| {
+            timeline.issues.push(TimelineIssue { index, severity, message });
+        };
+        if !msg.rel {
+            if let Some((prev_index, prev_ts)) = last_abs {
+                if msg.ts < prev_ts {
+                    issue(Severity::Error, format!(
+                        "absolute time {}s goes backwards from {}s at message {}.",
+                        msg.ts, prev_ts, prev_index + 1));
+                }
+            }
+            if (msg.ts as u64) < clock.now {
+                issue(Severity::Warning, format!(
+                    "absolute time {}s is earlier than the {}s already reached by relative offsets; it will run late.",
+                    msg.ts, clock.now));
+            }
+            last_abs = Some((index, msg.ts));
+        }
+        let now = clock.advance(msg.rel, msg.ts);
+        if now > MAX_TIMESTAMP as u64 && !overflowed {
+            overflowed = true;
+            issue(Severity::Error, format!(
+                "script time reaches {}s, which overflows the 31-bit timestamp ({}s).",
+                now, MAX_TIMESTAMP));
+        }
+        if let Some(max) = opts.max_duration {
+            if now > max && !too_long {
+                too_long = true;
+                issue(Severity::Error, format!(
+                    "script time reaches {}s, past the maximum duration of {}s.",
+                    now, max));
+            }
+        }
+        timeline.entries.push(TimelineEntry {
+            index,
+            rel: msg.rel,
+            ts: msg.ts,
+            start: now,
+        });
+    }
+    timeline.duration = clock.now;
+    timeline
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use crate::timeline::*;
+
+    fn script(lines: &[&str]) -> Vec<Box<dyn UCGMessage>> {
+        lines.iter().map(|l| UCGScriptedMessageInternal::parse_asm_line(l, false).unwrap()).collect()
+    }
+
+    fn check(msgs: &[Box<dyn UCGMessage>], opts: &TimelineOptions) -> Timeline {
+        build_timeline(msgs.iter().map(|m| m.as_any().downcast_ref::<UCGScriptedMessageInternal>().unwrap()), opts)
+    }
+
+    #[test]
+    fn timeline_mixed_offsets() {
+        let msgs = script(&["+5 03/4 1F/7 NOP 000", "20 03/4 1F/7 NOP 000", "+10 03/4 1F/7 NOP 000"]);
+        let t = check(&msgs, &TimelineOptions::default());
+        let starts: Vec<u64> = t.entries.iter().map(|e| e.start).collect();
+        assert_eq!(starts, vec![5, 20, 30]);
+        assert_eq!(t.duration, 30);
+        assert!(t.issues.is_empty());
+
+        let t = check(&msgs, &TimelineOptions { max_duration: Some(25) });
+        assert_eq!(t.issues.len(), 1);
+        assert_eq!(t.issues[0].index, 2);
+        assert!(t.has_errors());
+    }
+
+    #[test]
+    fn timeline_flags_problems() {
+        let msgs = script(&[
+            "100 03/4 1F/7 NOP 000",
+            "+50 03/4 1F/7 NOP 000",
+            "120 03/4 1F/7 NOP 000",  // behind relative time
+            "90 03/4 1F/7 NOP 000",   // goes backwards
+            "+2147483647 03/4 1F/7 NOP 000",
+        ]);
+        let t = check(&msgs, &TimelineOptions::default());
+        let found: Vec<(usize, Severity)> = t.issues.iter().map(|i| (i.index, i.severity)).collect();
+        assert_eq!(found, vec![
+            (2, Severity::Warning),
+            (3, Severity::Error),
+            (3, Severity::Warning),
+            (4, Severity::Error),
+        ]);
+    }
+
+    #[test]
+    fn timeline_overflow_reported_once() {
+        // The last second that fits, then two more that don't; only the first overflow is an issue
+        let msgs = script(&["2147483647 03/4 1F/7 NOP 000", "+1 03/4 1F/7 NOP 000", "+1 03/4 1F/7 NOP 000"]);
+        let t = check(&msgs, &TimelineOptions { max_duration: Some(2147483647) });
+        let found: Vec<(usize, Severity)> = t.issues.iter().map(|i| (i.index, i.severity)).collect();
+        assert_eq!(found, vec![(1, Severity::Error), (1, Severity::Error)]);
+        assert!(t.issues[0].message.contains("overflows"));
+        assert!(t.issues[1].message.contains("maximum duration"));
+        assert_eq!(t.duration, MAX_TIMESTAMP as u64 + 2);
+    }
+
+    #[test]
+    fn timeline_backwards_against_last_absolute() {
+        // Each absolute time is compared with the one before it, not with the first
+        let msgs = script(&["50 03/4 1F/7 NOP 000", "40 03/4 1F/7 NOP 000", "45 03/4 1F/7 NOP 000", "30 03/4 1F/7 NOP 000"]);
+        let t = check(&msgs, &TimelineOptions::default());
+        let backwards: Vec<&str> = t.issues.iter()
+            .filter(|i| i.severity == Severity::Error)
+            .map(|i| i.message.as_str())
+            .collect();
+        assert_eq!(backwards, vec![
+            "absolute time 40s goes backwards from 50s at message 1.",
+            "absolute time 30s goes backwards from 45s at message 3.",
+        ]);
+        // A late absolute message runs straight away, so time doesn't go back with it
+        let starts: Vec<u64> = t.entries.iter().map(|e| e.start).collect();
+        assert_eq!(starts, vec![50, 50, 50, 50]);
+        assert!(t.has_errors());
+    }
+}
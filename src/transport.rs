@@ -0,0 +1,170 @@
+// mod transport
+// Links to a device.  A transport moves bytes; framing and decoding stay with the callers, so
+// anything that can send and receive can carry UCG messages.
+
+use std::io;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::time::Duration;
+use serialport::SerialPort;
+
+pub trait Transport {
+    // Send all of bytes.
+    fn send(&mut self, bytes: &[u8]) -> io::Result<()>;
+
+    // Read whatever has arrived, waiting up to timeout for something to.  Ok(0) means nothing did.
+    fn receive(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<usize>;
+}
+
+// Collect everything that arrives until the link has been quiet for the given time.
+pub fn receive_until_quiet(t: &mut dyn Transport, quiet: Duration) -> io::Result<Vec<u8>> {
+    let mut received = Vec::new();
+    let mut chunk = [0u8; 1024];
+    loop {
+        match t.receive(&mut chunk, quiet)? {
+            0 => return Ok(received),
+            n => received.extend_from_slice(&chunk[..n]),
+        }
+    }
+}
+
+pub struct SerialTransport {
+    port: Box<dyn SerialPort>,
+}
+
+impl SerialTransport {
+    // Open a serial port at the given baud rate, 8N1 with no flow control.
+    pub fn open(path: &str, baud: u32) -> Result<SerialTransport, String> {
+        match serialport::new(path, baud).open() {
+            Ok(port) => Ok(SerialTransport { port }),
+            Err(e) => Err(format!("Unable to open serial port {}: {}", path, e)),
+        }
+    }
+
+    pub fn from_port(port: Box<dyn SerialPort>) -> SerialTransport {
+        SerialTransport { port }
+    }
+}
+
+impl Transport for SerialTransport {
+    fn send(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.port.write_all(bytes)?;
+        self.port.flush()
+    }
+
+    fn receive(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        self.port.set_timeout(timeout)?;
+        match self.port.read(buf) {
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => Ok(0),
+            r => r,
+        }
+    }
+}
+
+pub struct TcpTransport {
+    stream: TcpStream,
+}
+
+impl TcpTransport {
+    pub fn connect(addr: &str) -> Result<TcpTransport, String> {
+        let addrs = match addr.to_socket_addrs() {
+            Ok(a) => a,
+            Err(e) => return Err(format!("Bad address {}: {}", addr, e)),
+        };
+        match addrs.into_iter().find_map(|a| TcpStream::connect(a).ok()) {
+            Some(stream) => Ok(TcpTransport::from_stream(stream)),
+            None => Err(format!("Unable to connect to {}.", addr)),
+        }
+    }
+
+    pub fn from_stream(stream: TcpStream) -> TcpTransport {
+        // Messages are small and latency matters more than packet count
+        let _ = stream.set_nodelay(true);
+        TcpTransport { stream }
+    }
+}
+
+impl Transport for TcpTransport {
+    fn send(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.stream.write_all(bytes)
+    }
+
+    fn receive(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        // A zero timeout would mean waiting forever
+        self.stream.set_read_timeout(Some(timeout.max(Duration::from_millis(1))))?;
+        match self.stream.read(buf) {
+            Ok(0) if !buf.is_empty() => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed.")),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => Ok(0),
+            r => r,
+        }
+    }
+}
+
+// One end of a link within the program, for talking to a simulated device without any I/O.
+pub struct ChannelTransport {
+    tx: Sender<Vec<u8>>,
+    rx: Receiver<Vec<u8>>,
+    pending: Vec<u8>, // received but not yet handed out
+}
+
+impl ChannelTransport {
+    // Two ends joined to each other.
+    pub fn pair() -> (ChannelTransport, ChannelTransport) {
+        let (a_tx, b_rx) = channel();
+        let (b_tx, a_rx) = channel();
+        (
+            ChannelTransport { tx: a_tx, rx: a_rx, pending: Vec::new() },
+            ChannelTransport { tx: b_tx, rx: b_rx, pending: Vec::new() },
+        )
+    }
+}
+
+impl Transport for ChannelTransport {
+    fn send(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.tx.send(bytes.to_vec()).map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Other end has gone."))
+    }
+
+    fn receive(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            match self.rx.recv_timeout(timeout) {
+                Ok(bytes) => self.pending = bytes,
+                Err(RecvTimeoutError::Timeout) => return Ok(0),
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Other end has gone."));
+                }
+            }
+        }
+        let n = buf.len().min(self.pending.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        Ok(n)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use crate::transport::*;
+    use crate::disassembler::{Disassembler, RenderOptions};
+    use crate::framing::Framing;
+    use serialport::TTYPort;
+
+    #[test]
+    fn serial_over_pty() {
+        let (ground, device) = TTYPort::pair().unwrap();
+        let mut ground = SerialTransport::from_port(Box::new(ground));
+        let mut device = SerialTransport::from_port(Box::new(device));
+
+        ground.send(&[0x1C, 0xFF, 0x08, 0x01, 0x01, b'\r', b'\n']).unwrap();
+        let query = receive_until_quiet(&mut device, Duration::from_millis(100)).unwrap();
+        assert_eq!(query.len(), 7);
+        device.send(&[0xFF, 0x1C, 0x28, 0x02, 0x01, 0x2A, b'\r', b'\n']).unwrap();
+
+        let reply = receive_until_quiet(&mut ground, Duration::from_millis(100)).unwrap();
+        let decoded: Vec<String> = Disassembler::new(&reply[..], true, Framing::Crlf)
+            .map(|m| m.ok().unwrap().asm(&RenderOptions::default()))
+            .collect();
+        assert_eq!(decoded, vec!["1F/7 03/4 RVAL 002 01 2A"]);
+        assert_eq!(ground.receive(&mut [0u8; 4], Duration::from_millis(10)).unwrap(), 0);
+    }
+}